@@ -1,6 +1,16 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::path::PathBuf;
+
 use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
 use clap::{Parser, Subcommand};
-use enigma::Database;
+use enigma::{
+    Database, PermissionPolicy, SessionLimitAction, SessionPolicy, SigningKeyEncryptionKey,
+};
+use qrcode::{render::unicode, QrCode};
+
+mod serve;
 
 #[derive(Parser)]
 #[clap(version = "1.0", author = "Julgodis")]
@@ -10,6 +20,11 @@ struct Opts {
 
     #[clap(long, default_value = "enigma.db")]
     path: String,
+
+    /// File holding the key that encrypts the signing keys in the database,
+    /// as printed by `signing-key generate-encryption-key`
+    #[clap(long, global = true)]
+    signing_key_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -24,6 +39,30 @@ enum Command {
         #[clap(subcommand)]
         cmd: Perm,
     },
+    /// Manage roles, which bundle permissions
+    Role {
+        #[clap(subcommand)]
+        cmd: Role,
+    },
+    /// Manage API keys for services acting on behalf of a user
+    Key {
+        #[clap(subcommand)]
+        cmd: Key,
+    },
+    /// Inspect and revoke sessions
+    Session {
+        #[clap(subcommand)]
+        cmd: SessionCmd,
+    },
+    /// Manage the keys signing stateless session tokens
+    SigningKey {
+        #[clap(subcommand)]
+        cmd: SigningKeyCmd,
+    },
+    /// Issue a QR login token for a session and render it
+    Qr(Qr),
+    /// Serve the JSON REST API
+    Serve(Serve),
 }
 
 #[derive(Subcommand)]
@@ -34,6 +73,18 @@ enum User {
     Delete(DeleteUser),
     /// List all users
     List,
+    /// Rename a user or change their email address
+    Update(UpdateUser),
+    /// Block a user from signing in without deleting them
+    Disable(DisableUser),
+    /// Allow a disabled user to sign in again
+    Enable(EnableUser),
+    /// Change the password of a user, revoking their sessions
+    Passwd(Passwd),
+    /// Generate new recovery codes for a user, replacing the old ones
+    RecoveryCodes(RecoveryCodes),
+    /// Limit the number of active sessions of a user
+    SessionLimit(SessionLimit),
 }
 
 #[derive(Parser)]
@@ -52,6 +103,123 @@ struct DeleteUser {
     username: String,
 }
 
+#[derive(Parser)]
+struct UpdateUser {
+    /// Username of the user to update
+    username: String,
+    /// New username
+    #[clap(long = "username")]
+    new_username: Option<String>,
+    /// New email address, which has to be verified again
+    #[clap(long, conflicts_with = "clear_email")]
+    email: Option<String>,
+    /// Remove the email address
+    #[clap(long)]
+    clear_email: bool,
+}
+
+#[derive(Parser)]
+struct DisableUser {
+    /// Username of the user to disable
+    username: String,
+    /// Reason shown to administrators
+    #[clap(long)]
+    reason: Option<String>,
+    /// Enable the user again at this time (RFC 3339)
+    #[clap(long)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Parser)]
+struct EnableUser {
+    /// Username of the user to enable
+    username: String,
+}
+
+#[derive(Parser)]
+struct Passwd {
+    /// Username of the user whose password is changed
+    username: String,
+    /// New password
+    password: String,
+    /// Current password, required unless changing it as an administrator
+    #[clap(long)]
+    old_password: Option<String>,
+}
+
+#[derive(Parser)]
+struct RecoveryCodes {
+    /// Username of the user to generate recovery codes for
+    username: String,
+}
+
+#[derive(Parser)]
+struct SessionLimit {
+    /// Username of the user to limit
+    username: String,
+    /// Most active sessions, or the server default if omitted
+    limit: Option<u32>,
+}
+
+#[derive(Parser)]
+struct Qr {
+    /// Session token of the user signing in the new device
+    session_token: String,
+}
+
+#[derive(Parser)]
+struct Serve {
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:40080")]
+    addr: SocketAddr,
+    /// Site of the permission required for user and permission management
+    #[clap(long, default_value = "enigma")]
+    admin_site: String,
+    /// Permission required for user and permission management
+    #[clap(long, default_value = "admin")]
+    admin_permission: String,
+    /// Permission required on a site by the /forward-auth endpoint
+    #[clap(long, default_value = "read")]
+    forward_auth_permission: String,
+    /// Map a forwarded host to a permission site, as `host=site`
+    #[clap(long = "forward-auth-site", value_parser = parse_site)]
+    forward_auth_sites: Vec<(String, String)>,
+    /// Redirect unauthenticated forward-auth requests to this login page
+    #[clap(long)]
+    forward_auth_login_url: Option<String>,
+    /// Issue signed session tokens valid for this many minutes
+    #[clap(long)]
+    signed_token_minutes: Option<i64>,
+    /// Most active sessions per user, unless overridden for the user
+    #[clap(long)]
+    max_sessions: Option<u32>,
+    /// Refuse new sessions over the limit instead of evicting the least
+    /// recently used one
+    #[clap(long)]
+    refuse_over_session_limit: bool,
+    /// Let holders of a permission also pass checks for another, as
+    /// `permission=implied`
+    #[clap(long = "implication", value_parser = parse_implication)]
+    implications: Vec<(String, String)>,
+    /// Trust the X-Forwarded-For header of requests from this proxy address
+    #[clap(long = "trusted-proxy")]
+    trusted_proxies: Vec<IpAddr>,
+}
+
+fn parse_site(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(host, site)| (host.to_string(), site.to_string()))
+        .ok_or_else(|| format!("expected `host=site`, got {:?}", value))
+}
+
+fn parse_implication(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(permission, implied)| (permission.to_string(), implied.to_string()))
+        .ok_or_else(|| format!("expected `permission=implied`, got {:?}", value))
+}
+
 #[derive(Subcommand)]
 enum Perm {
     /// Add a permission to a user
@@ -68,6 +236,9 @@ struct AddPerm {
     site: String,
     /// Permission to add
     permission: String,
+    /// Revoke the permission at this time (RFC 3339)
+    #[clap(long)]
+    until: Option<DateTime<Utc>>,
 }
 
 #[derive(Parser)]
@@ -80,6 +251,150 @@ struct RemovePerm {
     permission: String,
 }
 
+#[derive(Subcommand)]
+enum Role {
+    /// Create a new role
+    Create(CreateRole),
+    /// Delete a role, removing it from its users
+    Delete(DeleteRole),
+    /// List all roles with their permissions
+    List,
+    /// Add a permission to a role
+    AddPerm(RolePerm),
+    /// Remove a permission from a role
+    RemovePerm(RolePerm),
+    /// Assign a role to a user
+    Assign(RoleMember),
+    /// Remove a role from a user
+    Unassign(RoleMember),
+}
+
+#[derive(Parser)]
+struct CreateRole {
+    /// Name of the new role
+    name: String,
+    /// Description of the new role
+    #[clap(long)]
+    description: Option<String>,
+}
+
+#[derive(Parser)]
+struct DeleteRole {
+    /// Name of the role to be deleted
+    name: String,
+}
+
+#[derive(Parser)]
+struct RolePerm {
+    /// Name of the role
+    name: String,
+    /// Site of the permission
+    site: String,
+    /// Permission on the site
+    permission: String,
+}
+
+#[derive(Parser)]
+struct RoleMember {
+    /// Username of the user
+    username: String,
+    /// Name of the role
+    name: String,
+}
+
+#[derive(Subcommand)]
+enum SessionCmd {
+    /// List the active sessions of a user
+    List(ListSessions),
+    /// Revoke a session of a user, or all of them
+    Revoke(RevokeSession),
+}
+
+#[derive(Parser)]
+struct ListSessions {
+    /// Username of the user owning the sessions
+    username: String,
+}
+
+#[derive(Subcommand)]
+enum SigningKeyCmd {
+    /// Replace the signing key, keeping the old one for verification
+    Rotate,
+    /// Print the public signing keys as a JWKS document
+    Export,
+    /// Delete retired keys whose tokens have all expired
+    Prune(PruneSigningKeys),
+    /// Print a new key for `--signing-key-file`
+    GenerateEncryptionKey,
+}
+
+#[derive(Parser)]
+struct PruneSigningKeys {
+    /// Lifetime of signed tokens in minutes, as passed to `serve`
+    #[clap(long, default_value = "15")]
+    signed_token_minutes: i64,
+}
+
+#[derive(Parser)]
+struct RevokeSession {
+    /// Username of the user owning the sessions
+    username: String,
+    /// Id of the session, as shown by `session list`
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    id: Option<i64>,
+    /// Revoke all sessions of the user
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Subcommand)]
+enum Key {
+    /// Mint a new API key and print it
+    Mint(MintKey),
+    /// List the API keys of a user
+    List(ListKeys),
+    /// Revoke an API key
+    Revoke(RevokeKey),
+}
+
+#[derive(Parser)]
+struct MintKey {
+    /// Username of the user owning the key
+    username: String,
+    /// Name describing what the key is used for
+    name: String,
+    /// Permission of the key, as `site:permission`; must be granted to the user
+    #[clap(long = "scope", value_parser = parse_scope)]
+    scopes: Vec<enigma::Permission>,
+    /// Expire the key at this time (RFC 3339)
+    #[clap(long)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Parser)]
+struct ListKeys {
+    /// Username of the user owning the keys
+    username: String,
+}
+
+#[derive(Parser)]
+struct RevokeKey {
+    /// Username of the user owning the key
+    username: String,
+    /// Id of the key, as shown by `key list`
+    id: i64,
+}
+
+fn parse_scope(value: &str) -> Result<enigma::Permission, String> {
+    value
+        .rsplit_once(':')
+        .map(|(site, permission)| enigma::Permission {
+            site: site.to_string(),
+            permission: permission.to_string(),
+        })
+        .ok_or_else(|| format!("expected `site:permission`, got {:?}", value))
+}
+
 fn main() {
     tracing_subscriber::fmt::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -102,10 +417,68 @@ fn main() {
 fn cli() -> Result<()> {
     let opts: Opts = Opts::parse();
     let database = Database::new(opts.path)?;
+    let signing_key_encryption_key = opts
+        .signing_key_file
+        .as_deref()
+        .map(read_signing_key_file)
+        .transpose()?;
 
     match opts.cmd {
         Command::User { cmd } => cli_user(database, cmd)?,
         Command::Perm { cmd } => cli_perms(database, cmd)?,
+        Command::Role { cmd } => cli_roles(database, cmd)?,
+        Command::Key { cmd } => cli_keys(database, cmd)?,
+        Command::Session { cmd } => cli_sessions(database, cmd)?,
+        Command::SigningKey { cmd } => cli_signing_keys(database, cmd, signing_key_encryption_key)?,
+        Command::Qr(qr) => cli_qr(database, qr)?,
+        Command::Serve(Serve {
+            addr,
+            admin_site,
+            admin_permission,
+            forward_auth_permission,
+            forward_auth_sites,
+            forward_auth_login_url,
+            signed_token_minutes,
+            max_sessions,
+            refuse_over_session_limit,
+            implications,
+            trusted_proxies,
+        }) => {
+            if signed_token_minutes.is_some() && signing_key_encryption_key.is_none() {
+                anyhow::bail!("--signed-token-minutes requires --signing-key-file");
+            }
+            let session_limit_action = if refuse_over_session_limit {
+                SessionLimitAction::Refuse
+            } else {
+                SessionLimitAction::EvictLeastRecentlyUsed
+            };
+            let permission_policy = implications.iter().fold(
+                PermissionPolicy::empty(),
+                |policy, (permission, implied)| policy.with_implication(permission, implied),
+            );
+            let database = database
+                .with_session_policy(SessionPolicy {
+                    signed_token_lifetime: signed_token_minutes.map(Duration::minutes),
+                    signing_key_encryption_key,
+                    max_sessions,
+                    session_limit_action,
+                    ..Default::default()
+                })
+                .with_permission_policy(permission_policy);
+            let forward_auth = enigma::ForwardAuth {
+                sites: forward_auth_sites.into_iter().collect(),
+                permission: forward_auth_permission,
+                login_url: forward_auth_login_url,
+            };
+            serve::serve(
+                database,
+                addr,
+                &admin_site,
+                &admin_permission,
+                forward_auth,
+                serve::TrustedProxies(trusted_proxies),
+            )?
+        }
     }
 
     Ok(())
@@ -139,9 +512,87 @@ fn cli_user(mut database: Database, cmd: User) -> Result<()> {
                     .map(|p| format!("{}:{}", p.site, p.permission))
                     .collect::<Vec<_>>()
                     .join(",");
-                println!("  #{:4} | {:>30} | {}", user.id, user.username, perms);
+                let email = match (&user.email, user.email_verified_at) {
+                    (Some(email), Some(_)) => email.clone(),
+                    (Some(email), None) => format!("{} (unverified)", email),
+                    (None, _) => String::new(),
+                };
+                let status = match &user.status {
+                    enigma::UserStatus::Active => "active".to_string(),
+                    enigma::UserStatus::Disabled {
+                        until: Some(until), ..
+                    } => {
+                        format!("disabled until {}", until)
+                    }
+                    enigma::UserStatus::Disabled { until: None, .. } => "disabled".to_string(),
+                };
+                println!(
+                    "  #{:4} | {:>30} | {:>40} | {:>35} | {}",
+                    user.id, user.username, email, status, perms
+                );
             }
         }
+        User::Update(UpdateUser {
+            username,
+            new_username,
+            email,
+            clear_email,
+        }) => {
+            println!("update user: {:?}", username);
+            let user = database.get_user_by_username(&username)?;
+            let email = if clear_email {
+                Some(None)
+            } else {
+                email.map(Some)
+            };
+            database.update_user(
+                user.id,
+                enigma::user::UserUpdate {
+                    username: new_username,
+                    email,
+                },
+            )?;
+        }
+        User::Disable(DisableUser {
+            username,
+            reason,
+            until,
+        }) => {
+            println!("disable user: {:?}", username);
+            let user = database.get_user_by_username(&username)?;
+            database.disable_user(user.id, reason.as_deref(), until)?;
+        }
+        User::Enable(EnableUser { username }) => {
+            println!("enable user: {:?}", username);
+            let user = database.get_user_by_username(&username)?;
+            database.enable_user(user.id)?;
+        }
+        User::Passwd(Passwd {
+            username,
+            password,
+            old_password,
+        }) => {
+            println!("change password: {:?}", username);
+            let user = database.get_user_by_username(&username)?;
+            match old_password {
+                Some(old_password) => {
+                    database.change_password(user.id, &old_password, &password)?
+                }
+                None => database.set_password(user.id, &password)?,
+            }
+        }
+        User::RecoveryCodes(RecoveryCodes { username }) => {
+            println!("generate recovery codes: {:?}", username);
+            let user = database.get_user_by_username(&username)?;
+            for code in database.generate_recovery_codes(user.id)? {
+                println!("  {}", code);
+            }
+        }
+        User::SessionLimit(SessionLimit { username, limit }) => {
+            println!("set session limit: {:?} {:?}", username, limit);
+            let user = database.get_user_by_username(&username)?;
+            database.set_session_limit(user.id, limit)?;
+        }
     }
 
     Ok(())
@@ -153,10 +604,11 @@ fn cli_perms(mut database: Database, cmd: Perm) -> Result<()> {
             username,
             site,
             permission,
+            until,
         }) => {
             println!("add permission: {:?} {:?} {:?}", username, site, permission);
             let user = database.get_user_by_username(&username)?;
-            database.add_permission(user.id, &site, &permission)?;
+            database.add_permission(user.id, &site, &permission, until)?;
         }
         Perm::Remove(RemovePerm {
             username,
@@ -174,3 +626,222 @@ fn cli_perms(mut database: Database, cmd: Perm) -> Result<()> {
 
     Ok(())
 }
+
+fn cli_roles(mut database: Database, cmd: Role) -> Result<()> {
+    match cmd {
+        Role::Create(CreateRole { name, description }) => {
+            println!("create role: {:?}", name);
+            database.create_role(&name, description.as_deref())?;
+        }
+        Role::Delete(DeleteRole { name }) => {
+            println!("delete role: {:?}", name);
+            let role = database.get_role_by_name(&name)?;
+            database.delete_role(role.id)?;
+        }
+        Role::List => {
+            let roles = database.list_roles()?;
+            println!("found {} roles:", roles.len());
+            for role in roles {
+                let perms = role
+                    .permissions
+                    .iter()
+                    .map(|p| format!("{}:{}", p.site, p.permission))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                println!(
+                    "  #{:4} | {:>30} | {:>40} | {}",
+                    role.id,
+                    role.name,
+                    role.description.unwrap_or_default(),
+                    perms
+                );
+            }
+        }
+        Role::AddPerm(RolePerm {
+            name,
+            site,
+            permission,
+        }) => {
+            println!(
+                "add role permission: {:?} {:?} {:?}",
+                name, site, permission
+            );
+            let role = database.get_role_by_name(&name)?;
+            database.add_role_permission(role.id, &site, &permission)?;
+        }
+        Role::RemovePerm(RolePerm {
+            name,
+            site,
+            permission,
+        }) => {
+            println!(
+                "remove role permission: {:?} {:?} {:?}",
+                name, site, permission
+            );
+            let role = database.get_role_by_name(&name)?;
+            database.remove_role_permission(role.id, &site, &permission)?;
+        }
+        Role::Assign(RoleMember { username, name }) => {
+            println!("assign role: {:?} {:?}", username, name);
+            let user = database.get_user_by_username(&username)?;
+            let role = database.get_role_by_name(&name)?;
+            database.assign_role(user.id, role.id)?;
+        }
+        Role::Unassign(RoleMember { username, name }) => {
+            println!("unassign role: {:?} {:?}", username, name);
+            let user = database.get_user_by_username(&username)?;
+            let role = database.get_role_by_name(&name)?;
+            database.unassign_role(user.id, role.id)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cli_keys(mut database: Database, cmd: Key) -> Result<()> {
+    match cmd {
+        Key::Mint(MintKey {
+            username,
+            name,
+            scopes,
+            until,
+        }) => {
+            println!("mint api key: {:?} {:?}", username, name);
+            let user = database.get_user_by_username(&username)?;
+            let minted = database.create_api_key(user.id, &name, &scopes, until)?;
+            println!("  #{}: {}", minted.api_key.id, minted.key);
+        }
+        Key::List(ListKeys { username }) => {
+            let user = database.get_user_by_username(&username)?;
+            let api_keys = database.list_api_keys(user.id)?;
+            println!("found {} api keys:", api_keys.len());
+            for api_key in api_keys {
+                let scopes = api_key
+                    .scopes
+                    .iter()
+                    .map(|p| format!("{}:{}", p.site, p.permission))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let expiry_date = api_key
+                    .expiry_date
+                    .map(|date| date.to_string())
+                    .unwrap_or_else(|| "never".into());
+                let last_used_at = api_key
+                    .last_used_at
+                    .map(|date| date.to_string())
+                    .unwrap_or_else(|| "never".into());
+                println!(
+                    "  #{:4} | {:>30} | expires {:>30} | used {:>30} | {}",
+                    api_key.id, api_key.name, expiry_date, last_used_at, scopes
+                );
+            }
+        }
+        Key::Revoke(RevokeKey { username, id }) => {
+            println!("revoke api key: {:?} #{}", username, id);
+            let user = database.get_user_by_username(&username)?;
+            database.revoke_api_key(user.id, id)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cli_sessions(mut database: Database, cmd: SessionCmd) -> Result<()> {
+    match cmd {
+        SessionCmd::List(ListSessions { username }) => {
+            let user = database.get_user_by_username(&username)?;
+            let sessions = database.list_sessions(user.id, None)?;
+            println!("found {} sessions:", sessions.len());
+            for session in sessions {
+                let track = &session.track;
+                let client = [&track.device, &track.os, &track.browser]
+                    .into_iter()
+                    .flatten()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" / ");
+                println!(
+                    "  #{:4} | last used {} | created {} | {:>15} | {:>20} | {}",
+                    session.id,
+                    session.last_used_at,
+                    session.created_at,
+                    track.ip_address.as_deref().unwrap_or("-"),
+                    track.location.as_deref().unwrap_or("-"),
+                    client
+                );
+            }
+        }
+        SessionCmd::Revoke(RevokeSession { username, id, all }) => {
+            let user = database.get_user_by_username(&username)?;
+            match id {
+                Some(id) if !all => {
+                    println!("revoke session: {:?} #{}", username, id);
+                    database.revoke_session_by_id(user.id, id)?;
+                }
+                _ => {
+                    println!("revoke all sessions: {:?}", username);
+                    database.revoke_all_sessions(user.id, None)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_signing_key_file(path: &Path) -> Result<SigningKeyEncryptionKey> {
+    let value = std::fs::read_to_string(path)?;
+    Ok(SigningKeyEncryptionKey::from_hex(&value)?)
+}
+
+fn cli_signing_keys(
+    database: Database,
+    cmd: SigningKeyCmd,
+    signing_key_encryption_key: Option<SigningKeyEncryptionKey>,
+) -> Result<()> {
+    let mut database = database.with_session_policy(SessionPolicy {
+        signing_key_encryption_key,
+        ..Default::default()
+    });
+    match cmd {
+        SigningKeyCmd::Rotate => {
+            let kid = database.rotate_signing_key()?;
+            println!("new signing key: {}", kid);
+        }
+        SigningKeyCmd::Export => {
+            let key_set = database.signing_key_set()?;
+            println!("{}", serde_json::to_string_pretty(&key_set)?);
+        }
+        SigningKeyCmd::GenerateEncryptionKey => {
+            println!("{}", SigningKeyEncryptionKey::generate().to_hex());
+        }
+        SigningKeyCmd::Prune(PruneSigningKeys {
+            signed_token_minutes,
+        }) => {
+            let mut database = database.with_session_policy(SessionPolicy {
+                signed_token_lifetime: Some(Duration::minutes(signed_token_minutes)),
+                ..Default::default()
+            });
+            database.delete_retired_signing_keys()?;
+            database.delete_expired_signed_token_revocations()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cli_qr(mut database: Database, Qr { session_token }: Qr) -> Result<()> {
+    let qr_token = database.create_qr_token(&session_token)?;
+    let code = QrCode::new(qr_token.qr_token.as_bytes())?;
+    let image = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+
+    println!("{}", image);
+    println!("qr token: {}", qr_token.qr_token);
+    println!("expires: {}", qr_token.expiry_date);
+
+    Ok(())
+}
@@ -0,0 +1,411 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Result;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use enigma::user::{CreateUser, UserUpdate};
+use enigma::{
+    CreateSession, Database, EnigmaState, ForwardAuth, JsonWebKeySet, Permission,
+    RequirePermission, SecondFactorVerify, Session, SessionCreate, SessionInfo, SessionVerify,
+    TotpEnrollment, User, VerifySession,
+};
+
+/// Maps enigma errors to HTTP responses with a JSON `{ "error": ... }` body.
+pub struct ApiError(enigma::Error);
+
+impl From<enigma::Error> for ApiError {
+    fn from(err: enigma::Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            enigma::Error::UserNotFound => StatusCode::NOT_FOUND,
+            enigma::Error::PasswordIncorrect
+            | enigma::Error::SessionNotFound
+            | enigma::Error::ChallengeNotFound
+            | enigma::Error::ChallengeExpired
+            | enigma::Error::TotpCodeIncorrect
+            | enigma::Error::RecoveryCodeIncorrect
+            | enigma::Error::ApiKeyNotFound
+            | enigma::Error::ApiKeyExpired
+            | enigma::Error::RefreshTokenNotFound
+            | enigma::Error::RefreshTokenExpired
+            | enigma::Error::RefreshTokenReused => StatusCode::UNAUTHORIZED,
+            enigma::Error::SignedTokensDisabled => StatusCode::NOT_FOUND,
+            enigma::Error::ScopeNotGranted { .. } => StatusCode::FORBIDDEN,
+            enigma::Error::UserDisabled { .. } => StatusCode::FORBIDDEN,
+            enigma::Error::SessionLimitReached { .. } => StatusCode::FORBIDDEN,
+            enigma::Error::VerificationTokenNotFound | enigma::Error::VerificationTokenExpired => {
+                StatusCode::BAD_REQUEST
+            }
+            enigma::Error::TotpNotEnrolled
+            | enigma::Error::TotpAlreadyEnrolled
+            | enigma::Error::UsernameTaken
+            | enigma::Error::EmailTaken => StatusCode::CONFLICT,
+            enigma::Error::TooManyAttempts { retry_after } => {
+                let seconds = (*retry_after - chrono::Utc::now()).num_seconds().max(1);
+                let body = Json(serde_json::json!({ "error": self.0.to_string() }));
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, seconds.to_string())],
+                    body,
+                )
+                    .into_response();
+            }
+            err => {
+                tracing::error!("request failed: {:?}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        let body = Json(serde_json::json!({ "error": self.0.to_string() }));
+        (status, body).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+fn lock(state: &EnigmaState) -> std::sync::MutexGuard<'_, Database> {
+    state
+        .database
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Proxies whose `X-Forwarded-For` header is trusted for the client address.
+#[derive(Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// The address of the peer, or, if the peer is a trusted proxy, the last
+    /// forwarded address that is not one.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.0.contains(&peer) {
+            return peer;
+        }
+
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|address| address.trim().parse::<IpAddr>())
+            .collect::<Vec<_>>();
+        match forwarded
+            .into_iter()
+            .rev()
+            .find(|address| address.as_ref().map_or(true, |ip| !self.0.contains(ip)))
+        {
+            Some(Ok(ip)) => ip,
+            _ => peer,
+        }
+    }
+}
+
+async fn create_session(
+    State(state): State<EnigmaState>,
+    Extension(trusted_proxies): Extension<TrustedProxies>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<SessionCreate>,
+) -> ApiResult<Response> {
+    // the throttle is keyed by this address, so never take it from the body
+    let mut track = body.track();
+    track.ip_address = Some(trusted_proxies.client_ip(addr.ip(), &headers).to_string());
+
+    let response = match lock(&state).create_session(&body.username, &body.password, track)? {
+        CreateSession::Session(session) => Json(session).into_response(),
+        CreateSession::SecondFactorRequired(challenge) => {
+            (StatusCode::ACCEPTED, Json(challenge)).into_response()
+        }
+    };
+    Ok(response)
+}
+
+async fn complete_totp_challenge(
+    State(state): State<EnigmaState>,
+    Json(body): Json<SecondFactorVerify>,
+) -> ApiResult<Json<Session>> {
+    let session = lock(&state).complete_totp_challenge(&body.challenge_token, &body.code)?;
+    Ok(Json(session))
+}
+
+async fn complete_recovery_code_challenge(
+    State(state): State<EnigmaState>,
+    Json(body): Json<SecondFactorVerify>,
+) -> ApiResult<Json<Session>> {
+    let session =
+        lock(&state).complete_recovery_code_challenge(&body.challenge_token, &body.code)?;
+    Ok(Json(session))
+}
+
+#[derive(serde::Deserialize)]
+struct TotpConfirm {
+    code: String,
+}
+
+async fn enroll_totp(
+    State(state): State<EnigmaState>,
+    session: Session,
+) -> ApiResult<Json<TotpEnrollment>> {
+    Ok(Json(lock(&state).enroll_totp(session.user.id)?))
+}
+
+async fn confirm_totp(
+    State(state): State<EnigmaState>,
+    session: Session,
+    Json(body): Json<TotpConfirm>,
+) -> ApiResult<StatusCode> {
+    lock(&state).confirm_totp(session.user.id, &body.code)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize)]
+struct EmailVerify {
+    token: String,
+}
+
+async fn verify_email(
+    State(state): State<EnigmaState>,
+    Json(body): Json<EmailVerify>,
+) -> ApiResult<Json<User>> {
+    Ok(Json(lock(&state).verify_email(&body.token)?))
+}
+
+async fn verify_session(
+    State(state): State<EnigmaState>,
+    Json(body): Json<SessionVerify>,
+) -> ApiResult<Response> {
+    let response = match lock(&state).verify_session(&body.session_token)? {
+        VerifySession::Session(session) => Json(session).into_response(),
+        VerifySession::SessionNotFound => error(StatusCode::UNAUTHORIZED, "session not found"),
+        VerifySession::SessionExpired => error(StatusCode::UNAUTHORIZED, "session expired"),
+        VerifySession::SessionIdleTimeout => error(
+            StatusCode::UNAUTHORIZED,
+            "session expired due to inactivity",
+        ),
+        VerifySession::UserDisabled { .. } => error(StatusCode::FORBIDDEN, "user disabled"),
+    };
+    Ok(response)
+}
+
+#[derive(serde::Deserialize)]
+struct SessionRefresh {
+    refresh_token: String,
+}
+
+async fn refresh_session(
+    State(state): State<EnigmaState>,
+    Json(body): Json<SessionRefresh>,
+) -> ApiResult<Json<Session>> {
+    Ok(Json(lock(&state).refresh_session(&body.refresh_token)?))
+}
+
+#[derive(serde::Serialize)]
+struct SignedToken {
+    signed_token: String,
+}
+
+async fn issue_signed_token(
+    State(state): State<EnigmaState>,
+    session: Session,
+) -> ApiResult<Json<SignedToken>> {
+    let signed_token = lock(&state).issue_signed_token(&session.session_token)?;
+    Ok(Json(SignedToken { signed_token }))
+}
+
+async fn signing_key_set(State(state): State<EnigmaState>) -> ApiResult<Json<JsonWebKeySet>> {
+    Ok(Json(lock(&state).signing_key_set()?))
+}
+
+async fn revoked_signed_tokens(State(state): State<EnigmaState>) -> ApiResult<Json<Vec<String>>> {
+    Ok(Json(lock(&state).revoked_signed_tokens()?))
+}
+
+async fn delete_session(
+    State(state): State<EnigmaState>,
+    Json(body): Json<SessionVerify>,
+) -> ApiResult<StatusCode> {
+    lock(&state).delete_session(&body.session_token)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_sessions(
+    State(state): State<EnigmaState>,
+    session: Session,
+) -> ApiResult<Json<Vec<SessionInfo>>> {
+    let sessions = lock(&state).list_sessions(session.user.id, Some(&session.session_token))?;
+    Ok(Json(sessions))
+}
+
+async fn revoke_session(
+    State(state): State<EnigmaState>,
+    session: Session,
+    Path(session_id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    lock(&state).revoke_session_by_id(session.user.id, session_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_users(State(state): State<EnigmaState>) -> ApiResult<Json<Vec<User>>> {
+    Ok(Json(lock(&state).list_users()?))
+}
+
+async fn create_user(
+    State(state): State<EnigmaState>,
+    Json(body): Json<CreateUser>,
+) -> ApiResult<(StatusCode, Json<User>)> {
+    let mut database = lock(&state);
+    let user_id = database.create_user(body)?;
+    Ok((StatusCode::CREATED, Json(database.get_user_by_id(user_id)?)))
+}
+
+async fn get_user(
+    State(state): State<EnigmaState>,
+    Path(username): Path<String>,
+) -> ApiResult<Json<User>> {
+    Ok(Json(lock(&state).get_user_by_username(&username)?))
+}
+
+async fn update_user(
+    State(state): State<EnigmaState>,
+    Path(username): Path<String>,
+    Json(body): Json<UserUpdate>,
+) -> ApiResult<Json<User>> {
+    let mut database = lock(&state);
+    let user = database.get_user_by_username(&username)?;
+    Ok(Json(database.update_user(user.id, body)?))
+}
+
+async fn delete_user(
+    State(state): State<EnigmaState>,
+    Path(username): Path<String>,
+) -> ApiResult<StatusCode> {
+    let mut database = lock(&state);
+    database.get_user_by_username(&username)?;
+    database.delete_user_by_username(&username)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize)]
+struct PermissionGrant {
+    site: String,
+    permission: String,
+    #[serde(default)]
+    expiry_date: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+async fn add_permission(
+    State(state): State<EnigmaState>,
+    Path(username): Path<String>,
+    Json(body): Json<PermissionGrant>,
+) -> ApiResult<Json<User>> {
+    let mut database = lock(&state);
+    let user = database.get_user_by_username(&username)?;
+    database.add_permission(user.id, &body.site, &body.permission, body.expiry_date)?;
+    Ok(Json(database.get_user_by_id(user.id)?))
+}
+
+async fn remove_permission(
+    State(state): State<EnigmaState>,
+    Path(username): Path<String>,
+    Json(body): Json<Permission>,
+) -> ApiResult<Json<User>> {
+    let mut database = lock(&state);
+    let user = database.get_user_by_username(&username)?;
+    database.remove_permission(user.id, &body.site, &body.permission)?;
+    Ok(Json(database.get_user_by_id(user.id)?))
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// Builds the REST API. User and permission management requires a session
+/// with `admin_permission` on `admin_site`.
+pub fn router(
+    state: EnigmaState,
+    admin_site: &str,
+    admin_permission: &str,
+    forward_auth: ForwardAuth,
+    trusted_proxies: TrustedProxies,
+) -> Router {
+    let admin = Router::new()
+        .route("/users", get(list_users).post(create_user))
+        .route(
+            "/users/:username",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        .route(
+            "/users/:username/permissions",
+            post(add_permission).delete(remove_permission),
+        )
+        .route_layer(RequirePermission::new(
+            state.clone(),
+            admin_site,
+            admin_permission,
+        ));
+
+    Router::new()
+        .route(
+            "/sessions",
+            get(list_sessions)
+                .post(create_session)
+                .delete(delete_session),
+        )
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/sessions/verify", post(verify_session))
+        .route("/sessions/refresh", post(refresh_session))
+        .route("/sessions/signed-token", post(issue_signed_token))
+        .route("/sessions/signed-token/revoked", get(revoked_signed_tokens))
+        .route("/.well-known/jwks.json", get(signing_key_set))
+        .route("/sessions/totp", post(complete_totp_challenge))
+        .route(
+            "/sessions/recovery-code",
+            post(complete_recovery_code_challenge),
+        )
+        .route("/totp", post(enroll_totp))
+        .route("/totp/confirm", post(confirm_totp))
+        .route("/email/verify", post(verify_email))
+        .route("/forward-auth", forward_auth.route(state.clone()))
+        .merge(admin)
+        .layer(Extension(trusted_proxies))
+        .with_state(state)
+}
+
+pub fn serve(
+    database: Database,
+    addr: SocketAddr,
+    admin_site: &str,
+    admin_permission: &str,
+    forward_auth: ForwardAuth,
+    trusted_proxies: TrustedProxies,
+) -> Result<()> {
+    let state = EnigmaState::new(database);
+    let router = router(
+        state,
+        admin_site,
+        admin_permission,
+        forward_auth,
+        trusted_proxies,
+    );
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tracing::info!("listening on {}", listener.local_addr()?);
+
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+        Ok(())
+    })
+}
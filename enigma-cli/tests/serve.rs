@@ -0,0 +1,201 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+struct Server {
+    child: Child,
+    database_path: PathBuf,
+    base_url: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.database_path);
+    }
+}
+
+fn cli(database_path: &PathBuf, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_enigma-cli"))
+        .arg("--path")
+        .arg(database_path)
+        .args(args)
+        .status()
+        .expect("failed to run enigma-cli");
+    assert!(status.success(), "enigma-cli {:?} failed", args);
+}
+
+// Creates a database with an `admin` user and starts `enigma-cli serve` on a
+// free localhost port.
+fn start_server(name: &str) -> Server {
+    start_server_with(name, &[])
+}
+
+fn start_server_with(name: &str, serve_args: &[&str]) -> Server {
+    let database_path =
+        std::env::temp_dir().join(format!("enigma-cli-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&database_path);
+
+    cli(&database_path, &["user", "create", "admin", "secret"]);
+    cli(&database_path, &["perm", "add", "admin", "enigma", "admin"]);
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_enigma-cli"))
+        .arg("--path")
+        .arg(&database_path)
+        .args(["serve", "--addr", &addr.to_string()])
+        .args(serve_args)
+        .spawn()
+        .expect("failed to start server");
+
+    let server = Server {
+        child,
+        database_path,
+        base_url: format!("http://{}", addr),
+    };
+    wait_for(addr);
+    server
+}
+
+fn wait_for(addr: SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    panic!("server did not start on {}", addr);
+}
+
+fn status(result: Result<ureq::Response, ureq::Error>) -> u16 {
+    match result {
+        Ok(response) => response.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(err) => panic!("request failed: {}", err),
+    }
+}
+
+impl Server {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn login(&self, username: &str, password: &str) -> String {
+        let session: Value = ureq::post(&self.url("/sessions"))
+            .send_json(json!({ "username": username, "password": password }))
+            .expect("failed to create session")
+            .into_json()
+            .unwrap();
+        session["session_token"].as_str().unwrap().to_string()
+    }
+}
+
+#[test]
+fn test_session_lifecycle() {
+    let server = start_server("sessions");
+
+    let result = ureq::post(&server.url("/sessions"))
+        .send_json(json!({ "username": "admin", "password": "wrong" }));
+    assert_eq!(status(result), 401);
+
+    let session_token = server.login("admin", "secret");
+
+    let session: Value = ureq::post(&server.url("/sessions/verify"))
+        .send_json(json!({ "session_token": session_token }))
+        .unwrap()
+        .into_json()
+        .unwrap();
+    assert_eq!(session["user"]["username"], "admin");
+    assert_eq!(session["track"]["ip_address"], "127.0.0.1");
+
+    let result =
+        ureq::delete(&server.url("/sessions")).send_json(json!({ "session_token": session_token }));
+    assert_eq!(status(result), 204);
+
+    let result = ureq::post(&server.url("/sessions/verify"))
+        .send_json(json!({ "session_token": session_token }));
+    assert_eq!(status(result), 401);
+}
+
+// Logs in with a spoofed address in the body and the X-Forwarded-For header and
+// returns the address recorded for the session.
+fn login_ip_address(server: &Server) -> Value {
+    let session: Value = ureq::post(&server.url("/sessions"))
+        .set("X-Forwarded-For", "10.0.0.1, 10.0.0.2")
+        .send_json(json!({
+            "username": "admin",
+            "password": "secret",
+            "ip_address": "10.0.0.3"
+        }))
+        .unwrap()
+        .into_json()
+        .unwrap();
+    session["track"]["ip_address"].clone()
+}
+
+#[test]
+fn test_client_address() {
+    let server = start_server("client-address");
+    assert_eq!(login_ip_address(&server), "127.0.0.1");
+
+    let server = start_server_with("trusted-proxy", &["--trusted-proxy", "127.0.0.1"]);
+    assert_eq!(login_ip_address(&server), "10.0.0.2");
+}
+
+#[test]
+fn test_user_management() {
+    let server = start_server("users");
+    let admin = format!("Bearer {}", server.login("admin", "secret"));
+
+    let result = ureq::get(&server.url("/users")).call();
+    assert_eq!(status(result), 401);
+
+    let result = ureq::post(&server.url("/users"))
+        .set("Authorization", &admin)
+        .send_json(json!({ "username": "bob", "password": "hunter2", "email": null }));
+    assert_eq!(status(result), 201);
+
+    let user: Value = ureq::post(&server.url("/users/bob/permissions"))
+        .set("Authorization", &admin)
+        .send_json(json!({ "site": "example.com", "permission": "read" }))
+        .unwrap()
+        .into_json()
+        .unwrap();
+    assert_eq!(
+        user["permissions"],
+        json!([{ "site": "example.com", "permission": "read" }])
+    );
+
+    let bob = format!("Bearer {}", server.login("bob", "hunter2"));
+    let result = ureq::get(&server.url("/users"))
+        .set("Authorization", &bob)
+        .call();
+    assert_eq!(status(result), 403);
+
+    let users: Value = ureq::get(&server.url("/users"))
+        .set("Authorization", &admin)
+        .call()
+        .unwrap()
+        .into_json()
+        .unwrap();
+    assert_eq!(users.as_array().unwrap().len(), 2);
+
+    let result = ureq::delete(&server.url("/users/bob"))
+        .set("Authorization", &admin)
+        .call();
+    assert_eq!(status(result), 204);
+
+    let result = ureq::get(&server.url("/users/bob"))
+        .set("Authorization", &admin)
+        .call();
+    assert_eq!(status(result), 404);
+}
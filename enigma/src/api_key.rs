@@ -0,0 +1,326 @@
+use chrono::DateTime;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::token::{generate_token, hash_token};
+use crate::Error;
+use crate::Permission;
+use crate::PermissionPolicy;
+use crate::Result;
+use crate::User;
+
+use super::Database;
+
+/// Prefix of every API key, so leaked keys are easy to recognize.
+pub const API_KEY_PREFIX: &str = "enigma_";
+
+/// Whether the user may hand `scope` to a key. Scopes with `*` or `?` are
+/// patterns rather than a single site or permission, so they need an
+/// identical grant.
+fn scope_granted(user: &User, policy: &PermissionPolicy, scope: &Permission) -> bool {
+    let is_pattern = |value: &str| value.contains(['*', '?']);
+    if is_pattern(&scope.site) || is_pattern(&scope.permission) {
+        user.permissions.contains(scope)
+    } else {
+        user.has_permission_with(policy, &scope.site, &scope.permission)
+    }
+}
+
+/// A key for services acting on behalf of a user, limited to `scopes`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub scopes: Vec<Permission>,
+    pub expiry_date: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Returned by `create_api_key`. Only a hash of `key` is stored, so it
+/// cannot be shown again.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MintedApiKey {
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+struct InnerApiKey {
+    id: i64,
+    user_id: i64,
+    name: String,
+    expiry_date: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerApiKey {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            name: row.get("name")?,
+            expiry_date: row.get("expiry_date")?,
+            created_at: row.get("created_at")?,
+            last_used_at: row.get("last_used_at")?,
+        })
+    }
+}
+
+impl InnerApiKey {
+    fn into_api_key(self, tx: &Transaction<'_>) -> Result<ApiKey> {
+        Ok(ApiKey {
+            scopes: Database::tx_get_api_key_scopes(tx, self.id)?,
+            id: self.id,
+            user_id: self.user_id,
+            name: self.name,
+            expiry_date: self.expiry_date,
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+        })
+    }
+}
+
+impl Database {
+    fn tx_get_api_key_scopes(tx: &Transaction<'_>, api_key_id: i64) -> Result<Vec<Permission>> {
+        let query = Query::select_from("api_key_scopes")
+            .column("site")
+            .column("permission")
+            .condition(query::eq(query::column("api_key_id"), param(1)))
+            .into_query();
+
+        struct InnerScope {
+            site: String,
+            permission: String,
+        }
+
+        impl FromRow for InnerScope {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    site: row.get("site")?,
+                    permission: row.get("permission")?,
+                })
+            }
+        }
+
+        let scopes = query.select_many::<InnerScope>(tx, params![api_key_id])?;
+        let scopes = scopes
+            .into_iter()
+            .map(|scope| Permission {
+                site: scope.site,
+                permission: scope.permission,
+            })
+            .collect();
+        Ok(scopes)
+    }
+
+    fn tx_get_api_key(tx: &Transaction<'_>, id: i64) -> Result<ApiKey> {
+        let query = Query::select_from("api_keys")
+            .all_columns()
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        query
+            .select_maybe::<InnerApiKey>(tx, params![id])?
+            .ok_or(Error::ApiKeyNotFound)?
+            .into_api_key(tx)
+    }
+
+    fn tx_delete_api_key(tx: &Transaction<'_>, id: i64) -> Result<()> {
+        let query = Query::delete_from("api_key_scopes")
+            .condition(query::eq(query::column("api_key_id"), param(1)))
+            .into_query();
+        query.delete(tx, params![id])?;
+
+        let query = Query::delete_from("api_keys")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+        query.delete(tx, params![id])?;
+        Ok(())
+    }
+
+    /// Mints a key for the user. Every scope has to be covered by the
+    /// user's own permissions.
+    pub fn create_api_key(
+        &mut self,
+        user_id: i64,
+        name: &str,
+        scopes: &[Permission],
+        expiry_date: Option<DateTime<Utc>>,
+    ) -> Result<MintedApiKey> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] create_api_key:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  name: {:?}", name);
+        tracing::trace!("  scopes: {:?}", scopes);
+        tracing::trace!("  expiry_date: {:?}", expiry_date);
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        for scope in scopes {
+            if !scope_granted(&user, &self.permission_policy, scope) {
+                return Err(Error::ScopeNotGranted {
+                    site: scope.site.clone(),
+                    permission: scope.permission.clone(),
+                });
+            }
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, generate_token());
+        let id = {
+            let query = Query::insert_into("api_keys")
+                .column("user_id", param(1))
+                .column("name", param(2))
+                .column("key_hash", param(3))
+                .column("expiry_date", param(4))
+                .column("created_at", param(5))
+                .into_query();
+            let id = query.insert(
+                &tx,
+                params![user_id, name, hash_token(&key), expiry_date, Utc::now()],
+            )?;
+
+            let query = Query::insert_into("api_key_scopes")
+                .or_ignore()
+                .column("api_key_id", param(1))
+                .column("site", param(2))
+                .column("permission", param(3))
+                .into_query();
+            for scope in scopes {
+                query.insert(&tx, params![id, scope.site, scope.permission])?;
+            }
+            id
+        };
+
+        let api_key = Self::tx_get_api_key(&tx, id)?;
+        tx.commit()?;
+        Ok(MintedApiKey { key, api_key })
+    }
+
+    /// Returns the key's owner with only the key's scopes as permissions.
+    /// Scopes the owner has lost since the key was minted are dropped.
+    pub fn verify_api_key(&mut self, key: &str) -> Result<User> {
+        let tx = self.database.transaction()?;
+
+        let key_hash = hash_token(key);
+        tracing::trace!("[database] verify_api_key:");
+        tracing::trace!("  key_hash: {:?}", key_hash);
+
+        let api_key = {
+            let query = Query::select_from("api_keys")
+                .all_columns()
+                .condition(query::eq(query::column("key_hash"), param(1)))
+                .into_query();
+            query
+                .select_maybe::<InnerApiKey>(&tx, params![key_hash])?
+                .ok_or(Error::ApiKeyNotFound)?
+                .into_api_key(&tx)?
+        };
+
+        let now = Utc::now();
+        if api_key
+            .expiry_date
+            .is_some_and(|expiry_date| expiry_date < now)
+        {
+            return Err(Error::ApiKeyExpired);
+        }
+
+        Self::tx_check_user_active(&tx, api_key.user_id)?;
+        let mut user = Self::tx_get_user_by_id(&tx, api_key.user_id)?;
+        user.permissions = api_key
+            .scopes
+            .into_iter()
+            .filter(|scope| scope_granted(&user, &self.permission_policy, scope))
+            .collect();
+
+        {
+            let query = Query::update("api_keys")
+                .set("last_used_at", param(1))
+                .condition(query::eq(query::column("id"), param(2)))
+                .into_query();
+            query.update(&tx, params![now, api_key.id])?;
+        }
+
+        tx.commit()?;
+        Ok(user)
+    }
+
+    pub fn list_api_keys(&mut self, user_id: i64) -> Result<Vec<ApiKey>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] list_api_keys:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let query = Query::select_from("api_keys")
+            .all_columns()
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+
+        let api_keys = query
+            .select_many::<InnerApiKey>(&tx, params![user_id])?
+            .into_iter()
+            .map(|api_key| api_key.into_api_key(&tx))
+            .collect::<Result<Vec<_>>>()?;
+
+        tx.commit()?;
+        Ok(api_keys)
+    }
+
+    pub fn revoke_api_key(&mut self, user_id: i64, id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] revoke_api_key:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  id: {:?}", id);
+
+            let api_key = Self::tx_get_api_key(&tx, id)?;
+            if api_key.user_id != user_id {
+                return Err(Error::ApiKeyNotFound);
+            }
+
+            Self::tx_delete_api_key(&tx, id)?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_expired_api_keys(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_api_keys");
+            let query = Query::select_from("api_keys")
+                .column("id")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            struct InnerId {
+                id: i64,
+            }
+
+            impl FromRow for InnerId {
+                fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                    Ok(Self { id: row.get("id")? })
+                }
+            }
+
+            let expired = query.select_many::<InnerId>(&tx, params![Utc::now()])?;
+            for api_key in expired {
+                Self::tx_delete_api_key(&tx, api_key.id)?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
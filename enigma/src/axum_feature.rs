@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use axum::extract::{FromRef, FromRequestParts, Request};
+use axum::http::header::{AUTHORIZATION, COOKIE, LOCATION};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, MethodRouter};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::permission::glob_match;
+use crate::token::url_encode;
+use crate::{Database, Session, User, VerifySession};
+
+/// Shared state for the axum extractors and layers. Add it to the router state
+/// (directly or through `FromRef`) to extract `Session` and `User` in handlers.
+#[derive(Clone)]
+pub struct EnigmaState {
+    pub database: Arc<Mutex<Database>>,
+    pub cookie_name: String,
+}
+
+impl EnigmaState {
+    pub fn new(database: Database) -> Self {
+        Self {
+            database: Arc::new(Mutex::new(database)),
+            cookie_name: "enigma_session".into(),
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: impl Into<String>) -> Self {
+        self.cookie_name = cookie_name.into();
+        self
+    }
+
+    /// Finds the session token in an `Authorization: Bearer` header or, if
+    /// there is none, in the session cookie.
+    pub fn session_token(&self, headers: &HeaderMap) -> Option<String> {
+        let bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        if bearer.is_some() {
+            return bearer;
+        }
+
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, token)| token.to_string())
+    }
+
+    /// Checks a permission with the database's `PermissionPolicy`.
+    pub fn has_permission(
+        &self,
+        user: &User,
+        site: &str,
+        permission: &str,
+    ) -> Result<bool, AuthRejection> {
+        let database = self.database.lock().map_err(|_| AuthRejection::Internal)?;
+        Ok(user.has_permission_with(database.permission_policy(), site, permission))
+    }
+
+    pub fn verify(&self, headers: &HeaderMap) -> Result<Session, AuthRejection> {
+        let session_token = self
+            .session_token(headers)
+            .ok_or(AuthRejection::MissingToken)?;
+
+        let mut database = self.database.lock().map_err(|_| AuthRejection::Internal)?;
+
+        match database.verify_session(&session_token) {
+            Ok(VerifySession::Session(session)) => Ok(session),
+            Ok(VerifySession::SessionNotFound) => Err(AuthRejection::SessionNotFound),
+            Ok(VerifySession::SessionExpired) => Err(AuthRejection::SessionExpired),
+            Ok(VerifySession::SessionIdleTimeout) => Err(AuthRejection::SessionIdleTimeout),
+            Ok(VerifySession::UserDisabled { .. }) => Err(AuthRejection::UserDisabled),
+            Err(err) => {
+                tracing::error!("failed to verify session: {:?}", err);
+                Err(AuthRejection::Internal)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthRejection {
+    MissingToken,
+    SessionNotFound,
+    SessionExpired,
+    SessionIdleTimeout,
+    UserDisabled,
+    Forbidden,
+    Internal,
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AuthRejection::MissingToken => (StatusCode::UNAUTHORIZED, "missing session token"),
+            AuthRejection::SessionNotFound => (StatusCode::UNAUTHORIZED, "session not found"),
+            AuthRejection::SessionExpired => (StatusCode::UNAUTHORIZED, "session expired"),
+            AuthRejection::SessionIdleTimeout => (
+                StatusCode::UNAUTHORIZED,
+                "session expired due to inactivity",
+            ),
+            AuthRejection::UserDisabled => (StatusCode::FORBIDDEN, "user disabled"),
+            AuthRejection::Forbidden => (StatusCode::FORBIDDEN, "permission denied"),
+            AuthRejection::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal error"),
+        };
+
+        (status, message).into_response()
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for Session
+where
+    S: Send + Sync,
+    EnigmaState: FromRef<S>,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        EnigmaState::from_ref(state).verify(&parts.headers)
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+    EnigmaState: FromRef<S>,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // `RequirePermission` has already verified the session
+        if let Some(user) = parts.extensions.get::<User>() {
+            return Ok(user.clone());
+        }
+
+        Ok(EnigmaState::from_ref(state).verify(&parts.headers)?.user)
+    }
+}
+
+/// Layer that only lets requests through if their session's user has the
+/// permission on the site. The user is added to the request extensions.
+#[derive(Clone)]
+pub struct RequirePermission {
+    state: EnigmaState,
+    site: String,
+    permission: String,
+}
+
+impl RequirePermission {
+    pub fn new(state: EnigmaState, site: impl Into<String>, permission: impl Into<String>) -> Self {
+        Self {
+            state,
+            site: site.into(),
+            permission: permission.into(),
+        }
+    }
+
+    fn authorize(&self, parts: &Parts) -> Result<User, AuthRejection> {
+        let user = self.state.verify(&parts.headers)?.user;
+        if self
+            .state
+            .has_permission(&user, &self.site, &self.permission)?
+        {
+            Ok(user)
+        } else {
+            Err(AuthRejection::Forbidden)
+        }
+    }
+}
+
+impl<S> Layer<S> for RequirePermission {
+    type Service = RequirePermissionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequirePermissionService {
+            inner,
+            require: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequirePermissionService<S> {
+    inner: S,
+    require: RequirePermission,
+}
+
+impl<S> Service<Request> for RequirePermissionService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // use the service that was driven to readiness and leave a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let (mut parts, body) = request.into_parts();
+
+        match self.require.authorize(&parts) {
+            Ok(user) => {
+                parts.extensions.insert(user);
+                Box::pin(inner.call(Request::from_parts(parts, body)))
+            }
+            Err(rejection) => Box::pin(async move { Ok(rejection.into_response()) }),
+        }
+    }
+}
+
+/// Forward-auth endpoint for reverse proxies such as nginx (`auth_request`) and
+/// Traefik (`forwardAuth`). The proxied host is read from `X-Forwarded-Host`
+/// and mapped to a permission site.
+#[derive(Debug, Clone)]
+pub struct ForwardAuth {
+    /// Maps forwarded hosts to permission sites. Hosts without an entry use
+    /// the host name itself as the site.
+    pub sites: HashMap<String, String>,
+    /// Permission required on the site.
+    pub permission: String,
+    /// If set, unauthenticated requests are redirected here with the original
+    /// URL in the `rd` query parameter instead of getting a 401.
+    pub login_url: Option<String>,
+}
+
+impl Default for ForwardAuth {
+    fn default() -> Self {
+        Self {
+            sites: HashMap::new(),
+            permission: "read".into(),
+            login_url: None,
+        }
+    }
+}
+
+impl ForwardAuth {
+    pub fn site(&self, host: &str) -> String {
+        let host = host.split(':').next().unwrap_or(host);
+        self.sites
+            .get(host)
+            .cloned()
+            .unwrap_or_else(|| host.to_string())
+    }
+
+    pub fn check(&self, state: &EnigmaState, headers: &HeaderMap) -> Response {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        let Some(host) = header("x-forwarded-host") else {
+            return (StatusCode::BAD_REQUEST, "missing X-Forwarded-Host").into_response();
+        };
+        let site = self.site(host);
+
+        let user = match state.verify(headers) {
+            Ok(session) => session.user,
+            Err(AuthRejection::Internal) => return AuthRejection::Internal.into_response(),
+            Err(rejection) => {
+                let Some(login_url) = &self.login_url else {
+                    return rejection.into_response();
+                };
+
+                let proto = header("x-forwarded-proto").unwrap_or("https");
+                let uri = header("x-forwarded-uri").unwrap_or("/");
+                let original = format!("{}://{}{}", proto, host, uri);
+                let separator = if login_url.contains('?') { '&' } else { '?' };
+                let location = format!("{}{}rd={}", login_url, separator, url_encode(&original));
+
+                return match HeaderValue::from_str(&location) {
+                    Ok(location) => (StatusCode::FOUND, [(LOCATION, location)]).into_response(),
+                    Err(_) => rejection.into_response(),
+                };
+            }
+        };
+
+        let allowed = match state.has_permission(&user, &site, &self.permission) {
+            Ok(allowed) => allowed,
+            Err(rejection) => return rejection.into_response(),
+        };
+        if !allowed {
+            tracing::debug!(
+                "forward auth denied {:?} on {:?} ({:?})",
+                user.username,
+                site,
+                self.permission
+            );
+            return AuthRejection::Forbidden.into_response();
+        }
+
+        let permissions = user
+            .permissions
+            .iter()
+            .filter(|p| glob_match(&p.site, &site))
+            .map(|p| p.permission.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut response = StatusCode::OK.into_response();
+        let headers = response.headers_mut();
+        if let Ok(username) = HeaderValue::from_str(&user.username) {
+            headers.insert("x-enigma-user", username);
+        }
+        if let Ok(permissions) = HeaderValue::from_str(&permissions) {
+            headers.insert("x-enigma-permissions", permissions);
+        }
+        response
+    }
+
+    /// Route that answers forward-auth requests for any method.
+    pub fn route<S>(self, state: EnigmaState) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        any(move |headers: HeaderMap| {
+            let response = self.check(&state, &headers);
+            async move { response }
+        })
+    }
+}
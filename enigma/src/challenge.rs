@@ -0,0 +1,202 @@
+use chrono::DateTime;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::token::{generate_token, hash_token};
+use crate::Error;
+use crate::LoginThrottle;
+use crate::Result;
+use crate::Session;
+use crate::SessionPolicy;
+use crate::TrackInformation;
+
+use super::Database;
+
+/// Returned by `create_session` in place of a session when the user has a
+/// second factor. The challenge token is exchanged for the session once the
+/// second factor is verified.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SecondFactorChallenge {
+    pub challenge_token: String,
+    pub expiry_date: DateTime<Utc>,
+}
+
+/// A pending login that passed the password check.
+pub(crate) struct LoginChallenge {
+    pub id: i64,
+    pub user_id: i64,
+    pub track: TrackInformation,
+}
+
+impl Database {
+    pub(crate) fn tx_create_login_challenge(
+        tx: &Transaction<'_>,
+        expiry_date: DateTime<Utc>,
+        user_id: i64,
+        track: TrackInformation,
+    ) -> Result<SecondFactorChallenge> {
+        tracing::trace!("[database] tx_create_login_challenge:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  expiry_date: {:?}", expiry_date);
+        tracing::trace!("  track: {:?}", track);
+
+        let challenge_token = generate_token();
+        let query = Query::insert_into("login_challenges")
+            .column("user_id", param(1))
+            .column("challenge_token", param(2))
+            .column("expiry_date", param(3))
+            .column("track_device", param(4))
+            .column("track_user_agent", param(5))
+            .column("track_ip_address", param(6))
+            .column("track_location", param(7))
+            .column("track_os", param(8))
+            .column("track_browser", param(9))
+            .column("track_screen_resolution", param(10))
+            .column("track_timezone", param(11))
+            .column("created_at", param(12))
+            .into_query();
+
+        query.insert(
+            tx,
+            params![
+                user_id,
+                hash_token(&challenge_token),
+                expiry_date,
+                track.device,
+                track.user_agent,
+                track.ip_address,
+                track.location,
+                track.os,
+                track.browser,
+                track.screen_resolution,
+                track.timezone,
+                Utc::now()
+            ],
+        )?;
+
+        Ok(SecondFactorChallenge {
+            challenge_token,
+            expiry_date,
+        })
+    }
+
+    pub(crate) fn tx_get_login_challenge(
+        tx: &Transaction<'_>,
+        challenge_token: &str,
+    ) -> Result<LoginChallenge> {
+        let token_hash = hash_token(challenge_token);
+        tracing::trace!("[database] tx_get_login_challenge: {:?}", token_hash);
+
+        struct InnerChallenge {
+            id: i64,
+            user_id: i64,
+            expiry_date: DateTime<Utc>,
+            track: TrackInformation,
+        }
+
+        impl FromRow for InnerChallenge {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    id: row.get("id")?,
+                    user_id: row.get("user_id")?,
+                    expiry_date: row.get("expiry_date")?,
+                    track: TrackInformation {
+                        device: row.get("track_device")?,
+                        user_agent: row.get("track_user_agent")?,
+                        ip_address: row.get("track_ip_address")?,
+                        location: row.get("track_location")?,
+                        os: row.get("track_os")?,
+                        browser: row.get("track_browser")?,
+                        screen_resolution: row.get("track_screen_resolution")?,
+                        timezone: row.get("track_timezone")?,
+                    },
+                })
+            }
+        }
+
+        let query = Query::select_from("login_challenges")
+            .all_columns()
+            .condition(query::eq(query::column("challenge_token"), param(1)))
+            .into_query();
+
+        let challenge = query
+            .select_maybe::<InnerChallenge>(tx, params![token_hash])?
+            .ok_or(Error::ChallengeNotFound)?;
+
+        if challenge.expiry_date < Utc::now() {
+            return Err(Error::ChallengeExpired);
+        }
+
+        Ok(LoginChallenge {
+            id: challenge.id,
+            user_id: challenge.user_id,
+            track: challenge.track,
+        })
+    }
+
+    pub(crate) fn tx_delete_login_challenge(tx: &Transaction<'_>, id: i64) -> Result<()> {
+        tracing::trace!("[database] tx_delete_login_challenge: {:?}", id);
+
+        let query = Query::delete_from("login_challenges")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        query.delete(tx, params![id])?;
+        Ok(())
+    }
+
+    /// Exchanges a login challenge for a session if `verify` accepts the
+    /// second factor of the challenge's user. Rejected second factors count
+    /// as failed logins and are reported as `incorrect`.
+    pub(crate) fn complete_login_challenge(
+        tx: Transaction<'_>,
+        login_throttle: &LoginThrottle,
+        session_policy: &SessionPolicy,
+        challenge_token: &str,
+        incorrect: Error,
+        verify: impl FnOnce(&Transaction<'_>, i64) -> Result<bool>,
+    ) -> Result<Session> {
+        let challenge = Self::tx_get_login_challenge(&tx, challenge_token)?;
+        let user_id = challenge.user_id;
+        let ip_address = challenge.track.ip_address.clone();
+
+        Self::tx_check_login_throttle(&tx, login_throttle, Some(user_id), ip_address.as_deref())?;
+
+        if !verify(&tx, user_id)? {
+            Self::tx_record_login_attempt(&tx, Some(user_id), ip_address.as_deref(), false)?;
+            tx.commit()?;
+            return Err(incorrect);
+        }
+
+        Self::tx_delete_login_challenge(&tx, challenge.id)?;
+        Self::tx_record_login_attempt(&tx, Some(user_id), ip_address.as_deref(), true)?;
+
+        let session = Self::tx_issue_session(&tx, session_policy, user_id, challenge.track)?;
+
+        tx.commit()?;
+        Ok(session)
+    }
+
+    pub fn delete_expired_login_challenges(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_login_challenges");
+            let query = Query::delete_from("login_challenges")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
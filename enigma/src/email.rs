@@ -0,0 +1,220 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::mail::Mail;
+use crate::token::{generate_token, hash_token, url_encode};
+use crate::Error;
+use crate::Result;
+use crate::User;
+
+use super::Database;
+
+/// Controls email verification tokens and the mails that deliver them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailVerificationPolicy {
+    pub token_lifetime: Duration,
+    /// Page that redeems the token, which is appended as the `token` query
+    /// parameter.
+    pub verify_url: String,
+    pub subject: String,
+}
+
+impl Default for EmailVerificationPolicy {
+    fn default() -> Self {
+        Self {
+            token_lifetime: Duration::days(1),
+            verify_url: "http://localhost/verify-email".into(),
+            subject: "Verify your email address".into(),
+        }
+    }
+}
+
+impl EmailVerificationPolicy {
+    pub fn verify_link(&self, token: &str) -> String {
+        let separator = if self.verify_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!(
+            "{}{}token={}",
+            self.verify_url,
+            separator,
+            url_encode(token)
+        )
+    }
+}
+
+struct InnerVerificationToken {
+    user_id: i64,
+    email: String,
+    expiry_date: DateTime<Utc>,
+}
+
+impl FromRow for InnerVerificationToken {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            email: row.get("email")?,
+            expiry_date: row.get("expiry_date")?,
+        })
+    }
+}
+
+impl Database {
+    pub fn with_email_verification_policy(
+        mut self,
+        email_verification_policy: EmailVerificationPolicy,
+    ) -> Self {
+        self.email_verification_policy = email_verification_policy;
+        self
+    }
+
+    /// Creates a token for the user's current email address. Returns the
+    /// address along with the token.
+    fn tx_create_email_verification_token(
+        tx: &Transaction<'_>,
+        policy: &EmailVerificationPolicy,
+        user_id: i64,
+    ) -> Result<(String, String)> {
+        tracing::trace!("[database] tx_create_email_verification_token:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let user = Self::tx_get_user_by_id(tx, user_id)?;
+        let email = user.email.ok_or(Error::EmailNotSet)?;
+
+        let token = generate_token();
+        let now = Utc::now();
+        let query = Query::insert_into("email_verification_tokens")
+            .column("user_id", param(1))
+            .column("email", param(2))
+            .column("token_hash", param(3))
+            .column("expiry_date", param(4))
+            .column("created_at", param(5))
+            .into_query();
+
+        query.insert(
+            tx,
+            params![
+                user_id,
+                email,
+                hash_token(&token),
+                now + policy.token_lifetime,
+                now
+            ],
+        )?;
+
+        Ok((email, token))
+    }
+
+    /// Creates a verification token for the user's email address without
+    /// sending it, for operators who deliver the link themselves.
+    pub fn create_email_verification_token(&mut self, user_id: i64) -> Result<String> {
+        let tx = self.database.transaction()?;
+
+        let (_, token) = Self::tx_create_email_verification_token(
+            &tx,
+            &self.email_verification_policy,
+            user_id,
+        )?;
+
+        tx.commit()?;
+        Ok(token)
+    }
+
+    /// Mails a verification link to the user's email address.
+    pub fn request_email_verification(&mut self, user_id: i64) -> Result<()> {
+        let mailer = self.mailer.as_ref().ok_or(Error::MailerNotConfigured)?;
+        let tx = self.database.transaction()?;
+
+        let policy = &self.email_verification_policy;
+        let (email, token) = Self::tx_create_email_verification_token(&tx, policy, user_id)?;
+        let mail = Mail {
+            to: email,
+            subject: policy.subject.clone(),
+            body: format!(
+                "Open the link below within {} hours to verify your email address:\n\n{}\n\n\
+                 If you did not request this, you can ignore this mail.\n",
+                policy.token_lifetime.num_hours(),
+                policy.verify_link(&token)
+            ),
+        };
+
+        tx.commit()?;
+
+        mailer.send(&mail)
+    }
+
+    /// Redeems a verification token and marks the address it was issued for
+    /// as verified. Tokens for an address the user no longer has are not
+    /// accepted.
+    pub fn verify_email(&mut self, token: &str) -> Result<User> {
+        let tx = self.database.transaction()?;
+
+        let token_hash = hash_token(token);
+        tracing::trace!("[database] verify_email:");
+        tracing::trace!("  token_hash: {:?}", token_hash);
+
+        let verification_token = {
+            let query = Query::select_from("email_verification_tokens")
+                .all_columns()
+                .condition(query::eq(query::column("token_hash"), param(1)))
+                .into_query();
+            query
+                .select_maybe::<InnerVerificationToken>(&tx, params![token_hash])?
+                .ok_or(Error::VerificationTokenNotFound)?
+        };
+
+        if verification_token.expiry_date < Utc::now() {
+            return Err(Error::VerificationTokenExpired);
+        }
+
+        let user_id = verification_token.user_id;
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        if user.email.as_ref() != Some(&verification_token.email) {
+            return Err(Error::VerificationTokenNotFound);
+        }
+
+        {
+            let query = Query::update("users")
+                .set("email_verified_at", param(1))
+                .condition(query::eq(query::column("id"), param(2)))
+                .into_query();
+            query.update(&tx, params![Utc::now(), user_id])?;
+
+            let query = Query::delete_from("email_verification_tokens")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![user_id])?;
+        }
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        tx.commit()?;
+        Ok(user)
+    }
+
+    pub fn delete_expired_email_verification_tokens(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_email_verification_tokens");
+            let query = Query::delete_from("email_verification_tokens")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
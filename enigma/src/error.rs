@@ -1,14 +1,59 @@
+use chrono::{DateTime, Utc};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("database error: {0}")]
     Database(#[from] kodama_api::Error),
     #[error("rusqlite error: {0}")]
     Rusqlite(#[from] rusqlite::Error),
-    #[error("pbkdf2 error: {0}")]
-    Pbkdf2(pbkdf2::password_hash::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("password hash error: {0}")]
+    PasswordHash(pbkdf2::password_hash::Error),
+    #[error("bcrypt error: {0}")]
+    Bcrypt(bcrypt::BcryptError),
+    #[error("unknown password method: {0}")]
+    UnknownPasswordMethod(String),
 
     #[error("user not found")]
     UserNotFound,
+    #[error("user disabled")]
+    UserDisabled {
+        reason: Option<String>,
+        until: Option<DateTime<Utc>>,
+    },
+    #[error("role not found")]
+    RoleNotFound,
+    #[error("role name already in use")]
+    RoleNameTaken,
+    #[error("api key not found")]
+    ApiKeyNotFound,
+    #[error("api key expired")]
+    ApiKeyExpired,
+    #[error("scope {site}:{permission} is not granted to the user")]
+    ScopeNotGranted { site: String, permission: String },
+    #[error("refresh token not found")]
+    RefreshTokenNotFound,
+    #[error("refresh token expired")]
+    RefreshTokenExpired,
+    #[error("refresh token reused, its sessions were revoked")]
+    RefreshTokenReused,
+    #[error("session limit of {limit} reached")]
+    SessionLimitReached { limit: u32 },
+    #[error("signed tokens are not enabled")]
+    SignedTokensDisabled,
+    #[error("signed token invalid")]
+    SignedTokenInvalid,
+    #[error("signed token expired")]
+    SignedTokenExpired,
+    #[error("signed token revoked")]
+    SignedTokenRevoked,
+    #[error("invalid signing key")]
+    InvalidSigningKey,
+    #[error("signing key encryption key not configured")]
+    SigningKeyEncryptionKeyMissing,
+    #[error("invalid signing key encryption key")]
+    InvalidSigningKeyEncryptionKey,
     #[error("password incorrect")]
     PasswordIncorrect,
     #[error("invalid password salt")]
@@ -17,4 +62,48 @@ pub enum Error {
     SessionCreationFailed,
     #[error("session not found")]
     SessionNotFound,
+    #[error("qr token not found")]
+    QrTokenNotFound,
+    #[error("qr token expired")]
+    QrTokenExpired,
+    #[error("login challenge not found")]
+    ChallengeNotFound,
+    #[error("login challenge expired")]
+    ChallengeExpired,
+    #[error("totp not enrolled")]
+    TotpNotEnrolled,
+    #[error("totp already enrolled")]
+    TotpAlreadyEnrolled,
+    #[error("totp code incorrect")]
+    TotpCodeIncorrect,
+    #[error("invalid totp policy: {0}")]
+    InvalidTotpPolicy(&'static str),
+    #[error("recovery code incorrect")]
+    RecoveryCodeIncorrect,
+    #[error("credential not found")]
+    CredentialNotFound,
+    #[error("webauthn verification failed: {0}")]
+    WebAuthnVerificationFailed(String),
+    #[error("webauthn sign count did not increase")]
+    WebAuthnCounterRegression,
+    #[error("password reset token not found")]
+    ResetTokenNotFound,
+    #[error("password reset token expired")]
+    ResetTokenExpired,
+    #[error("username already in use")]
+    UsernameTaken,
+    #[error("email address already in use")]
+    EmailTaken,
+    #[error("user has no email address")]
+    EmailNotSet,
+    #[error("email verification token not found")]
+    VerificationTokenNotFound,
+    #[error("email verification token expired")]
+    VerificationTokenExpired,
+    #[error("no mailer configured")]
+    MailerNotConfigured,
+    #[error("failed to send mail: {0}")]
+    Mail(String),
+    #[error("too many login attempts, retry after {retry_after}")]
+    TooManyAttempts { retry_after: DateTime<Utc> },
 }
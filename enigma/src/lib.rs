@@ -3,17 +3,57 @@ use std::{net::SocketAddr, path::Path};
 #[cfg(test)]
 mod tests;
 
+pub mod api_key;
+pub mod challenge;
+pub mod email;
 pub mod error;
+pub mod limit;
+pub mod mail;
 pub mod password;
+pub mod permission;
+pub mod qr;
+pub mod recovery;
+pub mod refresh;
+pub mod reset;
+pub mod role;
 pub mod session;
+pub mod signed;
+pub mod throttle;
+mod token;
+pub mod totp;
 pub mod user;
+pub mod webauthn;
 
+pub use api_key::{ApiKey, MintedApiKey};
+pub use challenge::SecondFactorChallenge;
+pub use email::EmailVerificationPolicy;
 pub use error::Error;
-pub use session::VerifySession;
+pub use limit::SessionLimitAction;
+pub use mail::Mailer;
+pub use password::PasswordHashers;
+pub use permission::{PermissionMatch, PermissionPolicy};
+pub use reset::PasswordResetPolicy;
+pub use role::Role;
+pub use session::{CreateSession, SessionInfo, SessionPolicy, VerifySession};
+pub use signed::{
+    JsonWebKeySet, SignedTokenClaims, SignedTokenVerifier, SigningKeyEncryptionKey,
+};
+pub use throttle::LoginThrottle;
+pub use totp::{TotpEnrollment, TotpPolicy};
+pub use webauthn::RelyingParty;
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Database {
     database: kodama_api::Database,
+    login_throttle: LoginThrottle,
+    password_hashers: PasswordHashers,
+    session_policy: SessionPolicy,
+    totp_policy: TotpPolicy,
+    relying_party: RelyingParty,
+    password_reset_policy: PasswordResetPolicy,
+    email_verification_policy: EmailVerificationPolicy,
+    permission_policy: PermissionPolicy,
+    mailer: Option<Box<dyn Mailer>>,
 }
 
 impl Database {
@@ -25,9 +65,41 @@ impl Database {
                 SocketAddr::from(([127, 0, 0, 1], 40092)),
             )
             .with_migration("001", include_str!("../../schema/001.sql"))
+            .with_migration("002", include_str!("../../schema/002.sql"))
+            .with_migration("003", include_str!("../../schema/003.sql"))
+            .with_migration("004", include_str!("../../schema/004.sql"))
+            .with_migration("005", include_str!("../../schema/005.sql"))
+            .with_migration("006", include_str!("../../schema/006.sql"))
+            .with_migration("007", include_str!("../../schema/007.sql"))
+            .with_migration("008", include_str!("../../schema/008.sql"))
+            .with_migration("009", include_str!("../../schema/009.sql"))
+            .with_migration("010", include_str!("../../schema/010.sql"))
+            .with_migration("011", include_str!("../../schema/011.sql"))
+            .with_migration("012", include_str!("../../schema/012.sql"))
+            .with_migration("013", include_str!("../../schema/013.sql"))
+            .with_migration("014", include_str!("../../schema/014.sql"))
+            .with_migration("015", include_str!("../../schema/015.sql"))
+            .with_migration("016", include_str!("../../schema/016.sql"))
+            .with_migration("017", include_str!("../../schema/017.sql"))
+            .with_migration("018", include_str!("../../schema/018.sql"))
+            .with_migration("019", include_str!("../../schema/019.sql"))
+            .with_migration("020", include_str!("../../schema/020.sql"))
             .build()?;
 
-        Ok(Database { database })
+        Self::hash_legacy_session_tokens(&database)?;
+
+        Ok(Database {
+            database,
+            login_throttle: LoginThrottle::default(),
+            password_hashers: PasswordHashers::default(),
+            session_policy: SessionPolicy::default(),
+            totp_policy: TotpPolicy::default(),
+            relying_party: RelyingParty::default(),
+            password_reset_policy: PasswordResetPolicy::default(),
+            email_verification_policy: EmailVerificationPolicy::default(),
+            permission_policy: PermissionPolicy::default(),
+            mailer: None,
+        })
     }
 }
 
@@ -35,6 +107,10 @@ use chrono::{DateTime, Utc};
 
 #[cfg(feature = "axum")]
 mod axum_feature;
+#[cfg(feature = "axum")]
+pub use axum_feature::{
+    AuthRejection, EnigmaState, ForwardAuth, RequirePermission, RequirePermissionService,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Session {
@@ -43,6 +119,14 @@ pub struct Session {
     pub expiry_date: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
     pub track: TrackInformation,
+    /// Set on newly issued sessions if `SessionPolicy::refresh_token_lifetime`
+    /// is set, see `Database::refresh_session`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// Set on newly issued sessions if `SessionPolicy::signed_token_lifetime`
+    /// is set, see the `signed` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_token: Option<String>,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -61,14 +145,33 @@ pub struct TrackInformation {
 pub struct User {
     pub id: i64,
     pub username: String,
+    pub email: Option<String>,
+    /// When the current email address was confirmed with
+    /// `verify_email`, if ever.
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub status: UserStatus,
     pub permissions: Vec<Permission>,
 }
 
+/// Disabled users keep their data but cannot sign in or use their sessions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UserStatus {
+    #[default]
+    Active,
+    Disabled {
+        reason: Option<String>,
+        /// The user is active again after this date.
+        until: Option<DateTime<Utc>>,
+    },
+}
+
 impl User {
+    /// Checks the user's grants, which may use `*` and `?` globs, without
+    /// any implications between permissions. Use `has_permission_with` with
+    /// `Database::permission_policy` to apply the configured ones.
     pub fn has_permission(&self, site: &str, permission: &str) -> bool {
-        self.permissions
-            .iter()
-            .any(|p| p.site == site && p.permission == permission)
+        self.has_permission_with(&PermissionPolicy::empty(), site, permission)
     }
 
 }
@@ -81,6 +184,9 @@ pub struct Permission {
 
 #[derive(Debug, serde::Deserialize)]
 pub struct SessionCreate {
+    /// Username, or a verified email address if the session policy allows
+    /// email logins.
+    #[serde(alias = "email")]
     pub username: String,
     pub password: String,
 
@@ -94,7 +200,28 @@ pub struct SessionCreate {
     pub timezone: Option<String>,
 }
 
+impl SessionCreate {
+    pub fn track(&self) -> TrackInformation {
+        TrackInformation {
+            device: self.device.clone(),
+            user_agent: self.user_agent.clone(),
+            ip_address: self.ip_address.clone(),
+            location: self.location.clone(),
+            os: self.os.clone(),
+            browser: self.browser.clone(),
+            screen_resolution: self.screen_resolution.clone(),
+            timezone: self.timezone.clone(),
+        }
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct SessionVerify {
     pub session_token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SecondFactorVerify {
+    pub challenge_token: String,
+    pub code: String,
 }
\ No newline at end of file
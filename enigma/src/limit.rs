@@ -0,0 +1,161 @@
+use chrono::DateTime;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::session::SessionPolicy;
+use crate::Error;
+use crate::Result;
+
+use super::Database;
+
+/// What `create_session` does when the user already has as many active
+/// sessions as allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionLimitAction {
+    /// Delete the least recently used sessions to make room.
+    #[default]
+    EvictLeastRecentlyUsed,
+    /// Fail with `Error::SessionLimitReached`.
+    Refuse,
+}
+
+struct InnerActiveSession {
+    id: i64,
+    expiry_date: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+}
+
+impl FromRow for InnerActiveSession {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            expiry_date: row.get("expiry_date")?,
+            created_at: row.get("created_at")?,
+            last_used_at: row.get("last_used_at")?,
+        })
+    }
+}
+
+struct InnerSessionLimit {
+    max_sessions: Option<u32>,
+}
+
+impl FromRow for InnerSessionLimit {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            max_sessions: row.get("max_sessions")?,
+        })
+    }
+}
+
+impl Database {
+    /// The user's override of the session limit, if any.
+    fn tx_get_session_limit_override(tx: &Transaction<'_>, user_id: i64) -> Result<Option<u32>> {
+        let query = Query::select_from("users")
+            .column("max_sessions")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        let limit = query
+            .select_maybe::<InnerSessionLimit>(tx, params![user_id])?
+            .ok_or(Error::UserNotFound)?;
+        Ok(limit.max_sessions)
+    }
+
+    /// Makes room for one more session of the user, or refuses to, as the
+    /// policy says. A limit of zero blocks new sessions either way.
+    pub(crate) fn tx_enforce_session_limit(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        user_id: i64,
+    ) -> Result<()> {
+        let limit = Self::tx_get_session_limit_override(tx, user_id)?.or(policy.max_sessions);
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+
+        let now = Utc::now();
+        let query = Query::select_from("sessions")
+            .column("id")
+            .column("expiry_date")
+            .column("created_at")
+            .column("last_used_at")
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+        let mut sessions = query
+            .select_many::<InnerActiveSession>(tx, params![user_id])?
+            .into_iter()
+            .filter(|session| {
+                policy.is_active(
+                    session.expiry_date,
+                    session.created_at,
+                    session.last_used_at,
+                    now,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let excess = (sessions.len() + 1).saturating_sub(limit as usize);
+        if excess == 0 {
+            return Ok(());
+        }
+        if limit == 0 || policy.session_limit_action == SessionLimitAction::Refuse {
+            return Err(Error::SessionLimitReached { limit });
+        }
+
+        tracing::trace!("[database] tx_enforce_session_limit:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  evicting: {:?}", excess);
+
+        sessions.sort_by_key(|session| session.last_used_at);
+        for session in sessions.into_iter().take(excess) {
+            Self::tx_delete_session_by_id(tx, session.id)?;
+        }
+        Ok(())
+    }
+
+    /// Overrides `SessionPolicy::max_sessions` for the user. `None` goes
+    /// back to the policy's limit.
+    pub fn set_session_limit(&mut self, user_id: i64, max_sessions: Option<u32>) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] set_session_limit:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  max_sessions: {:?}", max_sessions);
+
+            Self::tx_get_user_by_id(&tx, user_id)?;
+
+            let query = Query::update("users")
+                .set("max_sessions", param(1))
+                .condition(query::eq(query::column("id"), param(2)))
+                .into_query();
+            query.update(&tx, params![max_sessions, user_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The session limit that applies to the user, if any.
+    pub fn session_limit(&mut self, user_id: i64) -> Result<Option<u32>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] session_limit:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let limit =
+            Self::tx_get_session_limit_override(&tx, user_id)?.or(self.session_policy.max_sessions);
+
+        tx.commit()?;
+        Ok(limit)
+    }
+}
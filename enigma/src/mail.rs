@@ -0,0 +1,126 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::Error;
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers mails such as password reset links.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<()>;
+}
+
+/// Keeps sent mails in memory. Clones share the same mailbox, so a clone can
+/// be kept to inspect what the database sent.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    mails: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl MemoryMailer {
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, mail: &Mail) -> Result<()> {
+        self.mails
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(mail.clone());
+        Ok(())
+    }
+}
+
+/// Appends mails to a file instead of sending them, for development setups.
+#[derive(Debug, Clone)]
+pub struct FileMailer {
+    pub path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| Error::Mail(err.to_string()))?;
+
+        writeln!(
+            file,
+            "To: {}\nSubject: {}\n\n{}\n",
+            mail.to, mail.subject, mail.body
+        )
+        .map_err(|err| Error::Mail(err.to_string()))
+    }
+}
+
+/// Sends mails through an SMTP relay.
+#[cfg(feature = "smtp")]
+pub struct SmtpMailer {
+    transport: lettre::SmtpTransport,
+    from: lettre::message::Mailbox,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpMailer {
+    /// Connects to `relay` with TLS and authenticates with the credentials.
+    pub fn new(relay: &str, username: &str, password: &str, from: &str) -> Result<Self> {
+        let credentials = lettre::transport::smtp::authentication::Credentials::new(
+            username.into(),
+            password.into(),
+        );
+        let transport = lettre::SmtpTransport::relay(relay)
+            .map_err(|err| Error::Mail(err.to_string()))?
+            .credentials(credentials)
+            .build();
+        Self::with_transport(transport, from)
+    }
+
+    pub fn with_transport(transport: lettre::SmtpTransport, from: &str) -> Result<Self> {
+        let from = from
+            .parse()
+            .map_err(|err: lettre::address::AddressError| Error::Mail(err.to_string()))?;
+        Ok(Self { transport, from })
+    }
+}
+
+#[cfg(feature = "smtp")]
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<()> {
+        use lettre::Transport;
+
+        let to = mail
+            .to
+            .parse()
+            .map_err(|err: lettre::address::AddressError| Error::Mail(err.to_string()))?;
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .body(mail.body.clone())
+            .map_err(|err| Error::Mail(err.to_string()))?;
+
+        self.transport
+            .send(&message)
+            .map_err(|err| Error::Mail(err.to_string()))?;
+        Ok(())
+    }
+}
@@ -1,29 +1,337 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use argon2::Argon2;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::Transaction;
 use pbkdf2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
 use pbkdf2::{password_hash::PasswordHash, Pbkdf2};
+use rand_core::OsRng;
+use rusqlite::params;
+use scrypt::Scrypt;
 
 use crate::Result;
 use crate::{Database, Error};
 
-impl Database {
-    pub fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
-        let password_hash = Pbkdf2
+/// A password hashing scheme, stored by name in `users.password_method`.
+pub trait PasswordMethod: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String>;
+
+    fn verify_password(
+        &self,
+        password: &str,
+        password_hash: &str,
+        password_salt: &SaltString,
+    ) -> Result<bool>;
+
+    /// Whether a hash made by this method was made with other parameters than
+    /// the ones it would use now.
+    fn needs_rehash(&self, _password_hash: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+#[derive(Default)]
+pub struct Pbkdf2Method {
+    pub params: pbkdf2::Params,
+}
+
+impl PasswordMethod for Pbkdf2Method {
+    fn name(&self) -> &str {
+        "pbkdf2-sha256"
+    }
+
+    fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
+        Ok(Pbkdf2
+            .hash_password_customized(password.as_bytes(), None, None, self.params, password_salt)
+            .map_err(Error::PasswordHash)?
+            .to_string())
+    }
+
+    fn verify_password(&self, password: &str, password_hash: &str, _: &SaltString) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        Ok(Pbkdf2
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok())
+    }
+
+    fn needs_rehash(&self, password_hash: &str) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        let params = pbkdf2::Params::try_from(&parsed_hash).map_err(Error::PasswordHash)?;
+        Ok(
+            parsed_hash.algorithm != pbkdf2::Algorithm::Pbkdf2Sha256.ident()
+                || params != self.params,
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Argon2Method {
+    pub params: argon2::Params,
+}
+
+impl PasswordMethod for Argon2Method {
+    fn name(&self) -> &str {
+        "argon2id"
+    }
+
+    fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
+        let argon2 = Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            self.params.clone(),
+        );
+        Ok(argon2
             .hash_password(password.as_bytes(), password_salt)
-            .map_err(Error::Pbkdf2)?
-            .to_string();
+            .map_err(Error::PasswordHash)?
+            .to_string())
+    }
+
+    fn verify_password(&self, password: &str, password_hash: &str, _: &SaltString) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok())
+    }
+
+    fn needs_rehash(&self, password_hash: &str) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        let params = argon2::Params::try_from(&parsed_hash).map_err(Error::PasswordHash)?;
+        Ok(parsed_hash.algorithm != argon2::Algorithm::Argon2id.ident()
+            || parsed_hash.version != Some(argon2::Version::V0x13.into())
+            || params.m_cost() != self.params.m_cost()
+            || params.t_cost() != self.params.t_cost()
+            || params.p_cost() != self.params.p_cost())
+    }
+}
+
+pub struct ScryptMethod {
+    pub params: scrypt::Params,
+}
+
+impl Default for ScryptMethod {
+    fn default() -> Self {
+        Self {
+            params: scrypt::Params::recommended(),
+        }
+    }
+}
+
+impl PasswordMethod for ScryptMethod {
+    fn name(&self) -> &str {
+        "scrypt"
+    }
+
+    fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
+        Ok(Scrypt
+            .hash_password_customized(password.as_bytes(), None, None, self.params, password_salt)
+            .map_err(Error::PasswordHash)?
+            .to_string())
+    }
+
+    fn verify_password(&self, password: &str, password_hash: &str, _: &SaltString) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        Ok(Scrypt
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok())
+    }
+
+    fn needs_rehash(&self, password_hash: &str) -> Result<bool> {
+        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::PasswordHash)?;
+        let params = scrypt::Params::try_from(&parsed_hash).map_err(Error::PasswordHash)?;
+        Ok(params.log_n() != self.params.log_n()
+            || params.r() != self.params.r()
+            || params.p() != self.params.p())
+    }
+}
+
+/// bcrypt embeds its own salt in the hash, so the stored salt is unused.
+pub struct BcryptMethod {
+    pub cost: u32,
+}
+
+impl Default for BcryptMethod {
+    fn default() -> Self {
+        Self {
+            cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+impl PasswordMethod for BcryptMethod {
+    fn name(&self) -> &str {
+        "bcrypt"
+    }
+
+    fn hash_password(&self, _: &SaltString, password: &str) -> Result<String> {
+        bcrypt::hash(password, self.cost).map_err(Error::Bcrypt)
+    }
+
+    fn verify_password(&self, password: &str, password_hash: &str, _: &SaltString) -> Result<bool> {
+        bcrypt::verify(password, password_hash).map_err(Error::Bcrypt)
+    }
 
-        Ok(password_hash)
+    fn needs_rehash(&self, password_hash: &str) -> Result<bool> {
+        let parts = bcrypt::HashParts::from_str(password_hash).map_err(Error::Bcrypt)?;
+        Ok(parts.get_cost() != self.cost)
+    }
+}
+
+/// Registry of password methods keyed by name, with the method used for new
+/// password hashes.
+pub struct PasswordHashers {
+    methods: HashMap<String, Box<dyn PasswordMethod>>,
+    default_method: String,
+}
+
+impl Default for PasswordHashers {
+    fn default() -> Self {
+        Self {
+            methods: HashMap::new(),
+            default_method: "pbkdf2-sha256".into(),
+        }
+        .with_method(Pbkdf2Method::default())
+        .with_method(Argon2Method::default())
+        .with_method(ScryptMethod::default())
+        .with_method(BcryptMethod::default())
+    }
+}
+
+impl PasswordHashers {
+    pub fn with_method(mut self, method: impl PasswordMethod + 'static) -> Self {
+        self.methods.insert(method.name().into(), Box::new(method));
+        self
+    }
+
+    pub fn with_default_method(mut self, password_method: &str) -> Self {
+        self.default_method = password_method.into();
+        self
+    }
+
+    pub fn default_method(&self) -> &str {
+        &self.default_method
+    }
+
+    pub fn get(&self, password_method: &str) -> Result<&dyn PasswordMethod> {
+        self.methods
+            .get(password_method)
+            .map(|method| method.as_ref())
+            .ok_or_else(|| Error::UnknownPasswordMethod(password_method.into()))
+    }
+
+    pub fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
+        self.get(&self.default_method)?
+            .hash_password(password_salt, password)
     }
 
     pub fn verify_password(
+        &self,
         password: &str,
         password_hash: &str,
-        _password_salt: &SaltString,
-        _password_method: &str,
+        password_salt: &SaltString,
+        password_method: &str,
     ) -> Result<bool> {
-        let parsed_hash = PasswordHash::new(password_hash).map_err(Error::Pbkdf2)?;
-        Ok(Pbkdf2
-            .verify_password(password.as_bytes(), &parsed_hash)
-            .map(|_| true)
-            .unwrap_or(false))
+        self.get(password_method)?
+            .verify_password(password, password_hash, password_salt)
+    }
+
+    /// Whether a hash should be replaced by one made with the default method
+    /// and its current parameters.
+    pub fn needs_rehash(&self, password_hash: &str, password_method: &str) -> Result<bool> {
+        if password_method != self.default_method {
+            return Ok(true);
+        }
+        self.get(password_method)?.needs_rehash(password_hash)
+    }
+}
+
+impl Database {
+    pub fn with_password_hashers(mut self, password_hashers: PasswordHashers) -> Self {
+        self.password_hashers = password_hashers;
+        self
+    }
+
+    pub fn hash_password(&self, password_salt: &SaltString, password: &str) -> Result<String> {
+        self.password_hashers.hash_password(password_salt, password)
+    }
+
+    pub fn verify_password(
+        &self,
+        password: &str,
+        password_hash: &str,
+        password_salt: &SaltString,
+        password_method: &str,
+    ) -> Result<bool> {
+        self.password_hashers.verify_password(
+            password,
+            password_hash,
+            password_salt,
+            password_method,
+        )
+    }
+
+    pub(crate) fn tx_set_user_password(
+        tx: &Transaction<'_>,
+        password_hashers: &PasswordHashers,
+        user_id: i64,
+        password: &str,
+    ) -> Result<()> {
+        tracing::trace!("[database] tx_set_user_password:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  password: [REDACTED]");
+
+        let password_salt = SaltString::generate(&mut OsRng);
+        let password_hash = password_hashers.hash_password(&password_salt, password)?;
+        let password_method = password_hashers.default_method();
+
+        tracing::trace!("  password_method: {:?}", password_method);
+
+        let query = Query::update("users")
+            .set("password_hash", param(1))
+            .set("password_salt", param(2))
+            .set("password_method", param(3))
+            .condition(query::eq(query::column("id"), param(4)))
+            .into_query();
+
+        query.update(
+            tx,
+            params![
+                password_hash,
+                password_salt.as_str(),
+                password_method,
+                user_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Rehashes a verified password with the default method if it was stored
+    /// with another method or with weaker parameters.
+    pub(crate) fn tx_upgrade_password(
+        tx: &Transaction<'_>,
+        password_hashers: &PasswordHashers,
+        username: &str,
+        password: &str,
+    ) -> Result<()> {
+        let (user_id, password_hash, _, password_method) =
+            Self::tx_get_user_password(tx, username)?;
+        if !password_hashers.needs_rehash(&password_hash, &password_method)? {
+            return Ok(());
+        }
+
+        tracing::debug!(
+            "upgrading password of user {} from {:?} to {:?}",
+            user_id,
+            password_method,
+            password_hashers.default_method()
+        );
+        Self::tx_set_user_password(tx, password_hashers, user_id, password)
     }
 }
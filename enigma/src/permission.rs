@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::Database;
+use crate::Permission;
+use crate::User;
+
+/// Matches `value` against a glob `pattern`, where `*` matches any run of
+/// characters (including none) and `?` matches exactly one.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    let (mut p, mut v) = (0, 0);
+    // position of the last `*` and the value position it is matched up to
+    let mut star = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    p = star_p + 1;
+                    v = star_v + 1;
+                    star = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Which permissions imply others. Granting a permission grants everything
+/// reachable from it, so `admin -> write -> read` lets admins read. The
+/// default policy has no implications.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PermissionPolicy {
+    pub implications: BTreeMap<String, Vec<String>>,
+}
+
+impl PermissionPolicy {
+    /// A policy without implications, where permissions only match
+    /// themselves.
+    pub fn empty() -> Self {
+        Self {
+            implications: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_implication(mut self, permission: &str, implied: &str) -> Self {
+        let implied_permissions = self.implications.entry(permission.into()).or_default();
+        if !implied_permissions.iter().any(|p| p == implied) {
+            implied_permissions.push(implied.into());
+        }
+        self
+    }
+
+    /// The shortest chain of implications from `granted` to `required`,
+    /// starting with `granted`. Cycles in the graph are fine.
+    pub fn implication_path(&self, granted: &str, required: &str) -> Option<Vec<String>> {
+        let mut previous = BTreeMap::<&str, &str>::new();
+        let mut queue = VecDeque::from([granted]);
+
+        while let Some(permission) = queue.pop_front() {
+            if permission == required {
+                let mut path = vec![required.to_string()];
+                let mut current = required;
+                while let Some(p) = previous.get(current) {
+                    path.push(p.to_string());
+                    current = p;
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for implied in self.implications.get(permission).into_iter().flatten() {
+                if implied != granted && !previous.contains_key(implied.as_str()) {
+                    previous.insert(implied, permission);
+                    queue.push_back(implied);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Explains why `grant` covers `permission` on `site`, if it does.
+    pub fn explain(
+        &self,
+        grant: &Permission,
+        site: &str,
+        permission: &str,
+    ) -> Option<PermissionMatch> {
+        if !glob_match(&grant.site, site) {
+            return None;
+        }
+
+        if glob_match(&grant.permission, permission) {
+            return Some(PermissionMatch {
+                grant: grant.clone(),
+                implication: vec![],
+            });
+        }
+
+        self.implication_path(&grant.permission, permission)
+            .map(|implication| PermissionMatch {
+                grant: grant.clone(),
+                implication,
+            })
+    }
+}
+
+/// The grant that allowed a permission check, from
+/// `User::explain_permission`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PermissionMatch {
+    pub grant: Permission,
+    /// Chain from the granted permission to the required one, such as
+    /// `["admin", "read"]`. Empty if the grant matched directly.
+    pub implication: Vec<String>,
+}
+
+impl User {
+    /// Like `has_permission`, with the implications of `policy`.
+    pub fn has_permission_with(
+        &self,
+        policy: &PermissionPolicy,
+        site: &str,
+        permission: &str,
+    ) -> bool {
+        self.explain_permission(policy, site, permission).is_some()
+    }
+
+    /// Finds the grant that gives the user `permission` on `site`. Grants
+    /// that match directly are preferred over implied ones.
+    pub fn explain_permission(
+        &self,
+        policy: &PermissionPolicy,
+        site: &str,
+        permission: &str,
+    ) -> Option<PermissionMatch> {
+        let mut implied = None;
+        for grant in &self.permissions {
+            match policy.explain(grant, site, permission) {
+                Some(found) if found.implication.is_empty() => return Some(found),
+                Some(found) => {
+                    implied.get_or_insert(found);
+                }
+                None => (),
+            }
+        }
+        implied
+    }
+}
+
+impl Database {
+    pub fn with_permission_policy(mut self, permission_policy: PermissionPolicy) -> Self {
+        self.permission_policy = permission_policy;
+        self
+    }
+
+    pub fn permission_policy(&self) -> &PermissionPolicy {
+        &self.permission_policy
+    }
+}
@@ -0,0 +1,207 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::token::{generate_token, hash_token};
+use crate::Error;
+use crate::Result;
+use crate::Session;
+use crate::TrackInformation;
+use crate::VerifySession;
+
+use super::Database;
+
+/// Minutes a QR token can be polled and approved after it was issued.
+pub const QR_TOKEN_LIFETIME_MINUTES: i64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct QrToken {
+    pub qr_token: String,
+    pub expiry_date: DateTime<Utc>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollQrToken {
+    Session(Session),
+    Pending,
+    QrTokenNotFound,
+    QrTokenExpired,
+}
+
+impl PollQrToken {
+    pub fn unwrap_session(self) -> Session {
+        match self {
+            PollQrToken::Session(session) => session,
+            _ => panic!("qr token not approved, not found or expired"),
+        }
+    }
+}
+
+struct InnerQrCode {
+    user_id: i64,
+    expiry_date: DateTime<Utc>,
+    approved_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerQrCode {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            expiry_date: row.get("expiry_date")?,
+            approved_at: row.get("approved_at")?,
+        })
+    }
+}
+
+impl Database {
+    fn tx_get_qr_code(tx: &Transaction<'_>, qr_token: &str) -> Result<Option<InnerQrCode>> {
+        tracing::trace!("[database] tx_get_qr_code: {:?}", hash_token(qr_token));
+
+        let query = Query::select_from("qr_codes")
+            .all_columns()
+            .condition(query::eq(query::column("qr_token"), param(1)))
+            .into_query();
+
+        Ok(query.select_maybe::<InnerQrCode>(tx, params![hash_token(qr_token)])?)
+    }
+
+    fn tx_delete_qr_code(tx: &Transaction<'_>, qr_token: &str) -> Result<()> {
+        tracing::trace!("[database] tx_delete_qr_code: {:?}", hash_token(qr_token));
+
+        let query = Query::delete_from("qr_codes")
+            .condition(query::eq(query::column("qr_token"), param(1)))
+            .into_query();
+
+        query.delete(tx, params![hash_token(qr_token)])?;
+        Ok(())
+    }
+
+    fn verified_session(&mut self, session_token: &str) -> Result<Session> {
+        match self.verify_session(session_token)? {
+            VerifySession::Session(session) => Ok(session),
+            _ => Err(Error::SessionNotFound),
+        }
+    }
+
+    /// Issues a QR token for the user of an authenticated session. The token
+    /// is shown to a new device, which polls it with `poll_qr_token` until the
+    /// owner approves it with `approve_qr_token`.
+    pub fn create_qr_token(&mut self, session_token: &str) -> Result<QrToken> {
+        let session = self.verified_session(session_token)?;
+        let tx = self.database.transaction()?;
+
+        let qr_token = generate_token();
+        let expiry_date = Utc::now() + Duration::minutes(QR_TOKEN_LIFETIME_MINUTES);
+
+        {
+            tracing::trace!("[database] create_qr_token:");
+            tracing::trace!("  user_id: {:?}", session.user.id);
+            tracing::trace!("  expiry_date: {:?}", expiry_date);
+
+            let query = Query::insert_into("qr_codes")
+                .column("user_id", param(1))
+                .column("qr_token", param(2))
+                .column("expiry_date", param(3))
+                .into_query();
+
+            query.insert(
+                &tx,
+                params![session.user.id, hash_token(&qr_token), expiry_date],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(QrToken {
+            qr_token,
+            expiry_date,
+        })
+    }
+
+    /// Approves a QR token issued for the same user as `session_token`.
+    pub fn approve_qr_token(&mut self, session_token: &str, qr_token: &str) -> Result<()> {
+        let session = self.verified_session(session_token)?;
+        let tx = self.database.transaction()?;
+
+        let qr_code = Self::tx_get_qr_code(&tx, qr_token)?
+            .filter(|qr_code| qr_code.user_id == session.user.id)
+            .ok_or(Error::QrTokenNotFound)?;
+
+        if qr_code.expiry_date < Utc::now() {
+            return Err(Error::QrTokenExpired);
+        }
+
+        {
+            tracing::trace!("[database] approve_qr_token: {:?}", hash_token(qr_token));
+
+            let query = Query::update("qr_codes")
+                .set("approved_at", param(1))
+                .condition(query::eq(query::column("qr_token"), param(2)))
+                .into_query();
+
+            query.update(&tx, params![Utc::now(), hash_token(qr_token)])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Polls a QR token from the new device. Once the token has been approved
+    /// this returns a fresh session for the device and consumes the token.
+    pub fn poll_qr_token(
+        &mut self,
+        qr_token: &str,
+        track: TrackInformation,
+    ) -> Result<PollQrToken> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] poll_qr_token:");
+        tracing::trace!("  qr_token: {:?}", hash_token(qr_token));
+        tracing::trace!("  track: {:?}", track);
+
+        let qr_code = match Self::tx_get_qr_code(&tx, qr_token)? {
+            Some(qr_code) => qr_code,
+            None => return Ok(PollQrToken::QrTokenNotFound),
+        };
+
+        if qr_code.expiry_date < Utc::now() {
+            Self::tx_delete_qr_code(&tx, qr_token)?;
+            tx.commit()?;
+            return Ok(PollQrToken::QrTokenExpired);
+        }
+
+        if qr_code.approved_at.is_none() {
+            return Ok(PollQrToken::Pending);
+        }
+
+        Self::tx_delete_qr_code(&tx, qr_token)?;
+        let session = Self::tx_issue_session(&tx, &self.session_policy, qr_code.user_id, track)?;
+
+        tx.commit()?;
+        Ok(PollQrToken::Session(session))
+    }
+
+    pub fn delete_expired_qr_tokens(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_qr_tokens");
+            let query = Query::delete_from("qr_codes")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
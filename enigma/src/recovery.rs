@@ -0,0 +1,202 @@
+use chrono::DateTime;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use pbkdf2::password_hash::SaltString;
+use rand_core::{OsRng, RngCore};
+use rusqlite::params;
+
+use crate::Error;
+use crate::PasswordHashers;
+use crate::Result;
+use crate::Session;
+
+use super::Database;
+
+/// Number of codes issued by `generate_recovery_codes`.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+// Crockford's base32 alphabet, without the easily confused i, l, o and u.
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// A random code of ten characters, formatted as `xxxxx-xxxxx`.
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+
+    let code = bytes
+        .iter()
+        .map(|byte| RECOVERY_CODE_ALPHABET[(byte & 0x1f) as usize] as char)
+        .collect::<String>();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Ignores case, dashes and whitespace in codes typed in by users.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+struct InnerRecoveryCode {
+    id: i64,
+    code_hash: String,
+    code_salt: String,
+    code_method: String,
+    used_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerRecoveryCode {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            code_hash: row.get("code_hash")?,
+            code_salt: row.get("code_salt")?,
+            code_method: row.get("code_method")?,
+            used_at: row.get("used_at")?,
+        })
+    }
+}
+
+impl Database {
+    fn tx_get_unused_recovery_codes(
+        tx: &Transaction<'_>,
+        user_id: i64,
+    ) -> Result<Vec<InnerRecoveryCode>> {
+        tracing::trace!("[database] tx_get_unused_recovery_codes: {:?}", user_id);
+
+        let query = Query::select_from("recovery_codes")
+            .all_columns()
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+
+        let codes = query.select_many::<InnerRecoveryCode>(tx, params![user_id])?;
+        Ok(codes
+            .into_iter()
+            .filter(|code| code.used_at.is_none())
+            .collect())
+    }
+
+    /// Marks the matching unused recovery code as used. Returns `false` if
+    /// none matches.
+    pub(crate) fn tx_use_recovery_code(
+        tx: &Transaction<'_>,
+        password_hashers: &PasswordHashers,
+        user_id: i64,
+        code: &str,
+    ) -> Result<bool> {
+        tracing::trace!("[database] tx_use_recovery_code:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  code: [REDACTED]");
+
+        let code = normalize_recovery_code(code);
+        for recovery_code in Self::tx_get_unused_recovery_codes(tx, user_id)? {
+            let code_salt =
+                SaltString::from_b64(&recovery_code.code_salt).map_err(Error::PasswordHash)?;
+            let verified = password_hashers.verify_password(
+                &code,
+                &recovery_code.code_hash,
+                &code_salt,
+                &recovery_code.code_method,
+            )?;
+
+            if verified {
+                let query = Query::update("recovery_codes")
+                    .set("used_at", param(1))
+                    .condition(query::eq(query::column("id"), param(2)))
+                    .into_query();
+                query.update(tx, params![Utc::now(), recovery_code.id])?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Replaces the user's recovery codes with a new set and returns them.
+    /// Only hashes are stored, so the codes cannot be shown again.
+    pub fn generate_recovery_codes(&mut self, user_id: i64) -> Result<Vec<String>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] generate_recovery_codes:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect::<Vec<_>>();
+
+        {
+            let query = Query::delete_from("recovery_codes")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![user_id])?;
+
+            let query = Query::insert_into("recovery_codes")
+                .column("user_id", param(1))
+                .column("code_hash", param(2))
+                .column("code_salt", param(3))
+                .column("code_method", param(4))
+                .column("created_at", param(5))
+                .into_query();
+
+            let code_method = self.password_hashers.default_method();
+            for code in &codes {
+                let code_salt = SaltString::generate(&mut OsRng);
+                let code_hash = self
+                    .password_hashers
+                    .hash_password(&code_salt, &normalize_recovery_code(code))?;
+                query.insert(
+                    &tx,
+                    params![
+                        user_id,
+                        code_hash,
+                        code_salt.as_str(),
+                        code_method,
+                        Utc::now()
+                    ],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(codes)
+    }
+
+    /// Number of recovery codes the user has not used yet.
+    pub fn count_recovery_codes(&mut self, user_id: i64) -> Result<usize> {
+        let tx = self.database.transaction()?;
+        let codes = Self::tx_get_unused_recovery_codes(&tx, user_id)?;
+        Ok(codes.len())
+    }
+
+    /// Exchanges a challenge from `create_session` and a recovery code for
+    /// the session, for users who lost their second factor. Each code works
+    /// once and wrong codes count as failed logins.
+    pub fn complete_recovery_code_challenge(
+        &mut self,
+        challenge_token: &str,
+        code: &str,
+    ) -> Result<Session> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] complete_recovery_code_challenge:");
+
+        let password_hashers = &self.password_hashers;
+        Self::complete_login_challenge(
+            tx,
+            &self.login_throttle,
+            &self.session_policy,
+            challenge_token,
+            Error::RecoveryCodeIncorrect,
+            |tx, user_id| Self::tx_use_recovery_code(tx, password_hashers, user_id, code),
+        )
+    }
+}
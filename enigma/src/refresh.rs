@@ -0,0 +1,292 @@
+use chrono::DateTime;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::session::SessionPolicy;
+use crate::token::{generate_token, hash_token};
+use crate::Error;
+use crate::Result;
+use crate::Session;
+use crate::TrackInformation;
+
+use super::Database;
+
+struct InnerRefreshToken {
+    user_id: i64,
+    family: String,
+    session_id: i64,
+    expiry_date: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerRefreshToken {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            family: row.get("family")?,
+            session_id: row.get("session_id")?,
+            expiry_date: row.get("expiry_date")?,
+            used_at: row.get("used_at")?,
+        })
+    }
+}
+
+struct InnerSessionId {
+    id: i64,
+}
+
+impl FromRow for InnerSessionId {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self { id: row.get("id")? })
+    }
+}
+
+struct InnerSessionOwner {
+    user_id: i64,
+}
+
+impl FromRow for InnerSessionOwner {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+        })
+    }
+}
+
+impl Database {
+    /// The user of the session with this id, if it still exists.
+    pub(crate) fn tx_get_session_owner(
+        tx: &Transaction<'_>,
+        session_id: i64,
+    ) -> Result<Option<i64>> {
+        let query = Query::select_from("sessions")
+            .column("user_id")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        let session = query.select_maybe::<InnerSessionOwner>(tx, params![session_id])?;
+        Ok(session.map(|session| session.user_id))
+    }
+
+    pub(crate) fn tx_get_session_id(tx: &Transaction<'_>, session_token: &str) -> Result<i64> {
+        let query = Query::select_from("sessions")
+            .column("id")
+            .condition(query::eq(query::column("session_token"), param(1)))
+            .into_query();
+
+        let session = query
+            .select_maybe::<InnerSessionId>(tx, params![hash_token(session_token)])?
+            .ok_or(Error::SessionNotFound)?;
+        Ok(session.id)
+    }
+
+    fn tx_create_refresh_token(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        user_id: i64,
+        family: &str,
+        session_id: i64,
+    ) -> Result<Option<String>> {
+        let Some(refresh_token_lifetime) = policy.refresh_token_lifetime else {
+            return Ok(None);
+        };
+
+        tracing::trace!("[database] tx_create_refresh_token:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  session_id: {:?}", session_id);
+
+        let token = generate_token();
+        let now = Utc::now();
+        let query = Query::insert_into("refresh_tokens")
+            .column("user_id", param(1))
+            .column("family", param(2))
+            .column("session_id", param(3))
+            .column("token_hash", param(4))
+            .column("expiry_date", param(5))
+            .column("created_at", param(6))
+            .into_query();
+        query.insert(
+            tx,
+            params![
+                user_id,
+                family,
+                session_id,
+                hash_token(&token),
+                now + refresh_token_lifetime,
+                now
+            ],
+        )?;
+
+        Ok(Some(token))
+    }
+
+    /// Creates a session, along with the first refresh token of a new family
+    /// and a signed token if the policy enables them.
+    pub(crate) fn tx_issue_session(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        user_id: i64,
+        track: TrackInformation,
+    ) -> Result<Session> {
+        Self::tx_issue_session_in_family(tx, policy, user_id, track, &generate_token())
+    }
+
+    fn tx_issue_session_in_family(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        user_id: i64,
+        track: TrackInformation,
+        family: &str,
+    ) -> Result<Session> {
+        let token = Self::tx_create_session_token(tx, policy, user_id, track)?;
+        let mut session = Self::tx_get_session(tx, &token)?;
+
+        let session_id = Self::tx_get_session_id(tx, &token)?;
+        session.refresh_token =
+            Self::tx_create_refresh_token(tx, policy, user_id, family, session_id)?;
+        session.signed_token = Self::tx_issue_signed_token(tx, policy, session_id, &session)?;
+        Ok(session)
+    }
+
+    fn tx_delete_refresh_token_family(tx: &Transaction<'_>, family: &str) -> Result<()> {
+        tracing::trace!("[database] tx_delete_refresh_token_family: {:?}", family);
+
+        let query = Query::select_from("refresh_tokens")
+            .all_columns()
+            .condition(query::eq(query::column("family"), param(1)))
+            .into_query();
+        let tokens = query.select_many::<InnerRefreshToken>(tx, params![family])?;
+
+        let query = Query::delete_from("sessions")
+            .condition(query::eq(query::column("id"), param(1)))
+            .condition(query::eq(query::column("user_id"), param(2)))
+            .into_query();
+        for token in tokens {
+            if Self::tx_get_session_owner(tx, token.session_id)? != Some(token.user_id) {
+                continue;
+            }
+            Self::tx_revoke_signed_tokens(tx, token.session_id)?;
+            query.delete(tx, params![token.session_id, token.user_id])?;
+        }
+
+        let query = Query::delete_from("refresh_tokens")
+            .condition(query::eq(query::column("family"), param(1)))
+            .into_query();
+        query.delete(tx, params![family])?;
+        Ok(())
+    }
+
+    /// Revokes the refresh tokens issued to the user with a session, so a
+    /// revoked session cannot be brought back with `refresh_session`.
+    pub(crate) fn tx_delete_session_refresh_tokens(
+        tx: &Transaction<'_>,
+        user_id: i64,
+        session_id: i64,
+    ) -> Result<()> {
+        let query = Query::select_from("refresh_tokens")
+            .all_columns()
+            .condition(query::eq(query::column("session_id"), param(1)))
+            .condition(query::eq(query::column("user_id"), param(2)))
+            .into_query();
+
+        for token in query.select_many::<InnerRefreshToken>(tx, params![session_id, user_id])? {
+            Self::tx_delete_refresh_token_family(tx, &token.family)?;
+        }
+        Ok(())
+    }
+
+    /// Exchanges a refresh token for a new session and refresh token. The
+    /// old session is revoked. Presenting a refresh token a second time
+    /// revokes every session and refresh token descended from the same
+    /// login, since either the client or an attacker holds a stolen copy.
+    pub fn refresh_session(&mut self, refresh_token: &str) -> Result<Session> {
+        let tx = self.database.transaction()?;
+
+        let token_hash = hash_token(refresh_token);
+        tracing::trace!("[database] refresh_session:");
+        tracing::trace!("  token_hash: {:?}", token_hash);
+
+        let token = {
+            let query = Query::select_from("refresh_tokens")
+                .all_columns()
+                .condition(query::eq(query::column("token_hash"), param(1)))
+                .into_query();
+            query
+                .select_maybe::<InnerRefreshToken>(&tx, params![token_hash])?
+                .ok_or(Error::RefreshTokenNotFound)?
+        };
+
+        if token.used_at.is_some() {
+            tracing::warn!(
+                "refresh token reused, revoking token family of user {:?}",
+                token.user_id
+            );
+            Self::tx_delete_refresh_token_family(&tx, &token.family)?;
+            tx.commit()?;
+            return Err(Error::RefreshTokenReused);
+        }
+
+        let now = Utc::now();
+        if token.expiry_date < now {
+            return Err(Error::RefreshTokenExpired);
+        }
+
+        {
+            let query = Query::update("refresh_tokens")
+                .set("used_at", param(1))
+                .condition(query::eq(query::column("token_hash"), param(2)))
+                .into_query();
+            query.update(&tx, params![now, token_hash])?;
+        }
+
+        // the new session keeps the device information of the old one, if
+        // it still exists
+        let track = if Self::tx_get_session_owner(&tx, token.session_id)? == Some(token.user_id) {
+            let track = Self::tx_get_session_track(&tx, token.session_id)?;
+            Self::tx_revoke_signed_tokens(&tx, token.session_id)?;
+            let query = Query::delete_from("sessions")
+                .condition(query::eq(query::column("id"), param(1)))
+                .condition(query::eq(query::column("user_id"), param(2)))
+                .into_query();
+            query.delete(&tx, params![token.session_id, token.user_id])?;
+            track
+        } else {
+            TrackInformation::default()
+        };
+
+        let session = Self::tx_issue_session_in_family(
+            &tx,
+            &self.session_policy,
+            token.user_id,
+            track,
+            &token.family,
+        )?;
+
+        tx.commit()?;
+        Ok(session)
+    }
+
+    pub(crate) fn tx_delete_expired_refresh_tokens(tx: &Transaction<'_>) -> Result<()> {
+        tracing::trace!("[database] tx_delete_expired_refresh_tokens");
+        let query = Query::delete_from("refresh_tokens")
+            .condition(query::lt(query::column("expiry_date"), param(1)))
+            .into_query();
+
+        query.delete(tx, params![Utc::now()])?;
+        Ok(())
+    }
+
+    pub fn delete_expired_refresh_tokens(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+        Self::tx_delete_expired_refresh_tokens(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
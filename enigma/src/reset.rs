@@ -0,0 +1,216 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::mail::{Mail, Mailer};
+use crate::token::{generate_token, hash_token, url_encode};
+use crate::Error;
+use crate::Result;
+
+use super::Database;
+
+/// Controls password reset tokens and the mails that deliver them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordResetPolicy {
+    pub token_lifetime: Duration,
+    /// Page that redeems the token, which is appended as the `token` query
+    /// parameter.
+    pub reset_url: String,
+    pub subject: String,
+}
+
+impl Default for PasswordResetPolicy {
+    fn default() -> Self {
+        Self {
+            token_lifetime: Duration::hours(1),
+            reset_url: "http://localhost/reset-password".into(),
+            subject: "Reset your password".into(),
+        }
+    }
+}
+
+impl PasswordResetPolicy {
+    pub fn reset_link(&self, token: &str) -> String {
+        let separator = if self.reset_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{}token={}", self.reset_url, separator, url_encode(token))
+    }
+}
+
+struct InnerResetToken {
+    user_id: i64,
+    expiry_date: DateTime<Utc>,
+}
+
+impl FromRow for InnerResetToken {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            expiry_date: row.get("expiry_date")?,
+        })
+    }
+}
+
+impl Database {
+    pub fn with_mailer(mut self, mailer: impl Mailer + 'static) -> Self {
+        self.mailer = Some(Box::new(mailer));
+        self
+    }
+
+    pub fn with_password_reset_policy(
+        mut self,
+        password_reset_policy: PasswordResetPolicy,
+    ) -> Self {
+        self.password_reset_policy = password_reset_policy;
+        self
+    }
+
+    fn tx_create_password_reset_token(
+        tx: &Transaction<'_>,
+        policy: &PasswordResetPolicy,
+        user_id: i64,
+    ) -> Result<String> {
+        tracing::trace!("[database] tx_create_password_reset_token:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let token = generate_token();
+        let now = Utc::now();
+        let query = Query::insert_into("password_reset_tokens")
+            .column("user_id", param(1))
+            .column("token_hash", param(2))
+            .column("expiry_date", param(3))
+            .column("created_at", param(4))
+            .into_query();
+
+        query.insert(
+            tx,
+            params![
+                user_id,
+                hash_token(&token),
+                now + policy.token_lifetime,
+                now
+            ],
+        )?;
+
+        Ok(token)
+    }
+
+    /// Creates a reset token for a user without sending it, for operators
+    /// who deliver the link themselves.
+    pub fn create_password_reset_token(&mut self, user_id: i64) -> Result<String> {
+        let tx = self.database.transaction()?;
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+        let token =
+            Self::tx_create_password_reset_token(&tx, &self.password_reset_policy, user_id)?;
+
+        tx.commit()?;
+        Ok(token)
+    }
+
+    /// Mails a reset link to the user with the email address. Unknown
+    /// addresses are ignored, so callers cannot tell whether an account
+    /// exists.
+    pub fn request_password_reset(&mut self, email: &str) -> Result<()> {
+        let mailer = self.mailer.as_ref().ok_or(Error::MailerNotConfigured)?;
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] request_password_reset:");
+        tracing::trace!("  email: {:?}", email);
+
+        let user = match Self::tx_get_user_by_email(&tx, email) {
+            Ok(user) => user,
+            Err(Error::UserNotFound) => {
+                tracing::debug!("password reset requested for unknown email {:?}", email);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let policy = &self.password_reset_policy;
+        let token = Self::tx_create_password_reset_token(&tx, policy, user.id)?;
+        let mail = Mail {
+            to: user.email.unwrap_or_default(),
+            subject: policy.subject.clone(),
+            body: format!(
+                "A password reset was requested for {}.\n\n\
+                 Open the link below within {} minutes to choose a new password:\n\n{}\n\n\
+                 If you did not request this, you can ignore this mail.\n",
+                user.username,
+                policy.token_lifetime.num_minutes(),
+                policy.reset_link(&token)
+            ),
+        };
+
+        tx.commit()?;
+
+        mailer.send(&mail)?;
+        Ok(())
+    }
+
+    /// Redeems a reset token: sets the new password and revokes all of the
+    /// user's sessions and reset tokens.
+    pub fn reset_password(&mut self, token: &str, new_password: &str) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        let token_hash = hash_token(token);
+        tracing::trace!("[database] reset_password:");
+        tracing::trace!("  token_hash: {:?}", token_hash);
+        tracing::trace!("  new_password: [REDACTED]");
+
+        let reset_token = {
+            let query = Query::select_from("password_reset_tokens")
+                .all_columns()
+                .condition(query::eq(query::column("token_hash"), param(1)))
+                .into_query();
+            query
+                .select_maybe::<InnerResetToken>(&tx, params![token_hash])?
+                .ok_or(Error::ResetTokenNotFound)?
+        };
+
+        if reset_token.expiry_date < Utc::now() {
+            return Err(Error::ResetTokenExpired);
+        }
+
+        let user_id = reset_token.user_id;
+        Self::tx_set_user_password(&tx, &self.password_hashers, user_id, new_password)?;
+        Self::tx_delete_user_sessions(&tx, user_id, None)?;
+
+        {
+            let query = Query::delete_from("password_reset_tokens")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![user_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_expired_password_reset_tokens(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_password_reset_tokens");
+            let query = Query::delete_from("password_reset_tokens")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
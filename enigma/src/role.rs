@@ -0,0 +1,326 @@
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::Error;
+use crate::Permission;
+use crate::Result;
+
+use super::Database;
+
+/// A named bundle of permissions. Users assigned to a role get its
+/// permissions in addition to their own.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Role {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<Permission>,
+}
+
+struct InnerRole {
+    id: i64,
+    name: String,
+    description: Option<String>,
+}
+
+impl FromRow for InnerRole {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            description: row.get("description")?,
+        })
+    }
+}
+
+impl InnerRole {
+    fn into_role(self, tx: &Transaction<'_>) -> Result<Role> {
+        Ok(Role {
+            permissions: Database::tx_get_role_permissions(tx, self.id)?,
+            id: self.id,
+            name: self.name,
+            description: self.description,
+        })
+    }
+}
+
+impl Database {
+    pub(crate) fn tx_get_role_permissions(
+        tx: &Transaction<'_>,
+        role_id: i64,
+    ) -> Result<Vec<Permission>> {
+        let query = Query::select_from("role_permissions")
+            .column("site")
+            .column("permission")
+            .condition(query::eq(query::column("role_id"), param(1)))
+            .into_query();
+
+        struct InnerPermission {
+            site: String,
+            permission: String,
+        }
+
+        impl FromRow for InnerPermission {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    site: row.get("site")?,
+                    permission: row.get("permission")?,
+                })
+            }
+        }
+
+        let permissions = query.select_many::<InnerPermission>(tx, params![role_id])?;
+        let permissions = permissions
+            .into_iter()
+            .map(|p| Permission {
+                site: p.site,
+                permission: p.permission,
+            })
+            .collect();
+        Ok(permissions)
+    }
+
+    fn tx_get_role_by_id(tx: &Transaction<'_>, role_id: i64) -> Result<Role> {
+        let query = Query::select_from("roles")
+            .all_columns()
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        query
+            .select_maybe::<InnerRole>(tx, params![role_id])?
+            .ok_or(Error::RoleNotFound)?
+            .into_role(tx)
+    }
+
+    /// Roles the user is assigned to.
+    pub(crate) fn tx_get_user_roles(tx: &Transaction<'_>, user_id: i64) -> Result<Vec<Role>> {
+        struct InnerUserRole {
+            role_id: i64,
+        }
+
+        impl FromRow for InnerUserRole {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    role_id: row.get("role_id")?,
+                })
+            }
+        }
+
+        let query = Query::select_from("user_roles")
+            .column("role_id")
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+
+        query
+            .select_many::<InnerUserRole>(tx, params![user_id])?
+            .into_iter()
+            .map(|user_role| Self::tx_get_role_by_id(tx, user_role.role_id))
+            .collect()
+    }
+
+    pub fn create_role(&mut self, name: &str, description: Option<&str>) -> Result<i64> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] create_role:");
+        tracing::trace!("  name: {:?}", name);
+        tracing::trace!("  description: {:?}", description);
+
+        match Self::tx_get_role_by_name(&tx, name) {
+            Ok(_) => return Err(Error::RoleNameTaken),
+            Err(Error::RoleNotFound) => (),
+            Err(err) => return Err(err),
+        }
+
+        let query = Query::insert_into("roles")
+            .column("name", param(1))
+            .column("description", param(2))
+            .into_query();
+        let role_id = query.insert(&tx, params![name, description])?;
+
+        tx.commit()?;
+        Ok(role_id)
+    }
+
+    /// Deletes a role. Its users lose the role's permissions.
+    pub fn delete_role(&mut self, role_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] delete_role:");
+        tracing::trace!("  role_id: {:?}", role_id);
+
+        Self::tx_get_role_by_id(&tx, role_id)?;
+
+        for table in ["user_roles", "role_permissions"] {
+            let query = Query::delete_from(table)
+                .condition(query::eq(query::column("role_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![role_id])?;
+        }
+
+        let query = Query::delete_from("roles")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+        query.delete(&tx, params![role_id])?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn tx_get_role_by_name(tx: &Transaction<'_>, name: &str) -> Result<Role> {
+        let query = Query::select_from("roles")
+            .all_columns()
+            .condition(query::eq(query::column("name"), param(1)))
+            .into_query();
+
+        query
+            .select_maybe::<InnerRole>(tx, params![name])?
+            .ok_or(Error::RoleNotFound)?
+            .into_role(tx)
+    }
+
+    pub fn get_role_by_name(&self, name: &str) -> Result<Role> {
+        let tx = self.database.transaction()?;
+        let role = {
+            tracing::trace!("[database] get_role_by_name:");
+            tracing::trace!("  name: {:?}", name);
+            Self::tx_get_role_by_name(&tx, name)?
+        };
+
+        tx.commit()?;
+        Ok(role)
+    }
+
+    pub fn list_roles(&mut self) -> Result<Vec<Role>> {
+        let tx = self.database.transaction()?;
+
+        let roles = {
+            tracing::trace!("[database] list_roles");
+            let query = Query::select_from("roles").all_columns().into_query();
+
+            query
+                .select_many::<InnerRole>(&tx, params![])?
+                .into_iter()
+                .map(|role| role.into_role(&tx))
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        tx.commit()?;
+        Ok(roles)
+    }
+
+    pub fn add_role_permission(
+        &mut self,
+        role_id: i64,
+        site: &str,
+        permission: &str,
+    ) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] add_role_permission:");
+            tracing::trace!("  role_id: {:?}", role_id);
+            tracing::trace!("  site: {:?}", site);
+            tracing::trace!("  permission: {:?}", permission);
+
+            Self::tx_get_role_by_id(&tx, role_id)?;
+
+            let query = Query::insert_into("role_permissions")
+                .or_ignore()
+                .column("role_id", param(1))
+                .column("site", param(2))
+                .column("permission", param(3))
+                .into_query();
+            query.insert(&tx, params![role_id, site, permission])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_role_permission(
+        &mut self,
+        role_id: i64,
+        site: &str,
+        permission: &str,
+    ) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] remove_role_permission:");
+            tracing::trace!("  role_id: {:?}", role_id);
+            tracing::trace!("  site: {:?}", site);
+            tracing::trace!("  permission: {:?}", permission);
+
+            let query = Query::delete_from("role_permissions")
+                .condition(query::eq(query::column("role_id"), param(1)))
+                .condition(query::eq(query::column("site"), param(2)))
+                .condition(query::eq(query::column("permission"), param(3)))
+                .into_query();
+            query.delete(&tx, params![role_id, site, permission])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn assign_role(&mut self, user_id: i64, role_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] assign_role:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  role_id: {:?}", role_id);
+
+            Self::tx_get_user_by_id(&tx, user_id)?;
+            Self::tx_get_role_by_id(&tx, role_id)?;
+
+            let query = Query::insert_into("user_roles")
+                .or_ignore()
+                .column("user_id", param(1))
+                .column("role_id", param(2))
+                .into_query();
+            query.insert(&tx, params![user_id, role_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn unassign_role(&mut self, user_id: i64, role_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] unassign_role:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  role_id: {:?}", role_id);
+
+            let query = Query::delete_from("user_roles")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .condition(query::eq(query::column("role_id"), param(2)))
+                .into_query();
+            query.delete(&tx, params![user_id, role_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn list_user_roles(&mut self, user_id: i64) -> Result<Vec<Role>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] list_user_roles:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+        let roles = Self::tx_get_user_roles(&tx, user_id)?;
+
+        tx.commit()?;
+        Ok(roles)
+    }
+}
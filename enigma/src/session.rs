@@ -1,5 +1,5 @@
 use chrono::DateTime;
-use chrono::Days;
+use chrono::Duration;
 use chrono::Utc;
 use kodama_api::query;
 use kodama_api::query::param;
@@ -10,10 +10,15 @@ use kodama_api::FromRow;
 use kodama_api::Transaction;
 use rusqlite::params;
 
+use crate::challenge::SecondFactorChallenge;
+use crate::limit::SessionLimitAction;
+use crate::signed::SigningKeyEncryptionKey;
+use crate::token::{generate_token, hash_token};
 use crate::Error;
 use crate::Result;
 use crate::Session;
 use crate::TrackInformation;
+use crate::UserStatus;
 
 use super::Database;
 
@@ -22,7 +27,15 @@ use super::Database;
 pub enum VerifySession {
     Session(Session),
     SessionNotFound,
+    /// The session reached its expiry date or the policy's `max_lifetime`.
     SessionExpired,
+    /// The session was unused for longer than the policy's `idle_timeout`.
+    SessionIdleTimeout,
+    /// The session is valid but its user is disabled.
+    UserDisabled {
+        reason: Option<String>,
+        until: Option<DateTime<Utc>>,
+    },
 }
 
 impl VerifySession {
@@ -34,9 +47,221 @@ impl VerifySession {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CreateSession {
+    Session(Session),
+    /// The password was correct but the user has a second factor, which has
+    /// to be verified with the challenge before a session is issued.
+    SecondFactorRequired(SecondFactorChallenge),
+}
+
+impl CreateSession {
+    pub fn unwrap_session(self) -> Session {
+        match self {
+            CreateSession::Session(session) => session,
+            _ => panic!("second factor required"),
+        }
+    }
+}
+
+/// Controls how long sessions stay valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionPolicy {
+    /// Absolute lifetime of a session, measured from its creation.
+    pub max_lifetime: Duration,
+    /// Sessions unused for longer than this expire, measured from
+    /// `last_used_at`.
+    pub idle_timeout: Option<Duration>,
+    /// If set, sessions are created with this lifetime and every successful
+    /// `verify_session` extends the expiry to this long from now, capped at
+    /// `max_lifetime`.
+    pub sliding_renewal: Option<Duration>,
+    /// How long a second factor challenge from `create_session` is valid.
+    pub challenge_lifetime: Duration,
+    /// Lets `create_session` accept a verified email address. Login names
+    /// containing `@` are then only matched against email addresses.
+    pub email_login: bool,
+    /// If set, new sessions come with a refresh token valid for this long.
+    pub refresh_token_lifetime: Option<Duration>,
+    /// If set, new sessions come with a token signed with the current
+    /// signing key, valid for this long or until the session expires.
+    pub signed_token_lifetime: Option<Duration>,
+    /// Encrypts the signing keys stored in the database. Required to issue
+    /// signed tokens and to publish the signing keys.
+    pub signing_key_encryption_key: Option<SigningKeyEncryptionKey>,
+    /// Most active sessions a user may have, unless overridden for the user
+    /// with `Database::set_session_limit`.
+    pub max_sessions: Option<u32>,
+    /// What happens when a new session would exceed the limit.
+    pub session_limit_action: SessionLimitAction,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            max_lifetime: Duration::days(7),
+            idle_timeout: None,
+            sliding_renewal: None,
+            challenge_lifetime: Duration::minutes(5),
+            email_login: false,
+            refresh_token_lifetime: None,
+            signed_token_lifetime: None,
+            signing_key_encryption_key: None,
+            max_sessions: None,
+            session_limit_action: SessionLimitAction::default(),
+        }
+    }
+}
+
+impl SessionPolicy {
+    /// Whether a session with these dates would pass `verify_session`.
+    pub(crate) fn is_active(
+        &self,
+        expiry_date: DateTime<Utc>,
+        created_at: DateTime<Utc>,
+        last_used_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        expiry_date >= now
+            && created_at + self.max_lifetime >= now
+            && self
+                .idle_timeout
+                .is_none_or(|idle_timeout| last_used_at + idle_timeout >= now)
+    }
+
+    fn expiry_date(&self, created_at: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+        let max_expiry_date = created_at + self.max_lifetime;
+        match self.sliding_renewal {
+            Some(sliding_renewal) => max_expiry_date.min(now + sliding_renewal),
+            None => max_expiry_date,
+        }
+    }
+}
+
+/// A session as listed by `list_sessions`. Only a hash of the token is
+/// stored, so sessions are identified by `id` instead.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SessionInfo {
+    pub id: i64,
+    pub user_id: i64,
+    pub expiry_date: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub track: TrackInformation,
+    /// Whether this is the session whose token was passed to
+    /// `list_sessions`.
+    pub current: bool,
+}
+
+struct InnerSession {
+    id: i64,
+    user_id: i64,
+    /// Hash of the token, see `hash_token`.
+    session_token: String,
+    expiry_date: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    track_device: Option<String>,
+    track_user_agent: Option<String>,
+    track_ip_address: Option<String>,
+    track_location: Option<String>,
+    track_os: Option<String>,
+    track_browser: Option<String>,
+    track_screen_resolution: Option<String>,
+    track_timezone: Option<String>,
+}
+
+impl FromRow for InnerSession {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            session_token: row.get("session_token")?,
+            expiry_date: row.get("expiry_date")?,
+            created_at: row.get("created_at")?,
+            last_used_at: row.get("last_used_at")?,
+            track_device: row.get("track_device")?,
+            track_user_agent: row.get("track_user_agent")?,
+            track_ip_address: row.get("track_ip_address")?,
+            track_location: row.get("track_location")?,
+            track_os: row.get("track_os")?,
+            track_browser: row.get("track_browser")?,
+            track_screen_resolution: row.get("track_screen_resolution")?,
+            track_timezone: row.get("track_timezone")?,
+        })
+    }
+}
+
+impl InnerSession {
+    fn track(&self) -> TrackInformation {
+        TrackInformation {
+            device: self.track_device.clone(),
+            user_agent: self.track_user_agent.clone(),
+            ip_address: self.track_ip_address.clone(),
+            location: self.track_location.clone(),
+            os: self.track_os.clone(),
+            browser: self.track_browser.clone(),
+            screen_resolution: self.track_screen_resolution.clone(),
+            timezone: self.track_timezone.clone(),
+        }
+    }
+}
+
 impl Database {
-    fn tx_create_session_token(
+    /// Replaces session tokens stored before `005.sql` with their digest.
+    pub(crate) fn hash_legacy_session_tokens(database: &kodama_api::Database) -> Result<()> {
+        struct InnerLegacySession {
+            id: i64,
+            session_token: String,
+        }
+
+        impl FromRow for InnerLegacySession {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    id: row.get("id")?,
+                    session_token: row.get("session_token")?,
+                })
+            }
+        }
+
+        let tx = database.transaction()?;
+
+        {
+            let query = Query::select_from("sessions")
+                .column("id")
+                .column("session_token")
+                .condition(query::eq(query::column("token_hashed"), param(1)))
+                .into_query();
+            let sessions = query.select_many::<InnerLegacySession>(&tx, params![false])?;
+
+            if !sessions.is_empty() {
+                tracing::info!("hashing {} legacy session tokens", sessions.len());
+            }
+
+            let update_query = Query::update("sessions")
+                .set("session_token", param(1))
+                .set("token_hashed", param(2))
+                .condition(query::eq(query::column("id"), param(3)))
+                .into_query();
+            for session in sessions {
+                let token_hash = hash_token(&session.session_token);
+                update_query.update(&tx, params![token_hash, true, session.id])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn with_session_policy(mut self, session_policy: SessionPolicy) -> Self {
+        self.session_policy = session_policy;
+        self
+    }
+
+    pub(crate) fn tx_create_session_token(
         tx: &Transaction<'_>,
+        policy: &SessionPolicy,
         user_id: i64,
         track: TrackInformation,
     ) -> Result<String> {
@@ -44,30 +269,33 @@ impl Database {
         tracing::trace!("  user_id: {:?}", user_id);
         tracing::trace!("  track: {:?}", track);
 
+        Self::tx_check_user_active(tx, user_id)?;
+        Self::tx_enforce_session_limit(tx, policy, user_id)?;
+
         // try to create a session token
         let token_query = Query::select_from("sessions")
             .all_columns()
             .condition(query::eq(query::column("session_token"), param(1)))
             .into_query();
-        let mut token = uuid::Uuid::new_v4().to_string();
+        let mut token = generate_token();
         let mut retries = 0;
         loop {
-            let session_token = token_query.select_maybe::<()>(tx, params![token])?;
+            let session_token = token_query.select_maybe::<()>(tx, params![hash_token(&token)])?;
             if session_token.is_none() {
                 break;
             } else if retries > 10 {
                 return Err(Error::SessionCreationFailed);
             } else {
-                token = uuid::Uuid::new_v4().to_string();
+                token = generate_token();
                 retries += 1;
             }
         }
+        let token_hash = hash_token(&token);
 
-        let expiry_date = Utc::now()
-            .checked_add_days(Days::new(7))
-            .ok_or(Error::SessionCreationFailed)?;
+        let now = Utc::now();
+        let expiry_date = policy.expiry_date(now, now);
 
-        tracing::trace!("  token: {:?}", token);
+        tracing::trace!("  token_hash: {:?}", token_hash);
         tracing::trace!("  expiry_date: {:?}", expiry_date);
 
         let insert_query = Query::insert_into("sessions")
@@ -82,13 +310,16 @@ impl Database {
             .column("track_browser", param(9))
             .column("track_screen_resolution", param(10))
             .column("track_timezone", param(11))
+            .column("created_at", param(12))
+            .column("last_used_at", param(13))
+            .column("token_hashed", param(14))
             .into_query();
 
         insert_query.insert(
             tx,
             params![
                 user_id,
-                token,
+                token_hash,
                 expiry_date,
                 track.device,
                 track.user_agent,
@@ -97,49 +328,26 @@ impl Database {
                 track.os,
                 track.browser,
                 track.screen_resolution,
-                track.timezone
+                track.timezone,
+                now,
+                now,
+                true
             ],
         )?;
 
         Ok(token)
     }
 
-    fn tx_get_session(tx: &Transaction<'_>, session_token: &str) -> Result<Session> {
-        tracing::trace!("[database] tx_get_session: {:?}", session_token);
-
-        struct InnerSession {
-            user_id: i64,
-            session_token: String,
-            expiry_date: DateTime<Utc>,
-            created_at: DateTime<Utc>,
-            track_device: Option<String>,
-            track_user_agent: Option<String>,
-            track_ip_address: Option<String>,
-            track_location: Option<String>,
-            track_os: Option<String>,
-            track_browser: Option<String>,
-            track_screen_resolution: Option<String>,
-            track_timezone: Option<String>,
-        }
+    pub(crate) fn tx_get_session(tx: &Transaction<'_>, session_token: &str) -> Result<Session> {
+        Self::tx_get_session_with_last_used(tx, session_token).map(|(session, _)| session)
+    }
 
-        impl FromRow for InnerSession {
-            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-                Ok(Self {
-                    user_id: row.get("user_id")?,
-                    session_token: row.get("session_token")?,
-                    expiry_date: row.get("expiry_date")?,
-                    created_at: row.get("created_at")?,
-                    track_device: row.get("track_device")?,
-                    track_user_agent: row.get("track_user_agent")?,
-                    track_ip_address: row.get("track_ip_address")?,
-                    track_location: row.get("track_location")?,
-                    track_os: row.get("track_os")?,
-                    track_browser: row.get("track_browser")?,
-                    track_screen_resolution: row.get("track_screen_resolution")?,
-                    track_timezone: row.get("track_timezone")?,
-                })
-            }
-        }
+    pub(crate) fn tx_get_session_with_last_used(
+        tx: &Transaction<'_>,
+        session_token: &str,
+    ) -> Result<(Session, DateTime<Utc>)> {
+        let token_hash = hash_token(session_token);
+        tracing::trace!("[database] tx_get_session: {:?}", token_hash);
 
         let inner_session = {
             let query = Query::select_from("sessions")
@@ -148,50 +356,87 @@ impl Database {
                 .into_query();
 
             query
-                .select_maybe::<InnerSession>(tx, params![session_token])?
+                .select_maybe::<InnerSession>(tx, params![token_hash])?
                 .ok_or(Error::SessionNotFound)?
         };
 
         let user = Self::tx_get_user_by_id(tx, inner_session.user_id)?;
         let session = Session {
             user,
-            session_token: inner_session.session_token,
+            session_token: session_token.to_string(),
             expiry_date: inner_session.expiry_date,
             created_at: inner_session.created_at,
-            track: TrackInformation {
-                device: inner_session.track_device,
-                user_agent: inner_session.track_user_agent,
-                ip_address: inner_session.track_ip_address,
-                location: inner_session.track_location,
-                os: inner_session.track_os,
-                browser: inner_session.track_browser,
-                screen_resolution: inner_session.track_screen_resolution,
-                timezone: inner_session.track_timezone,
-            },
+            track: inner_session.track(),
+            refresh_token: None,
+            signed_token: None,
         };
 
-        Ok(session)
+        Ok((session, inner_session.last_used_at))
     }
 
-    fn tx_update_last_used(tx: &Transaction<'_>, session_token: &str) -> Result<()> {
-        tracing::trace!("[database] tx_update_last_used: {:?}", session_token);
+    /// Device information of the session, or the default if it is gone.
+    pub(crate) fn tx_get_session_track(
+        tx: &Transaction<'_>,
+        session_id: i64,
+    ) -> Result<TrackInformation> {
+        let query = Query::select_from("sessions")
+            .all_columns()
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+
+        let inner_session = query.select_maybe::<InnerSession>(tx, params![session_id])?;
+        Ok(inner_session
+            .map(|inner_session| inner_session.track())
+            .unwrap_or_default())
+    }
+
+    fn tx_update_last_used(
+        tx: &Transaction<'_>,
+        session_token: &str,
+        now: DateTime<Utc>,
+        expiry_date: DateTime<Utc>,
+    ) -> Result<()> {
+        let token_hash = hash_token(session_token);
+        tracing::trace!("[database] tx_update_last_used: {:?}", token_hash);
+        tracing::trace!("  expiry_date: {:?}", expiry_date);
 
         let query = Query::update("sessions")
             .set("last_used_at", param(1))
-            .condition(query::eq(query::column("session_token"), param(2)))
+            .set("expiry_date", param(2))
+            .condition(query::eq(query::column("session_token"), param(3)))
             .into_query();
 
-        query.update(tx, params![Utc::now(), session_token])?;
+        query.update(tx, params![now, expiry_date, token_hash])?;
 
         Ok(())
     }
 
+    /// Maps a login name to a username. With email login, names containing
+    /// `@` are only looked up as verified email addresses, so that a username
+    /// cannot stand in for someone else's address. Returns `None` if no user
+    /// has the address.
+    fn tx_resolve_login_name(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        login_name: &str,
+    ) -> Result<Option<String>> {
+        if !policy.email_login || !login_name.contains('@') {
+            return Ok(Some(login_name.to_string()));
+        }
+
+        match Self::tx_get_user_by_email(tx, login_name) {
+            Ok(user) if user.email_verified_at.is_some() => Ok(Some(user.username)),
+            Ok(_) | Err(Error::UserNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
     pub fn create_session(
         &mut self,
         username: &str,
         password: &str,
         track: TrackInformation,
-    ) -> Result<Session> {
+    ) -> Result<CreateSession> {
         let tx = self.database.transaction()?;
 
         tracing::trace!("[database] create_session:");
@@ -199,67 +444,322 @@ impl Database {
         tracing::trace!("  password: [REDACTED]");
         tracing::trace!("  track: {:?}", track);
 
-        let user_id = match Self::tx_verify_password(&tx, username, password) {
+        let ip_address = track.ip_address.clone();
+        let username = match Self::tx_resolve_login_name(&tx, &self.session_policy, username)? {
+            Some(username) => username,
+            None => {
+                Self::tx_check_login_throttle(
+                    &tx,
+                    &self.login_throttle,
+                    None,
+                    ip_address.as_deref(),
+                )?;
+                Self::tx_record_login_attempt(&tx, None, ip_address.as_deref(), false)?;
+                tx.commit()?;
+                return Err(Error::PasswordIncorrect);
+            }
+        };
+        let username = username.as_str();
+
+        let known_user_id = match Self::tx_get_user_password(&tx, username) {
+            Ok((user_id, ..)) => Some(user_id),
+            Err(Error::UserNotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        Self::tx_check_login_throttle(
+            &tx,
+            &self.login_throttle,
+            known_user_id,
+            ip_address.as_deref(),
+        )?;
+
+        let verified = Self::tx_verify_password(&tx, &self.password_hashers, username, password);
+        let user_id = match verified {
             Ok(Some(user_id)) => user_id,
-            Ok(None) => return Err(Error::PasswordIncorrect),
+            Ok(None) => {
+                Self::tx_record_login_attempt(&tx, known_user_id, ip_address.as_deref(), false)?;
+                tx.commit()?;
+                return Err(Error::PasswordIncorrect);
+            }
             Err(err) => {
                 tracing::warn!("failed to verify password: {:?}", err);
                 return Err(err);
             }
         };
 
-        let token = Self::tx_create_session_token(&tx, user_id, track)?;
-        let session = Self::tx_get_session(&tx, &token)?;
+        Self::tx_check_user_active(&tx, user_id)?;
+        // the password is correct, so a failed upgrade must not fail the login
+        if let Err(err) = Self::tx_upgrade_password(&tx, &self.password_hashers, username, password)
+        {
+            tracing::warn!("failed to upgrade password hash: {:?}", err);
+        }
+
+        // the login only counts as successful once the second factor is verified
+        if Self::tx_has_totp(&tx, user_id)? {
+            let expiry_date = Utc::now() + self.session_policy.challenge_lifetime;
+            let challenge = Self::tx_create_login_challenge(&tx, expiry_date, user_id, track)?;
+            tx.commit()?;
+            return Ok(CreateSession::SecondFactorRequired(challenge));
+        }
+
+        Self::tx_record_login_attempt(&tx, Some(user_id), ip_address.as_deref(), true)?;
+
+        let session = Self::tx_issue_session(&tx, &self.session_policy, user_id, track)?;
 
         tx.commit()?;
-        Ok(session)
+        Ok(CreateSession::Session(session))
     }
 
     pub fn verify_session(&mut self, session_token: &str) -> Result<VerifySession> {
         let tx = self.database.transaction()?;
 
-        let session = match Self::tx_get_session(&tx, session_token) {
-            Ok(session) => session,
-            Err(Error::SessionNotFound) => return Ok(VerifySession::SessionNotFound),
-            Err(err) => return Err(err),
-        };
+        let (mut session, last_used_at) =
+            match Self::tx_get_session_with_last_used(&tx, session_token) {
+                Ok(session) => session,
+                Err(Error::SessionNotFound) => return Ok(VerifySession::SessionNotFound),
+                Err(err) => return Err(err),
+            };
 
-        if session.expiry_date < Utc::now() {
+        let now = Utc::now();
+        let policy = &self.session_policy;
+        if session.expiry_date < now || session.created_at + policy.max_lifetime < now {
             return Ok(VerifySession::SessionExpired);
         }
 
-        Self::tx_update_last_used(&tx, session_token)?;
+        if let Some(idle_timeout) = policy.idle_timeout {
+            if last_used_at + idle_timeout < now {
+                return Ok(VerifySession::SessionIdleTimeout);
+            }
+        }
+
+        if let UserStatus::Disabled { reason, until } = &session.user.status {
+            return Ok(VerifySession::UserDisabled {
+                reason: reason.clone(),
+                until: *until,
+            });
+        }
+
+        if policy.sliding_renewal.is_some() {
+            session.expiry_date = session
+                .expiry_date
+                .max(policy.expiry_date(session.created_at, now));
+        }
+
+        Self::tx_update_last_used(&tx, session_token, now, session.expiry_date)?;
 
         tx.commit()?;
         Ok(VerifySession::Session(session))
     }
 
-    pub fn delete_session(&mut self, session_token: &str) -> Result<()> {
+    /// Deletes all sessions of the user except the one with `except_token`,
+    /// along with their refresh tokens and signed tokens.
+    pub(crate) fn tx_delete_user_sessions(
+        tx: &Transaction<'_>,
+        user_id: i64,
+        except_token: Option<&str>,
+    ) -> Result<()> {
+        tracing::trace!("[database] tx_delete_user_sessions:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        struct InnerSessionId {
+            id: i64,
+        }
+
+        impl FromRow for InnerSessionId {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self { id: row.get("id")? })
+            }
+        }
+
+        // `(session_token = ?2) = false` spares the excepted session. No
+        // token hashes to the empty string, so without one none is spared.
+        let except_hash = except_token.map(hash_token).unwrap_or_default();
+        let not_excepted = || {
+            query::eq(
+                query::eq(query::column("session_token"), param(2)),
+                param(3),
+            )
+        };
+
+        let query = Query::select_from("sessions")
+            .column("id")
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .condition(not_excepted())
+            .into_query();
+        for session in
+            query.select_many::<InnerSessionId>(tx, params![user_id, except_hash, false])?
+        {
+            Self::tx_revoke_signed_tokens(tx, session.id)?;
+            Self::tx_delete_session_refresh_tokens(tx, user_id, session.id)?;
+        }
+
+        let query = Query::delete_from("sessions")
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .condition(not_excepted())
+            .into_query();
+        query.delete(tx, params![user_id, except_hash, false])?;
+
+        Ok(())
+    }
+
+    /// Deletes a session along with its refresh tokens, and revokes the
+    /// signed tokens issued for it.
+    pub(crate) fn tx_delete_session_by_id(tx: &Transaction<'_>, session_id: i64) -> Result<()> {
+        let Some(user_id) = Self::tx_get_session_owner(tx, session_id)? else {
+            return Ok(());
+        };
+        Self::tx_delete_session_refresh_tokens(tx, user_id, session_id)?;
+        Self::tx_revoke_signed_tokens(tx, session_id)?;
+
+        let query = Query::delete_from("sessions")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+        query.delete(tx, params![session_id])?;
+        Ok(())
+    }
+
+    /// Sessions of the user that `verify_session` would still accept, most
+    /// recently used first.
+    /// `current_session` marks the caller's own session in the list.
+    pub fn list_sessions(
+        &mut self,
+        user_id: i64,
+        current_session: Option<&str>,
+    ) -> Result<Vec<SessionInfo>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] list_sessions:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+
+        let query = Query::select_from("sessions")
+            .all_columns()
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .condition(query::lt(param(2), query::column("expiry_date")))
+            .into_query();
+        let now = Utc::now();
+        let inner_sessions = query.select_many::<InnerSession>(&tx, params![user_id, now])?;
+
+        let current_hash = current_session.map(hash_token);
+        let mut sessions = vec![];
+        for inner_session in inner_sessions {
+            if !self.session_policy.is_active(
+                inner_session.expiry_date,
+                inner_session.created_at,
+                inner_session.last_used_at,
+                now,
+            ) {
+                continue;
+            }
+            let current = current_hash.as_ref() == Some(&inner_session.session_token);
+            sessions.push(SessionInfo {
+                id: inner_session.id,
+                user_id: inner_session.user_id,
+                expiry_date: inner_session.expiry_date,
+                created_at: inner_session.created_at,
+                last_used_at: inner_session.last_used_at,
+                track: inner_session.track(),
+                current,
+            });
+        }
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_used_at));
+
+        tx.commit()?;
+        Ok(sessions)
+    }
+
+    /// Revokes one of the user's sessions by its id from `list_sessions`.
+    pub fn revoke_session_by_id(&mut self, user_id: i64, session_id: i64) -> Result<()> {
         let tx = self.database.transaction()?;
 
         {
-            tracing::trace!("[database] delete_session: {:?}", session_token);
-            let query = Query::delete_from("sessions")
-                .condition(query::eq(query::column("session_token"), param(1)))
+            tracing::trace!("[database] revoke_session_by_id:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  session_id: {:?}", session_id);
+
+            let query = Query::select_from("sessions")
+                .column("id")
+                .condition(query::eq(query::column("id"), param(1)))
+                .condition(query::eq(query::column("user_id"), param(2)))
                 .into_query();
+            query
+                .select_maybe::<()>(&tx, params![session_id, user_id])?
+                .ok_or(Error::SessionNotFound)?;
 
-            query.delete(&tx, params![session_token])?;
+            Self::tx_delete_session_by_id(&tx, session_id)?;
         }
 
         tx.commit()?;
         Ok(())
     }
 
+    /// Revokes all of the user's sessions, except `except` if given, such
+    /// as the session of the user doing the revoking.
+    pub fn revoke_all_sessions(&mut self, user_id: i64, except: Option<&str>) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] revoke_all_sessions:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+        Self::tx_delete_user_sessions(&tx, user_id, except)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_session(&mut self, session_token: &str) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            let token_hash = hash_token(session_token);
+            tracing::trace!("[database] delete_session: {:?}", token_hash);
+            match Self::tx_get_session_id(&tx, session_token) {
+                Ok(session_id) => Self::tx_delete_session_by_id(&tx, session_id)?,
+                Err(Error::SessionNotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes expired sessions along with expired refresh tokens, and
+    /// revokes the signed tokens issued for the deleted sessions. Refresh
+    /// tokens that are still valid are kept, as they are meant to outlive
+    /// their session.
     pub fn delete_expired_sessions(&mut self) -> Result<()> {
         let tx = self.database.transaction()?;
 
         {
             tracing::trace!("[database] delete_expired_sessions");
-            let query = Query::delete_from("sessions")
-                .condition(query::lt(query::column("expiry_date"), param(1)))
-                .into_query();
 
-            query.delete(&tx, params![Utc::now()])?;
+            let now = Utc::now();
+            let mut cutoffs = vec![("expiry_date", now)];
+            if let Some(idle_timeout) = self.session_policy.idle_timeout {
+                cutoffs.push(("last_used_at", now - idle_timeout));
+            }
+
+            for (column, cutoff) in cutoffs {
+                // signed tokens may outlive an idle session
+                let query = Query::select_from("sessions")
+                    .all_columns()
+                    .condition(query::lt(query::column(column), param(1)))
+                    .into_query();
+                for session in query.select_many::<InnerSession>(&tx, params![cutoff])? {
+                    Self::tx_revoke_signed_tokens(&tx, session.id)?;
+                }
+
+                let query = Query::delete_from("sessions")
+                    .condition(query::lt(query::column(column), param(1)))
+                    .into_query();
+                query.delete(&tx, params![cutoff])?;
+            }
+
+            Self::tx_delete_expired_refresh_tokens(&tx)?;
         }
 
         tx.commit()?;
@@ -0,0 +1,595 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use chrono::DateTime;
+use chrono::Utc;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER_PERMISSIVE};
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use rusqlite::params;
+
+use crate::permission::PermissionPolicy;
+use crate::session::SessionPolicy;
+use crate::token::generate_token;
+use crate::Error;
+use crate::Permission;
+use crate::Result;
+use crate::Session;
+use crate::VerifySession;
+
+use super::Database;
+
+/// JWS algorithm of signed tokens, the only supported algorithm.
+pub const SIGNED_TOKEN_ALGORITHM: &str = "ES256";
+
+/// Claims of a signed token. `sub` is the user id, `sid` the session the
+/// token was issued for, `jti` identifies the token in the revocation list,
+/// `iat` and `exp` are Unix timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SignedTokenClaims {
+    pub sub: String,
+    pub sid: i64,
+    pub jti: String,
+    pub username: String,
+    pub permissions: Vec<Permission>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+impl SignedTokenClaims {
+    pub fn user_id(&self) -> Option<i64> {
+        self.sub.parse().ok()
+    }
+
+    /// Like `User::has_permission_with`, with the permissions the user had
+    /// when the token was issued.
+    pub fn has_permission(&self, policy: &PermissionPolicy, site: &str, permission: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|grant| policy.explain(grant, site, permission).is_some())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct SignedTokenHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// A public key in JWK format (RFC 7517).
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct JsonWebKey {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+    pub kid: String,
+    pub x: String,
+    pub y: String,
+}
+
+impl JsonWebKey {
+    fn from_verifying_key(kid: &str, verifying_key: &VerifyingKey) -> Self {
+        let point = verifying_key.to_encoded_point(false);
+        let bytes = point.as_bytes();
+        Self {
+            kty: "EC".into(),
+            crv: "P-256".into(),
+            alg: SIGNED_TOKEN_ALGORITHM.into(),
+            use_: "sig".into(),
+            kid: kid.into(),
+            x: BASE64URL_NOPAD.encode(&bytes[1..33]),
+            y: BASE64URL_NOPAD.encode(&bytes[33..]),
+        }
+    }
+
+    fn verifying_key(&self) -> Result<VerifyingKey> {
+        if self.kty != "EC" || self.crv != "P-256" {
+            return Err(Error::InvalidSigningKey);
+        }
+
+        let decode = |value: &str| {
+            BASE64URL_NOPAD
+                .decode(value.as_bytes())
+                .map_err(|_| Error::InvalidSigningKey)
+        };
+        let mut public_key = vec![0x04];
+        public_key.extend(decode(&self.x)?);
+        public_key.extend(decode(&self.y)?);
+        VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| Error::InvalidSigningKey)
+    }
+}
+
+/// Public keys for verifying signed tokens, served as a JWKS document.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct JsonWebKeySet {
+    pub keys: Vec<JsonWebKey>,
+}
+
+/// Verifies signed tokens without access to the database, from the
+/// published key set and revocation list.
+#[derive(Debug, Clone)]
+pub struct SignedTokenVerifier {
+    keys: BTreeMap<String, VerifyingKey>,
+    revoked_tokens: BTreeSet<String>,
+}
+
+impl SignedTokenVerifier {
+    pub fn new(key_set: &JsonWebKeySet) -> Result<Self> {
+        let keys = key_set
+            .keys
+            .iter()
+            .map(|key| Ok((key.kid.clone(), key.verifying_key()?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            keys,
+            revoked_tokens: BTreeSet::new(),
+        })
+    }
+
+    /// Rejects tokens with these `jti` claims, see
+    /// `Database::revoked_signed_tokens`.
+    pub fn with_revoked_tokens(mut self, jtis: impl IntoIterator<Item = String>) -> Self {
+        self.revoked_tokens.extend(jtis);
+        self
+    }
+
+    pub fn verify(&self, token: &str) -> Result<SignedTokenClaims> {
+        let (message, signature) = token.rsplit_once('.').ok_or(Error::SignedTokenInvalid)?;
+        let (header, claims) = message.split_once('.').ok_or(Error::SignedTokenInvalid)?;
+
+        let decode = |value: &str| {
+            BASE64URL_NOPAD
+                .decode(value.as_bytes())
+                .map_err(|_| Error::SignedTokenInvalid)
+        };
+        let header: SignedTokenHeader =
+            serde_json::from_slice(&decode(header)?).map_err(|_| Error::SignedTokenInvalid)?;
+        if header.alg != SIGNED_TOKEN_ALGORITHM {
+            return Err(Error::SignedTokenInvalid);
+        }
+
+        let verifying_key = self
+            .keys
+            .get(&header.kid)
+            .ok_or(Error::SignedTokenInvalid)?;
+        let signature =
+            Signature::from_slice(&decode(signature)?).map_err(|_| Error::SignedTokenInvalid)?;
+        verifying_key
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| Error::SignedTokenInvalid)?;
+
+        let claims: SignedTokenClaims =
+            serde_json::from_slice(&decode(claims)?).map_err(|_| Error::SignedTokenInvalid)?;
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(Error::SignedTokenExpired);
+        }
+        if self.revoked_tokens.contains(&claims.jti) {
+            return Err(Error::SignedTokenRevoked);
+        }
+
+        Ok(claims)
+    }
+}
+
+fn sign_token(kid: &str, signing_key: &SigningKey, claims: &SignedTokenClaims) -> Result<String> {
+    let header = SignedTokenHeader {
+        alg: SIGNED_TOKEN_ALGORITHM.into(),
+        typ: "JWT".into(),
+        kid: kid.into(),
+    };
+
+    let encode = |value: Vec<u8>| BASE64URL_NOPAD.encode(&value);
+    let message = format!(
+        "{}.{}",
+        encode(serde_json::to_vec(&header)?),
+        encode(serde_json::to_vec(claims)?)
+    );
+    let signature: Signature = signing_key.sign(message.as_bytes());
+    Ok(format!(
+        "{}.{}",
+        message,
+        encode(signature.to_bytes().to_vec())
+    ))
+}
+
+// Length of the AES-GCM nonce stored in front of each encrypted signing key.
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts the signing keys stored in the database with AES-256-GCM, so a
+/// copy of the database alone cannot be used to mint tokens. Keep it outside
+/// the database, for example in a key file.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKeyEncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for SigningKeyEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKeyEncryptionKey([REDACTED])")
+    }
+}
+
+impl SigningKeyEncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// A new random key.
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Parses a key written as 64 hex digits, as printed by `to_hex`.
+    pub fn from_hex(value: &str) -> Result<Self> {
+        let key = HEXLOWER_PERMISSIVE
+            .decode(value.trim().as_bytes())
+            .map_err(|_| Error::InvalidSigningKeyEncryptionKey)?;
+        let key = key
+            .try_into()
+            .map_err(|_| Error::InvalidSigningKeyEncryptionKey)?;
+        Ok(Self(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.0)
+    }
+
+    // The kid is authenticated along with the key, so stored keys cannot be
+    // swapped between rows.
+    fn encrypt(&self, kid: &str, signing_key: &SigningKey) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+
+        let secret = signing_key.to_bytes();
+        let payload = Payload {
+            msg: secret.as_slice(),
+            aad: kid.as_bytes(),
+        };
+        let ciphertext = Aes256Gcm::new(&self.0.into())
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::InvalidSigningKey)?;
+
+        let mut private_key = nonce.to_vec();
+        private_key.extend(ciphertext);
+        Ok(private_key)
+    }
+
+    fn decrypt(&self, kid: &str, private_key: &[u8]) -> Result<SigningKey> {
+        if private_key.len() < NONCE_LENGTH {
+            return Err(Error::InvalidSigningKey);
+        }
+        let (nonce, ciphertext) = private_key.split_at(NONCE_LENGTH);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: kid.as_bytes(),
+        };
+        let signing_key = Aes256Gcm::new(&self.0.into())
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| Error::InvalidSigningKey)?;
+        SigningKey::from_slice(&signing_key).map_err(|_| Error::InvalidSigningKey)
+    }
+}
+
+fn encryption_key(policy: &SessionPolicy) -> Result<&SigningKeyEncryptionKey> {
+    policy
+        .signing_key_encryption_key
+        .as_ref()
+        .ok_or(Error::SigningKeyEncryptionKeyMissing)
+}
+
+struct InnerSigningKey {
+    id: i64,
+    kid: String,
+    private_key: Vec<u8>,
+    created_at: DateTime<Utc>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerSigningKey {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            kid: row.get("kid")?,
+            private_key: row.get("private_key")?,
+            created_at: row.get("created_at")?,
+            retired_at: row.get("retired_at")?,
+        })
+    }
+}
+
+impl InnerSigningKey {
+    fn signing_key(&self, policy: &SessionPolicy) -> Result<SigningKey> {
+        encryption_key(policy)?.decrypt(&self.kid, &self.private_key)
+    }
+}
+
+impl Database {
+    fn tx_get_signing_keys(tx: &Transaction<'_>) -> Result<Vec<InnerSigningKey>> {
+        let query = Query::select_from("signing_keys")
+            .all_columns()
+            .into_query();
+        Ok(query.select_many::<InnerSigningKey>(tx, params![])?)
+    }
+
+    fn tx_create_signing_key(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+    ) -> Result<(String, SigningKey)> {
+        let kid = generate_token()[..16].to_string();
+        tracing::trace!("[database] tx_create_signing_key: {:?}", kid);
+
+        let signing_key = SigningKey::random(&mut OsRng);
+        let private_key = encryption_key(policy)?.encrypt(&kid, &signing_key)?;
+        let query = Query::insert_into("signing_keys")
+            .column("kid", param(1))
+            .column("private_key", param(2))
+            .column("created_at", param(3))
+            .into_query();
+        query.insert(tx, params![kid, private_key, Utc::now()])?;
+
+        Ok((kid, signing_key))
+    }
+
+    /// The key new tokens are signed with, created on first use. If several
+    /// keys are unretired the newest one is used.
+    fn tx_get_active_signing_key(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+    ) -> Result<(String, SigningKey)> {
+        let active = Self::tx_get_signing_keys(tx)?
+            .into_iter()
+            .filter(|key| key.retired_at.is_none())
+            .max_by_key(|key| (key.created_at, key.id));
+
+        match active {
+            Some(key) => {
+                let signing_key = key.signing_key(policy)?;
+                Ok((key.kid, signing_key))
+            }
+            None => Self::tx_create_signing_key(tx, policy),
+        }
+    }
+
+    /// Signs a token for the session if the policy enables signed tokens.
+    /// The token expires with the session at the latest.
+    pub(crate) fn tx_issue_signed_token(
+        tx: &Transaction<'_>,
+        policy: &SessionPolicy,
+        session_id: i64,
+        session: &Session,
+    ) -> Result<Option<String>> {
+        let Some(signed_token_lifetime) = policy.signed_token_lifetime else {
+            return Ok(None);
+        };
+
+        tracing::trace!("[database] tx_issue_signed_token:");
+        tracing::trace!("  session_id: {:?}", session_id);
+
+        let now = Utc::now();
+        let expiry_date = session.expiry_date.min(now + signed_token_lifetime);
+        let claims = SignedTokenClaims {
+            sub: session.user.id.to_string(),
+            sid: session_id,
+            jti: generate_token(),
+            username: session.user.username.clone(),
+            permissions: session.user.permissions.clone(),
+            iat: now.timestamp(),
+            exp: expiry_date.timestamp(),
+        };
+
+        let (kid, signing_key) = Self::tx_get_active_signing_key(tx, policy)?;
+        let token = sign_token(&kid, &signing_key, &claims)?;
+
+        let query = Query::insert_into("signed_tokens")
+            .column("jti", param(1))
+            .column("user_id", param(2))
+            .column("session_id", param(3))
+            .column("expiry_date", param(4))
+            .column("created_at", param(5))
+            .into_query();
+        query.insert(
+            tx,
+            params![claims.jti, session.user.id, session_id, expiry_date, now],
+        )?;
+
+        Ok(Some(token))
+    }
+
+    /// Adds the signed tokens issued for the session to the revocation
+    /// list. Called before a session is deleted.
+    pub(crate) fn tx_revoke_signed_tokens(tx: &Transaction<'_>, session_id: i64) -> Result<()> {
+        tracing::trace!("[database] tx_revoke_signed_tokens:");
+        tracing::trace!("  session_id: {:?}", session_id);
+
+        let query = Query::update("signed_tokens")
+            .set("revoked", param(1))
+            .condition(query::eq(query::column("session_id"), param(2)))
+            .into_query();
+        query.update(tx, params![true, session_id])?;
+        Ok(())
+    }
+
+    /// Adds every signed token issued to the user to the revocation list.
+    /// Called before the user is disabled or deleted.
+    pub(crate) fn tx_revoke_user_signed_tokens(tx: &Transaction<'_>, user_id: i64) -> Result<()> {
+        tracing::trace!("[database] tx_revoke_user_signed_tokens:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let query = Query::update("signed_tokens")
+            .set("revoked", param(1))
+            .condition(query::eq(query::column("user_id"), param(2)))
+            .into_query();
+        query.update(tx, params![true, user_id])?;
+        Ok(())
+    }
+
+    /// Signs a new token for a valid session, for clients whose signed
+    /// token expired before the session did.
+    pub fn issue_signed_token(&mut self, session_token: &str) -> Result<String> {
+        if self.session_policy.signed_token_lifetime.is_none() {
+            return Err(Error::SignedTokensDisabled);
+        }
+
+        let session = match self.verify_session(session_token)? {
+            VerifySession::Session(session) => session,
+            VerifySession::UserDisabled { reason, until } => {
+                return Err(Error::UserDisabled { reason, until })
+            }
+            VerifySession::SessionNotFound
+            | VerifySession::SessionExpired
+            | VerifySession::SessionIdleTimeout => return Err(Error::SessionNotFound),
+        };
+
+        let tx = self.database.transaction()?;
+        let session_id = Self::tx_get_session_id(&tx, session_token)?;
+        let token = Self::tx_issue_signed_token(&tx, &self.session_policy, session_id, &session)?
+            .ok_or(Error::SignedTokensDisabled)?;
+
+        tx.commit()?;
+        Ok(token)
+    }
+
+    /// Retires the current signing key and returns the id of its
+    /// replacement. Retired keys stay in `signing_key_set` so tokens they
+    /// signed remain valid until they expire.
+    pub fn rotate_signing_key(&mut self) -> Result<String> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] rotate_signing_key");
+
+        {
+            let query = Query::update("signing_keys")
+                .set("retired_at", param(1))
+                .condition(query::eq(query::column("kid"), param(2)))
+                .into_query();
+            let now = Utc::now();
+            for key in Self::tx_get_signing_keys(&tx)? {
+                if key.retired_at.is_none() {
+                    query.update(&tx, params![now, key.kid])?;
+                }
+            }
+        }
+        let (kid, _) = Self::tx_create_signing_key(&tx, &self.session_policy)?;
+
+        tx.commit()?;
+        Ok(kid)
+    }
+
+    /// Public keys of all signing keys, for services verifying signed tokens
+    /// with `SignedTokenVerifier`.
+    pub fn signing_key_set(&mut self) -> Result<JsonWebKeySet> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] signing_key_set");
+
+        let keys = Self::tx_get_signing_keys(&tx)?
+            .into_iter()
+            .map(|key| {
+                let signing_key = key.signing_key(&self.session_policy)?;
+                Ok(JsonWebKey::from_verifying_key(
+                    &key.kid,
+                    signing_key.verifying_key(),
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        tx.commit()?;
+        Ok(JsonWebKeySet { keys })
+    }
+
+    /// The `jti` claims of signed tokens that are revoked but not yet
+    /// expired.
+    pub fn revoked_signed_tokens(&mut self) -> Result<Vec<String>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] revoked_signed_tokens");
+
+        struct InnerRevokedToken {
+            jti: String,
+        }
+
+        impl FromRow for InnerRevokedToken {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    jti: row.get("jti")?,
+                })
+            }
+        }
+
+        let query = Query::select_from("signed_tokens")
+            .column("jti")
+            .condition(query::eq(query::column("revoked"), param(1)))
+            .condition(query::lt(param(2), query::column("expiry_date")))
+            .into_query();
+        let jtis = query
+            .select_many::<InnerRevokedToken>(&tx, params![true, Utc::now()])?
+            .into_iter()
+            .map(|revoked| revoked.jti)
+            .collect();
+
+        tx.commit()?;
+        Ok(jtis)
+    }
+
+    /// A verifier with the current key set and revocation list.
+    pub fn signed_token_verifier(&mut self) -> Result<SignedTokenVerifier> {
+        let key_set = self.signing_key_set()?;
+        let revoked_tokens = self.revoked_signed_tokens()?;
+        Ok(SignedTokenVerifier::new(&key_set)?.with_revoked_tokens(revoked_tokens))
+    }
+
+    pub fn verify_signed_token(&mut self, token: &str) -> Result<SignedTokenClaims> {
+        self.signed_token_verifier()?.verify(token)
+    }
+
+    /// Deletes retired signing keys once every token they signed has expired.
+    pub fn delete_retired_signing_keys(&mut self) -> Result<()> {
+        let Some(signed_token_lifetime) = self.session_policy.signed_token_lifetime else {
+            return Ok(());
+        };
+
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_retired_signing_keys");
+            let query = Query::delete_from("signing_keys")
+                .condition(query::lt(query::column("retired_at"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now() - signed_token_lifetime])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Forgets signed tokens once they have expired, revoked or not.
+    pub fn delete_expired_signed_token_revocations(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_signed_token_revocations");
+            let query = Query::delete_from("signed_tokens")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
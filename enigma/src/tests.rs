@@ -1,13 +1,98 @@
+use chrono::{DateTime, Duration, Utc};
+use kodama_api::query::{self, param, IntoQuery, Query};
+use kodama_api::DatabaseQuery;
 use pbkdf2::password_hash::SaltString;
+use rusqlite::params;
 
-use crate::{user::CreateUser, VerifySession, TrackInformation};
+use crate::{
+    mail::{FileMailer, Mail, MemoryMailer},
+    password::{Argon2Method, BcryptMethod, Pbkdf2Method, ScryptMethod},
+    permission::glob_match,
+    qr::PollQrToken,
+    recovery::RECOVERY_CODE_COUNT,
+    token::hash_token,
+    totp::totp_code,
+    user::{CreateUser, UserUpdate},
+    webauthn::{
+        WebAuthnAssertion, WebAuthnAssertionOptions, WebAuthnRegistration,
+        WebAuthnRegistrationOptions,
+    },
+    CreateSession, EmailVerificationPolicy, Error, JsonWebKeySet, LoginThrottle, Mailer,
+    PasswordHashers, PasswordResetPolicy, Permission, PermissionPolicy, SessionLimitAction,
+    SessionPolicy, SignedTokenClaims, SignedTokenVerifier, SigningKeyEncryptionKey, TotpPolicy,
+    TrackInformation, User, UserStatus, VerifySession,
+};
 
 use super::Database;
 
 // Setup and teardown functions for tests
 fn setup_test_db() -> Database {
     let db = Database::new(":memory:").expect("failed to create database");
-    db
+    db.with_password_hashers(test_password_hashers())
+}
+
+// Creates a user with the password "password123", hashed with a low iteration
+// count so tests that log in repeatedly stay fast.
+fn create_test_user(db: &mut Database, username: &str) -> i64 {
+    let password_salt = SaltString::from_b64("vkzROAFwR3Zgx+KZU7Ecxw").unwrap();
+    db.create_user_with_hash_password(
+        username,
+        &None,
+        "$pbkdf2-sha256$i=1000,l=32$vkzROAFwR3Zgx+KZU7Ecxw$fO5ThASO/f3RAhsBAU0917aJ1D9eMK+tGktBhnA0YaA",
+        password_salt,
+        "pbkdf2-sha256",
+    )
+    .expect("failed to create user")
+}
+
+// Password hashers with minimal work factors, so tests can hash with every
+// method quickly. The pbkdf2 parameters match the hashes of `create_test_user`.
+fn test_password_hashers() -> PasswordHashers {
+    PasswordHashers::default()
+        .with_method(Pbkdf2Method {
+            params: pbkdf2::Params {
+                rounds: 1000,
+                output_length: 32,
+            },
+        })
+        .with_method(Argon2Method {
+            params: argon2::Params::new(64, 1, 1, None).unwrap(),
+        })
+        .with_method(ScryptMethod {
+            params: scrypt::Params::new(4, 8, 1, 32).unwrap(),
+        })
+        .with_method(BcryptMethod { cost: 4 })
+}
+
+fn password_method(db: &Database, username: &str) -> String {
+    let tx = db.database.transaction().unwrap();
+    let (_, _, _, password_method) = Database::tx_get_user_password(&tx, username).unwrap();
+    password_method
+}
+
+fn password_hash(db: &Database, username: &str) -> String {
+    let tx = db.database.transaction().unwrap();
+    let (_, password_hash, _, _) = Database::tx_get_user_password(&tx, username).unwrap();
+    password_hash
+}
+
+fn set_session_date(db: &Database, session_token: &str, column: &str, date: DateTime<Utc>) {
+    let tx = db.database.transaction().unwrap();
+    let query = Query::update("sessions")
+        .set(column, param(1))
+        .condition(query::eq(query::column("session_token"), param(2)))
+        .into_query();
+    query
+        .update(&tx, params![date, hash_token(session_token)])
+        .unwrap();
+    tx.commit().unwrap();
+}
+
+fn track_from_ip(ip_address: &str) -> TrackInformation {
+    TrackInformation {
+        ip_address: Some(ip_address.into()),
+        ..Default::default()
+    }
 }
 
 #[test]
@@ -60,7 +145,7 @@ fn test_add_and_remove_permission() {
     let site = "example.com";
     let permission = "read";
 
-    db.add_permission(user_id, site, permission).unwrap();
+    db.add_permission(user_id, site, permission, None).unwrap();
 
     let permissions = db.get_user_by_id(user_id).unwrap().permissions;
     assert_eq!(permissions.len(), 1);
@@ -99,7 +184,8 @@ fn test_create_session_token() {
     };
     let session = db
         .create_session("test", "password123", track)
-        .expect("failed to create session");
+        .expect("failed to create session")
+        .unwrap_session();
     assert_eq!(session.user.username, "test");
     assert_eq!(session.track.device.as_ref().unwrap(), "Android");
 
@@ -109,3 +195,3007 @@ fn test_create_session_token() {
     assert_eq!(matches!(get_session, VerifySession::Session(_)), true);
     assert_eq!(session, get_session.unwrap_session());
 }
+
+#[test]
+#[tracing_test::traced_test]
+fn test_same_permission_for_multiple_users() {
+    let mut db = setup_test_db();
+
+    let alice = db
+        .create_user(CreateUser {
+            username: "alice".into(),
+            password: "password123".into(),
+            email: None,
+        })
+        .unwrap();
+    let bob = db
+        .create_user(CreateUser {
+            username: "bob".into(),
+            password: "password123".into(),
+            email: None,
+        })
+        .unwrap();
+
+    db.add_permission(alice, "example.com", "read", None)
+        .unwrap();
+    db.add_permission(bob, "example.com", "read", None).unwrap();
+
+    assert!(db
+        .get_user_by_id(alice)
+        .unwrap()
+        .has_permission("example.com", "read"));
+    assert!(db
+        .get_user_by_id(bob)
+        .unwrap()
+        .has_permission("example.com", "read"));
+
+    db.remove_permission(alice, "example.com", "read").unwrap();
+
+    assert!(!db
+        .get_user_by_id(alice)
+        .unwrap()
+        .has_permission("example.com", "read"));
+    assert!(db
+        .get_user_by_id(bob)
+        .unwrap()
+        .has_permission("example.com", "read"));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_login_throttle_per_user() {
+    let mut db = setup_test_db().with_login_throttle(LoginThrottle {
+        max_failures_per_user: 3,
+        max_failures_per_ip: 100,
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+
+    for i in 0..3 {
+        let ip_address = format!("10.0.0.{}", i);
+        let result = db.create_session("test", "wrong", track_from_ip(&ip_address));
+        assert!(matches!(result, Err(Error::PasswordIncorrect)));
+    }
+
+    let result = db.create_session("test", "password123", track_from_ip("10.0.0.9"));
+    assert!(matches!(result, Err(Error::TooManyAttempts { .. })));
+
+    db.clear_login_attempts(user_id).unwrap();
+    db.create_session("test", "password123", track_from_ip("10.0.0.9"))
+        .expect("failed to create session after clearing lockout");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_login_throttle_resets_after_success() {
+    let mut db = setup_test_db().with_login_throttle(LoginThrottle {
+        max_failures_per_user: 2,
+        max_failures_per_ip: 100,
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    assert!(db
+        .create_session("test", "wrong", Default::default())
+        .is_err());
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session");
+    assert!(db
+        .create_session("test", "wrong", Default::default())
+        .is_err());
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_login_throttle_per_ip() {
+    let mut db = setup_test_db().with_login_throttle(LoginThrottle {
+        max_failures_per_user: 100,
+        max_failures_per_ip: 2,
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let result = db.create_session("nobody", "wrong", track_from_ip("10.0.0.1"));
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+    let result = db.create_session("test", "wrong", track_from_ip("10.0.0.1"));
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    let result = db.create_session("test", "password123", track_from_ip("10.0.0.1"));
+    assert!(matches!(result, Err(Error::TooManyAttempts { .. })));
+
+    db.create_session("test", "password123", track_from_ip("10.0.0.2"))
+        .expect("failed to create session from another address");
+
+    db.clear_login_attempts_by_ip("10.0.0.1").unwrap();
+    db.create_session("test", "password123", track_from_ip("10.0.0.1"))
+        .expect("failed to create session after clearing lockout");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_qr_login() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+
+    let owner = db
+        .create_session("test", "password123", Default::default())
+        .expect("failed to create session")
+        .unwrap_session();
+    let qr_token = db
+        .create_qr_token(&owner.session_token)
+        .expect("failed to create qr token");
+
+    let poll = db
+        .poll_qr_token(&qr_token.qr_token, track_from_ip("10.0.0.2"))
+        .unwrap();
+    assert_eq!(poll, PollQrToken::Pending);
+
+    db.approve_qr_token(&owner.session_token, &qr_token.qr_token)
+        .expect("failed to approve qr token");
+
+    let session = db
+        .poll_qr_token(&qr_token.qr_token, track_from_ip("10.0.0.2"))
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.user.username, "test");
+    assert_ne!(session.session_token, owner.session_token);
+    assert_eq!(session.track.ip_address.as_deref(), Some("10.0.0.2"));
+
+    // the token is single-use
+    let poll = db
+        .poll_qr_token(&qr_token.qr_token, Default::default())
+        .unwrap();
+    assert_eq!(poll, PollQrToken::QrTokenNotFound);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_qr_approve_requires_owner() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "alice");
+    create_test_user(&mut db, "mallory");
+
+    let alice = db
+        .create_session("alice", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let mallory = db
+        .create_session("mallory", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let qr_token = db.create_qr_token(&alice.session_token).unwrap();
+
+    let result = db.approve_qr_token(&mallory.session_token, &qr_token.qr_token);
+    assert!(matches!(result, Err(Error::QrTokenNotFound)));
+
+    let result = db.create_qr_token("not-a-session");
+    assert!(matches!(result, Err(Error::SessionNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_methods() {
+    for method in ["pbkdf2-sha256", "argon2id", "scrypt", "bcrypt"] {
+        let mut db = setup_test_db()
+            .with_password_hashers(test_password_hashers().with_default_method(method));
+        db.create_user(CreateUser {
+            username: "test".into(),
+            password: "password123".into(),
+            email: None,
+        })
+        .expect("failed to create user");
+        assert_eq!(password_method(&db, "test"), method);
+
+        let result = db.create_session("test", "wrong", Default::default());
+        assert!(
+            matches!(result, Err(Error::PasswordIncorrect)),
+            "{}",
+            method
+        );
+        db.create_session("test", "password123", Default::default())
+            .unwrap_or_else(|err| panic!("{}: {:?}", method, err));
+    }
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_upgraded_on_login() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+    assert_eq!(password_method(&db, "test"), "pbkdf2-sha256");
+
+    let mut db = db.with_password_hashers(test_password_hashers().with_default_method("argon2id"));
+
+    assert!(db
+        .create_session("test", "wrong", Default::default())
+        .is_err());
+    assert_eq!(password_method(&db, "test"), "pbkdf2-sha256");
+
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session");
+    assert_eq!(password_method(&db, "test"), "argon2id");
+
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session with upgraded password");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_params_upgraded_on_login() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+    assert!(password_hash(&db, "test").contains("i=1000,"));
+
+    let mut db = db.with_password_hashers(test_password_hashers().with_method(Pbkdf2Method {
+        params: pbkdf2::Params {
+            rounds: 2000,
+            output_length: 32,
+        },
+    }));
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session");
+    assert_eq!(password_method(&db, "test"), "pbkdf2-sha256");
+    assert!(password_hash(&db, "test").contains("i=2000,"));
+
+    db.create_session("test", "password123", Default::default())
+        .expect("failed to create session with upgraded password");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_change_password() {
+    let mut db = setup_test_db()
+        .with_password_hashers(test_password_hashers().with_default_method("argon2id"));
+    let user_id = create_test_user(&mut db, "test");
+
+    let current = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let other = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    let result = db.change_password(user_id, "wrong", "new-password");
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    db.change_password(user_id, "password123", "new-password")
+        .unwrap();
+    assert_eq!(password_method(&db, "test"), "argon2id");
+
+    let result = db.verify_session(&current.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+    let result = db.verify_session(&other.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+    db.create_session("test", "new-password", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_set_password() {
+    let mut db = setup_test_db()
+        .with_password_hashers(test_password_hashers().with_default_method("scrypt"));
+    let user_id = create_test_user(&mut db, "test");
+    let other_id = create_test_user(&mut db, "other");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let other = db
+        .create_session("other", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    let (_, _, old_salt, _) = {
+        let tx = db.database.transaction().unwrap();
+        Database::tx_get_user_password(&tx, "test").unwrap()
+    };
+    db.set_password(user_id, "new-password").unwrap();
+    let (_, _, new_salt, method) = {
+        let tx = db.database.transaction().unwrap();
+        Database::tx_get_user_password(&tx, "test").unwrap()
+    };
+    assert_ne!(old_salt, new_salt);
+    assert_eq!(method, "scrypt");
+
+    let result = db.verify_session(&session.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+    let result = db.verify_session(&other.session_token).unwrap();
+    assert_eq!(result.unwrap_session().user.id, other_id);
+
+    db.create_session("test", "new-password", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    let result = db.set_password(1234, "new-password");
+    assert!(matches!(result, Err(Error::UserNotFound)));
+}
+
+fn create_test_user_with_email(db: &mut Database, username: &str, email: &str) -> i64 {
+    let password_salt = SaltString::from_b64("vkzROAFwR3Zgx+KZU7Ecxw").unwrap();
+    db.create_user_with_hash_password(
+        username,
+        &Some(email.to_string()),
+        "$pbkdf2-sha256$i=1000,l=32$vkzROAFwR3Zgx+KZU7Ecxw$fO5ThASO/f3RAhsBAU0917aJ1D9eMK+tGktBhnA0YaA",
+        password_salt,
+        "pbkdf2-sha256",
+    )
+    .expect("failed to create user")
+}
+
+fn token_from_mail(mail: &Mail) -> String {
+    let (_, token) = mail.body.split_once("?token=").unwrap();
+    token.split_whitespace().next().unwrap().to_string()
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_reset() {
+    let mailer = MemoryMailer::default();
+    let mut db = setup_test_db()
+        .with_password_hashers(test_password_hashers().with_default_method("argon2id"))
+        .with_mailer(mailer.clone());
+    create_test_user_with_email(&mut db, "test", "test@example.com");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    // unknown addresses are silently ignored
+    db.request_password_reset("nobody@example.com").unwrap();
+    assert!(mailer.mails().is_empty());
+
+    db.request_password_reset("test@example.com").unwrap();
+    let mails = mailer.mails();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0].to, "test@example.com");
+    assert!(mails[0]
+        .body
+        .contains("http://localhost/reset-password?token="));
+    let token = token_from_mail(&mails[0]);
+
+    let result = db.reset_password("wrong", "new-password");
+    assert!(matches!(result, Err(Error::ResetTokenNotFound)));
+
+    db.reset_password(&token, "new-password").unwrap();
+    let result = db.verify_session(&session.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+    db.create_session("test", "new-password", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    // tokens are single use
+    let result = db.reset_password(&token, "another-password");
+    assert!(matches!(result, Err(Error::ResetTokenNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_reset_email_case() {
+    let mailer = MemoryMailer::default();
+    let mut db = setup_test_db().with_mailer(mailer.clone());
+    create_test_user_with_email(&mut db, "test", "Test@Example.com");
+
+    // the mail goes to the stored address, not the one typed in
+    db.request_password_reset(" TEST@example.COM").unwrap();
+    let mails = mailer.mails();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0].to, "test@example.com");
+
+    db.reset_password(&token_from_mail(&mails[0]), "new-password")
+        .unwrap();
+    db.create_session("test", "new-password", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_password_reset_expired() {
+    let mut db = setup_test_db().with_password_reset_policy(PasswordResetPolicy {
+        token_lifetime: Duration::seconds(-1),
+        ..Default::default()
+    });
+    let user_id = create_test_user_with_email(&mut db, "test", "test@example.com");
+
+    let result = db.request_password_reset("test@example.com");
+    assert!(matches!(result, Err(Error::MailerNotConfigured)));
+
+    let token = db.create_password_reset_token(user_id).unwrap();
+    let result = db.reset_password(&token, "new-password");
+    assert!(matches!(result, Err(Error::ResetTokenExpired)));
+
+    db.delete_expired_password_reset_tokens().unwrap();
+    let result = db.reset_password(&token, "new-password");
+    assert!(matches!(result, Err(Error::ResetTokenNotFound)));
+}
+
+#[test]
+fn test_file_mailer() {
+    let path = std::env::temp_dir().join(format!("enigma-mail-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mailer = FileMailer::new(&path);
+    let mail = Mail {
+        to: "test@example.com".into(),
+        subject: "Hello".into(),
+        body: "first".into(),
+    };
+    mailer.send(&mail).unwrap();
+    mailer
+        .send(&Mail {
+            body: "second".into(),
+            ..mail
+        })
+        .unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with("To: test@example.com\nSubject: Hello\n\nfirst\n"));
+    assert!(contents.contains("second"));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_email_verification() {
+    let mailer = MemoryMailer::default();
+    let mut db = setup_test_db().with_mailer(mailer.clone());
+    let user_id = create_test_user_with_email(&mut db, "test", "test@example.com");
+    let no_email = create_test_user(&mut db, "no-email");
+
+    let user = db.get_user_by_email("test@example.com").unwrap();
+    assert_eq!(user.id, user_id);
+    assert_eq!(user.email_verified_at, None);
+    let result = db.get_user_by_email("nobody@example.com");
+    assert!(matches!(result, Err(Error::UserNotFound)));
+
+    let result = db.request_email_verification(no_email);
+    assert!(matches!(result, Err(Error::EmailNotSet)));
+
+    db.request_email_verification(user_id).unwrap();
+    let mails = mailer.mails();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0].to, "test@example.com");
+    assert!(mails[0]
+        .body
+        .contains("http://localhost/verify-email?token="));
+    let token = token_from_mail(&mails[0]);
+
+    let result = db.verify_email("wrong");
+    assert!(matches!(result, Err(Error::VerificationTokenNotFound)));
+
+    let user = db.verify_email(&token).unwrap();
+    assert!(user.email_verified_at.is_some());
+    assert_eq!(db.get_user_by_id(user_id).unwrap(), user);
+
+    let result = db.verify_email(&token);
+    assert!(matches!(result, Err(Error::VerificationTokenNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_email_verification_expired() {
+    let mut db = setup_test_db().with_email_verification_policy(EmailVerificationPolicy {
+        token_lifetime: Duration::seconds(-1),
+        ..Default::default()
+    });
+    let user_id = create_test_user_with_email(&mut db, "test", "test@example.com");
+
+    let result = db.request_email_verification(user_id);
+    assert!(matches!(result, Err(Error::MailerNotConfigured)));
+
+    let token = db.create_email_verification_token(user_id).unwrap();
+    let result = db.verify_email(&token);
+    assert!(matches!(result, Err(Error::VerificationTokenExpired)));
+
+    db.delete_expired_email_verification_tokens().unwrap();
+    let result = db.verify_email(&token);
+    assert!(matches!(result, Err(Error::VerificationTokenNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_email_unique() {
+    let mut db = setup_test_db();
+    create_test_user_with_email(&mut db, "first", "test@example.com");
+
+    let result = db.create_user(CreateUser {
+        username: "second".into(),
+        password: "password123".into(),
+        email: Some("test@example.com".into()),
+    });
+    assert!(matches!(result, Err(Error::EmailTaken)));
+
+    // addresses differing only in case are the same address
+    let result = db.create_user(CreateUser {
+        username: "second".into(),
+        password: "password123".into(),
+        email: Some(" Test@Example.COM".into()),
+    });
+    assert!(matches!(result, Err(Error::EmailTaken)));
+
+    // users without an email address do not collide
+    create_test_user(&mut db, "third");
+    create_test_user(&mut db, "fourth");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_create_session_with_email() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user_with_email(&mut db, "test", "test@example.com");
+
+    let result = db.create_session("test@example.com", "password123", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    db = db.with_session_policy(SessionPolicy {
+        email_login: true,
+        ..Default::default()
+    });
+
+    // unverified addresses are not accepted
+    let result = db.create_session("test@example.com", "password123", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    let token = db.create_email_verification_token(user_id).unwrap();
+    db.verify_email(&token).unwrap();
+
+    let session = db
+        .create_session("test@example.com", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.user.id, user_id);
+    let result = db.create_session("test@example.com", "wrong", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    let session = db
+        .create_session("Test@Example.com", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.user.id, user_id);
+
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_email_login_ignores_usernames_with_at() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        email_login: true,
+        ..Default::default()
+    });
+    create_test_user_with_email(&mut db, "victim", "victim@example.com");
+    create_test_user(&mut db, "victim@example.com");
+
+    // the address is not verified, but the username does not stand in for it
+    let result = db.create_session("victim@example.com", "password123", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_update_user() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user_with_email(&mut db, "test", "test@example.com");
+    create_test_user_with_email(&mut db, "other", "other@example.com");
+    db.add_permission(user_id, "site", "read", None).unwrap();
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let token = db.create_email_verification_token(user_id).unwrap();
+    db.verify_email(&token).unwrap();
+
+    let result = db.update_user(
+        user_id,
+        UserUpdate {
+            username: Some("other".into()),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(result, Err(Error::UsernameTaken)));
+    let result = db.update_user(
+        user_id,
+        UserUpdate {
+            email: Some(Some("other@example.com".into())),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(result, Err(Error::EmailTaken)));
+
+    // unchanged fields keep the verification
+    let user = db
+        .update_user(
+            user_id,
+            UserUpdate {
+                username: Some("renamed".into()),
+                email: Some(Some("test@example.com".into())),
+            },
+        )
+        .unwrap();
+    assert_eq!(user.username, "renamed");
+    assert!(user.email_verified_at.is_some());
+    assert!(user.has_permission("site", "read"));
+    let result = db.get_user_by_username("test");
+    assert!(matches!(result, Err(Error::UserNotFound)));
+    db.create_session("renamed", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let VerifySession::Session(verified) = db.verify_session(&session.session_token).unwrap()
+    else {
+        panic!("session should survive the rename");
+    };
+    assert_eq!(verified.user.username, "renamed");
+
+    let user = db
+        .update_user(
+            user_id,
+            UserUpdate {
+                email: Some(Some("new@example.com".into())),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(user.email.as_deref(), Some("new@example.com"));
+    assert_eq!(user.email_verified_at, None);
+
+    let user = db
+        .update_user(
+            user_id,
+            UserUpdate {
+                email: Some(None),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(user.email, None);
+    db.get_user_by_email("test@example.com").unwrap_err();
+}
+
+#[test]
+fn test_user_update_deserialize() {
+    let update: UserUpdate = serde_json::from_str(r#"{"username": "test"}"#).unwrap();
+    assert_eq!(update.username.as_deref(), Some("test"));
+    assert_eq!(update.email, None);
+
+    let update: UserUpdate = serde_json::from_str(r#"{"email": null}"#).unwrap();
+    assert_eq!(update.email, Some(None));
+
+    let update: UserUpdate = serde_json::from_str(r#"{"email": "a@example.com"}"#).unwrap();
+    assert_eq!(update.email, Some(Some("a@example.com".into())));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_disable_user() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.user.status, UserStatus::Active);
+
+    db.disable_user(user_id, Some("abuse"), None).unwrap();
+    let user = db.get_user_by_id(user_id).unwrap();
+    assert_eq!(
+        user.status,
+        UserStatus::Disabled {
+            reason: Some("abuse".into()),
+            until: None,
+        }
+    );
+
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(result, Err(Error::UserDisabled { .. })));
+    // wrong passwords do not reveal the status
+    let result = db.create_session("test", "wrong", Default::default());
+    assert!(matches!(result, Err(Error::PasswordIncorrect)));
+
+    let result = db.verify_session(&session.session_token).unwrap();
+    assert_eq!(
+        result,
+        VerifySession::UserDisabled {
+            reason: Some("abuse".into()),
+            until: None,
+        }
+    );
+
+    db.enable_user(user_id).unwrap();
+    let result = db.verify_session(&session.session_token).unwrap();
+    assert!(matches!(result, VerifySession::Session(_)));
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_disable_user_until() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let until = Utc::now() + Duration::hours(1);
+    db.disable_user(user_id, None, Some(until)).unwrap();
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(
+        result,
+        Err(Error::UserDisabled { until: Some(date), .. }) if date == until
+    ));
+
+    // the suspension is over once the date passes
+    db.disable_user(user_id, None, Some(Utc::now() - Duration::seconds(1)))
+        .unwrap();
+    assert_eq!(
+        db.get_user_by_id(user_id).unwrap().status,
+        UserStatus::Active
+    );
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_roles() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    let other_id = create_test_user(&mut db, "other");
+    db.add_permission(user_id, "site-a", "read", None).unwrap();
+
+    let role_id = db.create_role("ops", Some("Operations")).unwrap();
+    let result = db.create_role("ops", None);
+    assert!(matches!(result, Err(Error::RoleNameTaken)));
+
+    db.add_role_permission(role_id, "site-a", "read").unwrap();
+    db.add_role_permission(role_id, "site-b", "write").unwrap();
+    db.assign_role(user_id, role_id).unwrap();
+    db.assign_role(user_id, role_id).unwrap();
+
+    // direct and role grants are merged without duplicates
+    let user = db.get_user_by_id(user_id).unwrap();
+    assert_eq!(user.permissions.len(), 2);
+    assert!(user.has_permission("site-a", "read"));
+    assert!(user.has_permission("site-b", "write"));
+    assert!(!db
+        .get_user_by_id(other_id)
+        .unwrap()
+        .has_permission("site-b", "write"));
+
+    let roles = db.list_user_roles(user_id).unwrap();
+    assert_eq!(roles.len(), 1);
+    assert_eq!(roles[0].name, "ops");
+    assert_eq!(roles[0].description.as_deref(), Some("Operations"));
+
+    // changes to the role apply to its users
+    db.add_role_permission(role_id, "site-c", "read").unwrap();
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert!(session.user.has_permission("site-c", "read"));
+    db.remove_role_permission(role_id, "site-c", "read")
+        .unwrap();
+    assert!(!db
+        .get_user_by_id(user_id)
+        .unwrap()
+        .has_permission("site-c", "read"));
+
+    db.unassign_role(user_id, role_id).unwrap();
+    let user = db.get_user_by_id(user_id).unwrap();
+    assert!(user.has_permission("site-a", "read"));
+    assert!(!user.has_permission("site-b", "write"));
+
+    db.assign_role(other_id, role_id).unwrap();
+    db.delete_role(role_id).unwrap();
+    assert!(db.list_roles().unwrap().is_empty());
+    assert!(db.get_user_by_id(other_id).unwrap().permissions.is_empty());
+    let result = db.get_role_by_name("ops");
+    assert!(matches!(result, Err(Error::RoleNotFound)));
+    let result = db.assign_role(user_id, role_id);
+    assert!(matches!(result, Err(Error::RoleNotFound)));
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("example.com", "example.com"));
+    assert!(!glob_match("example.com", "example.org"));
+    assert!(!glob_match("example.com", "www.example.com"));
+    assert!(!glob_match("", "a"));
+    assert!(glob_match("", ""));
+
+    assert!(glob_match("*", ""));
+    assert!(glob_match("*", "anything"));
+    assert!(glob_match("**", "anything"));
+    assert!(glob_match(
+        "*.internal.example.com",
+        "git.internal.example.com"
+    ));
+    assert!(glob_match(
+        "*.internal.example.com",
+        "a.b.internal.example.com"
+    ));
+    assert!(!glob_match(
+        "*.internal.example.com",
+        "internal.example.com"
+    ));
+    assert!(!glob_match(
+        "*.internal.example.com",
+        "git.internal.example.org"
+    ));
+    assert!(glob_match("git.*.example.com", "git.eu.example.com"));
+    assert!(glob_match("*example*", "www.example.com"));
+    assert!(glob_match("a*b*c", "abc"));
+    assert!(glob_match("a*b*c", "axxbyyc"));
+    assert!(!glob_match("a*b*c", "axxbyy"));
+    assert!(glob_match("a*c", "abcbc"));
+
+    assert!(glob_match("site-?", "site-a"));
+    assert!(!glob_match("site-?", "site-"));
+    assert!(!glob_match("site-?", "site-ab"));
+    assert!(glob_match("?*", "x"));
+    assert!(!glob_match("?*", ""));
+
+    assert!(glob_match("straße", "straße"));
+    assert!(glob_match("stra?e", "straße"));
+}
+
+// Policy where `admin` implies `read` and `write`.
+fn admin_policy() -> PermissionPolicy {
+    PermissionPolicy::empty()
+        .with_implication("admin", "read")
+        .with_implication("admin", "write")
+}
+
+#[test]
+fn test_permission_policy_implications() {
+    assert!(PermissionPolicy::default().implications.is_empty());
+
+    let policy = admin_policy();
+    assert_eq!(
+        policy.implication_path("admin", "read"),
+        Some(vec!["admin".to_string(), "read".to_string()])
+    );
+    assert!(policy.implication_path("admin", "write").is_some());
+    assert_eq!(policy.implication_path("write", "read"), None);
+    assert_eq!(policy.implication_path("read", "admin"), None);
+    assert_eq!(
+        policy.implication_path("read", "read"),
+        Some(vec!["read".to_string()])
+    );
+
+    // implications are transitive and cycles terminate
+    let policy = PermissionPolicy::empty()
+        .with_implication("owner", "admin")
+        .with_implication("admin", "write")
+        .with_implication("write", "read")
+        .with_implication("read", "write")
+        .with_implication("write", "read");
+    assert_eq!(policy.implications["write"], vec!["read".to_string()]);
+    assert_eq!(
+        policy.implication_path("owner", "read"),
+        Some(vec![
+            "owner".to_string(),
+            "admin".to_string(),
+            "write".to_string(),
+            "read".to_string()
+        ])
+    );
+    assert_eq!(policy.implication_path("read", "admin"), None);
+    assert_eq!(policy.implication_path("read", "delete"), None);
+
+    assert_eq!(
+        PermissionPolicy::empty().implication_path("admin", "read"),
+        None
+    );
+}
+
+fn test_user_with_permissions(permissions: &[(&str, &str)]) -> User {
+    User {
+        id: 1,
+        username: "test".into(),
+        email: None,
+        email_verified_at: None,
+        status: UserStatus::Active,
+        permissions: permissions
+            .iter()
+            .map(|(site, permission)| Permission {
+                site: site.to_string(),
+                permission: permission.to_string(),
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_has_permission_wildcards() {
+    let user = test_user_with_permissions(&[
+        ("*.internal.example.com", "*"),
+        ("example.com", "admin"),
+        ("shop-?", "read"),
+    ]);
+
+    assert!(user.has_permission("git.internal.example.com", "read"));
+    assert!(user.has_permission("git.internal.example.com", "deploy"));
+    assert!(!user.has_permission("internal.example.com", "read"));
+
+    assert!(user.has_permission("example.com", "admin"));
+    assert!(!user.has_permission("example.com", "read"));
+    assert!(user.has_permission_with(&admin_policy(), "example.com", "read"));
+    assert!(user.has_permission_with(&admin_policy(), "example.com", "write"));
+    assert!(!user.has_permission_with(&admin_policy(), "example.com", "deploy"));
+    assert!(!user.has_permission("www.example.com", "read"));
+
+    assert!(user.has_permission("shop-1", "read"));
+    assert!(!user.has_permission("shop-1", "write"));
+    assert!(!user.has_permission("shop-12", "read"));
+
+    // the required permission is never treated as a pattern
+    let user = test_user_with_permissions(&[("example.com", "read")]);
+    assert!(!user.has_permission("example.com", "*"));
+    assert!(!user.has_permission("*", "read"));
+
+    let user = test_user_with_permissions(&[]);
+    assert!(!user.has_permission("example.com", "read"));
+}
+
+#[test]
+fn test_has_permission_with_policy() {
+    let user = test_user_with_permissions(&[("example.com", "admin")]);
+
+    let empty = PermissionPolicy::empty();
+    assert!(user.has_permission_with(&empty, "example.com", "admin"));
+    assert!(!user.has_permission_with(&empty, "example.com", "read"));
+
+    let policy = PermissionPolicy::empty()
+        .with_implication("admin", "deploy")
+        .with_implication("deploy", "read");
+    assert!(user.has_permission_with(&policy, "example.com", "deploy"));
+    assert!(user.has_permission_with(&policy, "example.com", "read"));
+    assert!(!user.has_permission_with(&policy, "example.com", "write"));
+}
+
+#[test]
+fn test_explain_permission() {
+    let policy = admin_policy();
+    let user = test_user_with_permissions(&[
+        ("example.com", "admin"),
+        ("*.example.com", "read"),
+        ("example.com", "read"),
+    ]);
+
+    // a direct grant is preferred even if an implied one comes first
+    let found = user
+        .explain_permission(&policy, "example.com", "read")
+        .unwrap();
+    assert_eq!(found.grant, user.permissions[2]);
+    assert!(found.implication.is_empty());
+
+    let found = user
+        .explain_permission(&policy, "example.com", "write")
+        .unwrap();
+    assert_eq!(found.grant, user.permissions[0]);
+    assert_eq!(found.implication, vec!["admin", "write"]);
+
+    let found = user
+        .explain_permission(&policy, "www.example.com", "read")
+        .unwrap();
+    assert_eq!(found.grant, user.permissions[1]);
+    assert!(found.implication.is_empty());
+
+    assert_eq!(
+        user.explain_permission(&policy, "www.example.com", "write"),
+        None
+    );
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_database_permission_policy() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    db.add_permission(user_id, "*.example.com", "admin", None)
+        .unwrap();
+    let user = db.get_user_by_id(user_id).unwrap();
+    assert!(!user.has_permission_with(db.permission_policy(), "www.example.com", "read"));
+    assert!(user.has_permission_with(db.permission_policy(), "www.example.com", "admin"));
+    assert!(!user.has_permission("www.example.com", "read"));
+
+    let db = db.with_permission_policy(admin_policy());
+    assert!(user.has_permission_with(db.permission_policy(), "www.example.com", "read"));
+    assert!(!user.has_permission("www.example.com", "read"));
+}
+
+fn permission_expiry_dates(db: &Database, user_id: i64) -> Vec<Option<DateTime<Utc>>> {
+    struct InnerPermission {
+        expiry_date: Option<DateTime<Utc>>,
+    }
+
+    impl kodama_api::FromRow for InnerPermission {
+        fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+            Ok(Self {
+                expiry_date: row.get("expiry_date")?,
+            })
+        }
+    }
+
+    let tx = db.database.transaction().unwrap();
+    let query = Query::select_from("permissions")
+        .column("expiry_date")
+        .condition(query::eq(query::column("user_id"), param(1)))
+        .into_query();
+    query
+        .select_many::<InnerPermission>(&tx, params![user_id])
+        .unwrap()
+        .into_iter()
+        .map(|p| p.expiry_date)
+        .collect()
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_permission_expiry() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let tomorrow = Utc::now() + Duration::hours(24);
+    db.add_permission(user_id, "site", "write", Some(tomorrow))
+        .unwrap();
+    db.add_permission(
+        user_id,
+        "site",
+        "deploy",
+        Some(Utc::now() - Duration::seconds(1)),
+    )
+    .unwrap();
+    db.add_permission(user_id, "site", "read", None).unwrap();
+
+    let user = db.get_user_by_id(user_id).unwrap();
+    assert!(user.has_permission("site", "read"));
+    assert!(user.has_permission("site", "write"));
+    assert!(!user.has_permission("site", "deploy"));
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert!(!session.user.has_permission("site", "deploy"));
+
+    db.delete_expired_permissions().unwrap();
+    assert_eq!(permission_expiry_dates(&db, user_id).len(), 2);
+
+    // granting again keeps whichever grant lasts longer
+    db.add_permission(user_id, "site", "write", None).unwrap();
+    let expiry_dates = permission_expiry_dates(&db, user_id);
+    assert_eq!(expiry_dates, vec![None, None]);
+    db.add_permission(user_id, "site", "write", Some(tomorrow))
+        .unwrap();
+    let expiry_dates = permission_expiry_dates(&db, user_id);
+    assert_eq!(expiry_dates, vec![None, None]);
+
+    db.add_permission(user_id, "site", "deploy", Some(tomorrow))
+        .unwrap();
+    assert!(db
+        .get_user_by_id(user_id)
+        .unwrap()
+        .has_permission("site", "deploy"));
+    db.add_permission(
+        user_id,
+        "site",
+        "deploy",
+        Some(Utc::now() + Duration::hours(1)),
+    )
+    .unwrap();
+    assert!(permission_expiry_dates(&db, user_id).contains(&Some(tomorrow)));
+
+    db.remove_permission(user_id, "site", "write").unwrap();
+    db.add_permission(user_id, "site", "write", Some(tomorrow))
+        .unwrap();
+    assert_eq!(
+        permission_expiry_dates(&db, user_id)
+            .iter()
+            .filter(|expiry_date| expiry_date.is_none())
+            .count(),
+        1
+    );
+}
+
+fn permission(site: &str, permission: &str) -> Permission {
+    Permission {
+        site: site.into(),
+        permission: permission.into(),
+    }
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_api_keys() {
+    let mut db = setup_test_db().with_permission_policy(admin_policy());
+    let user_id = create_test_user(&mut db, "test");
+    let other_id = create_test_user(&mut db, "other");
+    db.add_permission(user_id, "example.com", "admin", None)
+        .unwrap();
+    db.add_permission(user_id, "other.com", "write", None)
+        .unwrap();
+
+    let result = db.create_api_key(user_id, "batch", &[permission("other.com", "admin")], None);
+    assert!(matches!(
+        result,
+        Err(Error::ScopeNotGranted { site, permission }) if site == "other.com" && permission == "admin"
+    ));
+
+    let minted = db
+        .create_api_key(
+            user_id,
+            "batch",
+            &[
+                permission("example.com", "read"),
+                permission("other.com", "write"),
+            ],
+            None,
+        )
+        .unwrap();
+    assert!(minted.key.starts_with("enigma_"));
+    assert_eq!(minted.api_key.name, "batch");
+    assert_eq!(minted.api_key.last_used_at, None);
+
+    // only the scopes, not the user's other permissions
+    let user = db.verify_api_key(&minted.key).unwrap();
+    assert_eq!(user.id, user_id);
+    assert!(user.has_permission("example.com", "read"));
+    assert!(!user.has_permission("example.com", "admin"));
+    assert!(!user.has_permission("example.com", "write"));
+    assert!(user.has_permission("other.com", "write"));
+
+    let api_keys = db.list_api_keys(user_id).unwrap();
+    assert_eq!(api_keys.len(), 1);
+    assert!(api_keys[0].last_used_at.is_some());
+    assert_eq!(api_keys[0].scopes.len(), 2);
+    assert!(db.list_api_keys(other_id).unwrap().is_empty());
+
+    // the key is stored hashed
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::select_from("api_keys")
+            .all_columns()
+            .condition(query::eq(query::column("key_hash"), param(1)))
+            .into_query();
+        assert!(query
+            .select_maybe::<()>(&tx, params![minted.key])
+            .unwrap()
+            .is_none());
+    }
+
+    // scopes the user loses are dropped
+    db.remove_permission(user_id, "other.com", "write").unwrap();
+    let user = db.verify_api_key(&minted.key).unwrap();
+    assert!(!user.has_permission("other.com", "write"));
+    assert!(user.has_permission("example.com", "read"));
+
+    db.disable_user(user_id, None, None).unwrap();
+    let result = db.verify_api_key(&minted.key);
+    assert!(matches!(result, Err(Error::UserDisabled { .. })));
+    db.enable_user(user_id).unwrap();
+
+    let result = db.revoke_api_key(other_id, minted.api_key.id);
+    assert!(matches!(result, Err(Error::ApiKeyNotFound)));
+    db.revoke_api_key(user_id, minted.api_key.id).unwrap();
+    let result = db.verify_api_key(&minted.key);
+    assert!(matches!(result, Err(Error::ApiKeyNotFound)));
+    assert!(db.list_api_keys(user_id).unwrap().is_empty());
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_api_key_expiry() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    db.add_permission(user_id, "example.com", "read", None)
+        .unwrap();
+
+    let scopes = [permission("example.com", "read")];
+    let expired = db
+        .create_api_key(
+            user_id,
+            "expired",
+            &scopes,
+            Some(Utc::now() - Duration::seconds(1)),
+        )
+        .unwrap();
+    let valid = db
+        .create_api_key(
+            user_id,
+            "valid",
+            &scopes,
+            Some(Utc::now() + Duration::hours(1)),
+        )
+        .unwrap();
+
+    let result = db.verify_api_key(&expired.key);
+    assert!(matches!(result, Err(Error::ApiKeyExpired)));
+    db.verify_api_key(&valid.key).unwrap();
+
+    db.delete_expired_api_keys().unwrap();
+    let result = db.verify_api_key(&expired.key);
+    assert!(matches!(result, Err(Error::ApiKeyNotFound)));
+    let api_keys = db.list_api_keys(user_id).unwrap();
+    assert_eq!(api_keys.len(), 1);
+    assert_eq!(api_keys[0].name, "valid");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_api_key_pattern_scopes() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    db.add_permission(user_id, "site-?", "read", None).unwrap();
+    db.add_permission(user_id, "*.example.com", "*", None)
+        .unwrap();
+
+    // a pattern scope matching a grant is still broader than it
+    for scope in [
+        permission("site-*", "read"),
+        permission("*", "read"),
+        permission("*.example.com", "read*"),
+    ] {
+        let result = db.create_api_key(user_id, "batch", &[scope], None);
+        assert!(matches!(result, Err(Error::ScopeNotGranted { .. })));
+    }
+
+    let minted = db
+        .create_api_key(
+            user_id,
+            "batch",
+            &[
+                permission("site-?", "read"),
+                permission("*.example.com", "*"),
+                permission("git.example.com", "deploy"),
+            ],
+            None,
+        )
+        .unwrap();
+    let user = db.verify_api_key(&minted.key).unwrap();
+    assert_eq!(user.permissions.len(), 3);
+    assert!(user.has_permission("site-1", "read"));
+    assert!(!user.has_permission("site-12", "read"));
+
+    // a narrower grant no longer covers the pattern scope
+    db.remove_permission(user_id, "*.example.com", "*").unwrap();
+    db.add_permission(user_id, "*.example.com", "deploy", None)
+        .unwrap();
+    let user = db.verify_api_key(&minted.key).unwrap();
+    assert_eq!(user.permissions.len(), 2);
+    assert!(!user.permissions.contains(&permission("*.example.com", "*")));
+    assert!(user.has_permission("git.example.com", "deploy"));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_list_and_revoke_sessions() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    let other_id = create_test_user(&mut db, "other");
+
+    let laptop = db
+        .create_session("test", "password123", track_from_ip("10.0.0.1"))
+        .unwrap()
+        .unwrap_session();
+    let phone = db
+        .create_session("test", "password123", track_from_ip("10.0.0.2"))
+        .unwrap()
+        .unwrap_session();
+    let tablet = db
+        .create_session("test", "password123", track_from_ip("10.0.0.3"))
+        .unwrap()
+        .unwrap_session();
+    let expired = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    set_session_date(
+        &db,
+        &expired.session_token,
+        "expiry_date",
+        Utc::now() - Duration::seconds(1),
+    );
+    set_session_date(
+        &db,
+        &laptop.session_token,
+        "last_used_at",
+        Utc::now() + Duration::seconds(10),
+    );
+
+    let sessions = db
+        .list_sessions(user_id, Some(&phone.session_token))
+        .unwrap();
+    assert_eq!(sessions.len(), 3);
+    assert_eq!(sessions[0].track.ip_address.as_deref(), Some("10.0.0.1"));
+    assert!(sessions.iter().all(|s| s.user_id == user_id));
+    let current = sessions.iter().filter(|s| s.current).collect::<Vec<_>>();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0].track.ip_address.as_deref(), Some("10.0.0.2"));
+    assert!(db.list_sessions(other_id, None).unwrap().is_empty());
+
+    let laptop_id = sessions[0].id;
+    let result = db.revoke_session_by_id(other_id, laptop_id);
+    assert!(matches!(result, Err(Error::SessionNotFound)));
+    db.revoke_session_by_id(user_id, laptop_id).unwrap();
+    let result = db.verify_session(&laptop.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+    let result = db.revoke_session_by_id(user_id, laptop_id);
+    assert!(matches!(result, Err(Error::SessionNotFound)));
+
+    db.revoke_all_sessions(user_id, Some(&phone.session_token))
+        .unwrap();
+    let result = db.verify_session(&tablet.session_token).unwrap();
+    assert_eq!(result, VerifySession::SessionNotFound);
+    let sessions = db.list_sessions(user_id, None).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert!(matches!(
+        db.verify_session(&phone.session_token).unwrap(),
+        VerifySession::Session(_)
+    ));
+
+    db.revoke_all_sessions(user_id, None).unwrap();
+    assert!(db.list_sessions(user_id, None).unwrap().is_empty());
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_token_stored_hashed() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.session_token.len(), 64);
+
+    let tx = db.database.transaction().unwrap();
+    let query = Query::select_from("sessions")
+        .all_columns()
+        .condition(query::eq(query::column("session_token"), param(1)))
+        .into_query();
+    let plaintext = query
+        .select_maybe::<()>(&tx, params![session.session_token])
+        .unwrap();
+    let hashed = query
+        .select_maybe::<()>(&tx, params![hash_token(&session.session_token)])
+        .unwrap();
+    assert!(plaintext.is_none());
+    assert!(hashed.is_some());
+    drop(tx);
+
+    let verified = db.verify_session(&session.session_token).unwrap();
+    assert_eq!(verified.unwrap_session(), session);
+
+    let result = db.verify_session(&hash_token(&session.session_token));
+    assert_eq!(result.unwrap(), VerifySession::SessionNotFound);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_legacy_session_tokens_hashed() {
+    let path = std::env::temp_dir().join(format!("enigma-legacy-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut db = Database::new(&path).unwrap();
+    let user_id = create_test_user(&mut db, "test");
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::insert_into("sessions")
+            .column("user_id", param(1))
+            .column("session_token", param(2))
+            .column("expiry_date", param(3))
+            .into_query();
+        query
+            .insert(
+                &tx,
+                params![
+                    user_id,
+                    "3f2a6e3c-6f0e-4a55-9d4b-6f1c1f0a2b7d",
+                    Utc::now() + Duration::days(1)
+                ],
+            )
+            .unwrap();
+        tx.commit().unwrap();
+    }
+    drop(db);
+
+    let mut db = Database::new(&path).unwrap();
+    let session = db
+        .verify_session("3f2a6e3c-6f0e-4a55-9d4b-6f1c1f0a2b7d")
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.user.id, user_id);
+
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
+
+// TOTP tests confirm and complete challenges at this fixed time, so a step
+// boundary passing mid-test cannot change which codes are accepted.
+fn totp_test_time() -> DateTime<Utc> {
+    DateTime::from_timestamp(1_700_000_010, 0).unwrap()
+}
+
+// Code of the enrolled secret for the time step `offset` steps from
+// `totp_test_time`.
+fn totp_test_code(secret: &str, offset: i64) -> String {
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(secret.as_bytes())
+        .unwrap();
+    let step = totp_test_time().timestamp() / 30 + offset;
+    totp_code(&secret, step as u64, 6)
+}
+
+#[test]
+fn test_totp_code_rfc6238() {
+    let secret = b"12345678901234567890";
+    assert_eq!(totp_code(secret, 59 / 30, 8), "94287082");
+    assert_eq!(totp_code(secret, 1111111109 / 30, 8), "07081804");
+    assert_eq!(totp_code(secret, 1234567890 / 30, 8), "89005924");
+    assert_eq!(totp_code(secret, 20000000000 / 30, 8), "65353130");
+    assert_eq!(totp_code(secret, 59 / 30, 6), "287082");
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_totp_enrollment() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let enrollment = db.enroll_totp(user_id).unwrap();
+    assert_eq!(enrollment.secret.len(), 32);
+    assert!(enrollment
+        .otpauth_uri
+        .starts_with("otpauth://totp/enigma:test?secret="));
+    assert!(enrollment.otpauth_uri.contains(&enrollment.secret));
+
+    // unconfirmed enrollments are not required at login
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+
+    let result = db.confirm_totp(user_id, "000000x");
+    assert!(matches!(result, Err(Error::TotpCodeIncorrect)));
+    db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    )
+    .unwrap();
+
+    let result = db.enroll_totp(user_id);
+    assert!(matches!(result, Err(Error::TotpAlreadyEnrolled)));
+    let result = db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::TotpAlreadyEnrolled)));
+
+    db.disable_totp(user_id).unwrap();
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+fn test_totp_policy_validated() {
+    let policy = TotpPolicy {
+        digits: 10,
+        ..Default::default()
+    };
+    let result = setup_test_db().with_totp_policy(policy);
+    assert!(matches!(result, Err(Error::InvalidTotpPolicy(_))));
+
+    let policy = TotpPolicy {
+        step: Duration::zero(),
+        ..Default::default()
+    };
+    let result = setup_test_db().with_totp_policy(policy);
+    assert!(matches!(result, Err(Error::InvalidTotpPolicy(_))));
+
+    let policy = TotpPolicy {
+        digits: 8,
+        step: Duration::seconds(60),
+        ..Default::default()
+    };
+    let db = setup_test_db().with_totp_policy(policy).unwrap();
+    assert_eq!(db.totp_policy.digits, 8);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_totp_login() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let enrollment = db.enroll_totp(user_id).unwrap();
+    db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, -1),
+        totp_test_time(),
+    )
+    .unwrap();
+
+    let challenge = match db
+        .create_session("test", "password123", track_from_ip("10.0.0.1"))
+        .unwrap()
+    {
+        CreateSession::SecondFactorRequired(challenge) => challenge,
+        CreateSession::Session(_) => panic!("second factor not required"),
+    };
+    assert!(challenge.expiry_date <= Utc::now() + Duration::minutes(5));
+
+    // the code used for confirmation cannot be replayed
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, -1),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::TotpCodeIncorrect)));
+
+    let session = db
+        .complete_totp_challenge_at(
+            &challenge.challenge_token,
+            &totp_test_code(&enrollment.secret, 0),
+            totp_test_time(),
+        )
+        .unwrap();
+    assert_eq!(session.user.id, user_id);
+    assert_eq!(session.track.ip_address.as_deref(), Some("10.0.0.1"));
+    db.verify_session(&session.session_token)
+        .unwrap()
+        .unwrap_session();
+
+    // challenges are single use
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, 1),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+
+    // and so are codes
+    let challenge = match db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+    {
+        CreateSession::SecondFactorRequired(challenge) => challenge,
+        CreateSession::Session(_) => panic!("second factor not required"),
+    };
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::TotpCodeIncorrect)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_totp_challenge_throttled() {
+    let mut db = setup_test_db().with_login_throttle(LoginThrottle {
+        max_failures_per_user: 3,
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+
+    let enrollment = db.enroll_totp(user_id).unwrap();
+    db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, -1),
+        totp_test_time(),
+    )
+    .unwrap();
+
+    let challenge = match db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+    {
+        CreateSession::SecondFactorRequired(challenge) => challenge,
+        CreateSession::Session(_) => panic!("second factor not required"),
+    };
+
+    for _ in 0..3 {
+        let result = db.complete_totp_challenge(&challenge.challenge_token, "abcdef");
+        assert!(matches!(result, Err(Error::TotpCodeIncorrect)));
+    }
+
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::TooManyAttempts { .. })));
+
+    // a correct password does not reset the failures while the second factor is pending
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(result, Err(Error::TooManyAttempts { .. })));
+}
+
+fn second_factor_challenge(db: &mut Database, username: &str) -> String {
+    match db
+        .create_session(username, "password123", Default::default())
+        .unwrap()
+    {
+        CreateSession::SecondFactorRequired(challenge) => challenge.challenge_token,
+        CreateSession::Session(_) => panic!("second factor not required"),
+    }
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_recovery_codes() {
+    let mut db = setup_test_db()
+        .with_password_hashers(test_password_hashers().with_default_method("argon2id"));
+    let user_id = create_test_user(&mut db, "test");
+
+    let enrollment = db.enroll_totp(user_id).unwrap();
+    db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, -1),
+        totp_test_time(),
+    )
+    .unwrap();
+
+    let codes = db.generate_recovery_codes(user_id).unwrap();
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert_eq!(
+        db.count_recovery_codes(user_id).unwrap(),
+        RECOVERY_CODE_COUNT
+    );
+    assert!(codes.iter().all(|code| code.len() == 11));
+
+    let challenge_token = second_factor_challenge(&mut db, "test");
+    let result = db.complete_recovery_code_challenge(&challenge_token, "00000-00000");
+    assert!(matches!(result, Err(Error::RecoveryCodeIncorrect)));
+
+    // codes are accepted regardless of case and dashes
+    let code = codes[3].to_uppercase().replace('-', " ");
+    let session = db
+        .complete_recovery_code_challenge(&challenge_token, &code)
+        .unwrap();
+    assert_eq!(session.user.id, user_id);
+    assert_eq!(
+        db.count_recovery_codes(user_id).unwrap(),
+        RECOVERY_CODE_COUNT - 1
+    );
+
+    // each code works once
+    let challenge_token = second_factor_challenge(&mut db, "test");
+    let result = db.complete_recovery_code_challenge(&challenge_token, &codes[3]);
+    assert!(matches!(result, Err(Error::RecoveryCodeIncorrect)));
+
+    // regenerating invalidates the previous codes
+    let new_codes = db.generate_recovery_codes(user_id).unwrap();
+    let result = db.complete_recovery_code_challenge(&challenge_token, &codes[4]);
+    assert!(matches!(result, Err(Error::RecoveryCodeIncorrect)));
+    db.complete_recovery_code_challenge(&challenge_token, &new_codes[0])
+        .unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_recovery_codes_hashed_with_password_method() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let codes = db.generate_recovery_codes(user_id).unwrap();
+
+    struct InnerRecoveryCode {
+        code_hash: String,
+        code_salt: String,
+        code_method: String,
+    }
+
+    impl kodama_api::FromRow for InnerRecoveryCode {
+        fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+            Ok(Self {
+                code_hash: row.get("code_hash")?,
+                code_salt: row.get("code_salt")?,
+                code_method: row.get("code_method")?,
+            })
+        }
+    }
+
+    let tx = db.database.transaction().unwrap();
+    let query = Query::select_from("recovery_codes")
+        .all_columns()
+        .condition(query::eq(query::column("user_id"), param(1)))
+        .into_query();
+    let stored = query
+        .select_many::<InnerRecoveryCode>(&tx, params![user_id])
+        .unwrap();
+    assert_eq!(stored.len(), RECOVERY_CODE_COUNT);
+
+    // every code has its own salt, so they cannot be cracked together
+    let salts = stored
+        .iter()
+        .map(|code| code.code_salt.as_str())
+        .collect::<std::collections::BTreeSet<_>>();
+    assert_eq!(salts.len(), RECOVERY_CODE_COUNT);
+    for code in &stored {
+        assert_eq!(code.code_method, "pbkdf2-sha256");
+        let code_salt = SaltString::from_b64(&code.code_salt).unwrap();
+        assert!(codes.iter().any(|plain| {
+            let plain = plain.replace('-', "");
+            db.verify_password(&plain, &code.code_hash, &code_salt, &code.code_method)
+                .unwrap()
+        }));
+    }
+}
+
+// Software authenticator with a single ES256 credential and `none`
+// attestation, for the default relying party.
+struct SoftwareAuthenticator {
+    signing_key: p256::ecdsa::SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+    origin: String,
+}
+
+impl SoftwareAuthenticator {
+    fn new() -> Self {
+        Self {
+            signing_key: p256::ecdsa::SigningKey::random(&mut rand_core::OsRng),
+            credential_id: b"software-credential".to_vec(),
+            sign_count: 0,
+            origin: "http://localhost".into(),
+        }
+    }
+
+    fn authenticator_data(&self, flags: u8, attested: bool) -> Vec<u8> {
+        use sha2::Digest;
+
+        let mut data = sha2::Sha256::digest(b"localhost").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+
+        if attested {
+            let point = self.signing_key.verifying_key().to_encoded_point(false);
+            let cose_key = ciborium::Value::Map(vec![
+                (1.into(), 2.into()),
+                (3.into(), (-7).into()),
+                ((-1).into(), 1.into()),
+                ((-2).into(), point.x().unwrap().to_vec().into()),
+                ((-3).into(), point.y().unwrap().to_vec().into()),
+            ]);
+
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&cose_key, &mut data).unwrap();
+        }
+
+        data
+    }
+
+    fn client_data(&self, ceremony_type: &str, challenge: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}","crossOrigin":false}}"#,
+            ceremony_type, challenge, self.origin
+        )
+        .into_bytes()
+    }
+
+    fn register(&mut self, options: &WebAuthnRegistrationOptions) -> WebAuthnRegistration {
+        let attestation_object = ciborium::Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), ciborium::Value::Map(vec![])),
+            (
+                "authData".into(),
+                self.authenticator_data(0x45, true).into(),
+            ),
+        ]);
+        let mut attestation_bytes = Vec::new();
+        ciborium::ser::into_writer(&attestation_object, &mut attestation_bytes).unwrap();
+
+        let encode = |bytes: &[u8]| data_encoding::BASE64URL_NOPAD.encode(bytes);
+        WebAuthnRegistration {
+            client_data_json: encode(&self.client_data("webauthn.create", &options.challenge)),
+            attestation_object: encode(&attestation_bytes),
+            name: Some("software".into()),
+        }
+    }
+
+    fn assert(&mut self, options: &WebAuthnAssertionOptions) -> WebAuthnAssertion {
+        use p256::ecdsa::signature::Signer;
+        use sha2::Digest;
+
+        self.sign_count += 1;
+        let authenticator_data = self.authenticator_data(0x05, false);
+        let client_data = self.client_data("webauthn.get", &options.challenge);
+
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&sha2::Sha256::digest(&client_data));
+        let signature: p256::ecdsa::Signature = self.signing_key.sign(&message);
+
+        let encode = |bytes: &[u8]| data_encoding::BASE64URL_NOPAD.encode(bytes);
+        WebAuthnAssertion {
+            credential_id: encode(&self.credential_id),
+            client_data_json: encode(&client_data),
+            authenticator_data: encode(&authenticator_data),
+            signature: encode(signature.to_der().as_bytes()),
+            user_handle: None,
+        }
+    }
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_webauthn_login() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let options = db.start_webauthn_registration(user_id).unwrap();
+    assert_eq!(options.username, "test");
+    assert!(options.exclude_credentials.is_empty());
+
+    let credential = db
+        .finish_webauthn_registration(user_id, authenticator.register(&options))
+        .unwrap();
+    assert_eq!(credential.name.as_deref(), Some("software"));
+    assert_eq!(db.list_webauthn_credentials(user_id).unwrap().len(), 1);
+
+    let options = db.start_webauthn_registration(user_id).unwrap();
+    assert_eq!(options.exclude_credentials, vec![credential.credential_id]);
+
+    // with a username
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    assert_eq!(options.allow_credentials.len(), 1);
+    let session = db
+        .finish_webauthn_assertion(authenticator.assert(&options), track_from_ip("10.0.0.1"))
+        .unwrap();
+    assert_eq!(session.user.id, user_id);
+    db.verify_session(&session.session_token)
+        .unwrap()
+        .unwrap_session();
+
+    // with a discoverable credential
+    let options = db.start_webauthn_assertion(None).unwrap();
+    assert!(options.allow_credentials.is_empty());
+    let mut assertion = authenticator.assert(&options);
+    assertion.user_handle = Some(data_encoding::BASE64URL_NOPAD.encode(&user_id.to_be_bytes()));
+    let session = db
+        .finish_webauthn_assertion(assertion, Default::default())
+        .unwrap();
+    assert_eq!(session.user.id, user_id);
+
+    let credentials = db.list_webauthn_credentials(user_id).unwrap();
+    assert_eq!(credentials[0].sign_count, 2);
+    assert!(credentials[0].last_used_at.is_some());
+
+    db.delete_webauthn_credential(user_id, credentials[0].id)
+        .unwrap();
+    let options = db.start_webauthn_assertion(None).unwrap();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::CredentialNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_webauthn_assertion_rejected() {
+    let mut db = setup_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let options = db.start_webauthn_registration(user_id).unwrap();
+    db.finish_webauthn_registration(user_id, authenticator.register(&options))
+        .unwrap();
+
+    // challenges are single use
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let assertion = authenticator.assert(&options);
+    db.finish_webauthn_assertion(assertion.clone(), Default::default())
+        .unwrap();
+    let result = db.finish_webauthn_assertion(assertion, Default::default());
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+
+    // the sign counter has to increase
+    authenticator.sign_count = 0;
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::WebAuthnCounterRegression)));
+
+    // signatures from another key
+    authenticator.sign_count = 10;
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let mut assertion = authenticator.assert(&options);
+    let other = SoftwareAuthenticator::new();
+    let signature: p256::ecdsa::Signature =
+        p256::ecdsa::signature::Signer::sign(&other.signing_key, b"message");
+    assertion.signature = data_encoding::BASE64URL_NOPAD.encode(signature.to_der().as_bytes());
+    let result = db.finish_webauthn_assertion(assertion, Default::default());
+    assert!(matches!(result, Err(Error::WebAuthnVerificationFailed(_))));
+
+    // and other origins
+    authenticator.origin = "https://phishing.example.com".into();
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::WebAuthnVerificationFailed(_))));
+
+    // another user's challenge
+    create_test_user(&mut db, "other");
+    let options = db.start_webauthn_assertion(Some("other")).unwrap();
+    authenticator.origin = "http://localhost".into();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_webauthn_failures_consume_challenge() {
+    let mut db = setup_test_db().with_login_throttle(LoginThrottle {
+        max_failures_per_user: 2,
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let options = db.start_webauthn_registration(user_id).unwrap();
+    db.finish_webauthn_registration(user_id, authenticator.register(&options))
+        .unwrap();
+
+    // a rejected registration uses up its challenge
+    let options = db.start_webauthn_registration(user_id).unwrap();
+    let registration = authenticator.register(&options);
+    let result = db.finish_webauthn_registration(user_id, registration.clone());
+    assert!(matches!(result, Err(Error::WebAuthnVerificationFailed(_))));
+    let result = db.finish_webauthn_registration(user_id, registration);
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    db.finish_webauthn_assertion(authenticator.assert(&options), Default::default())
+        .unwrap();
+
+    // so does a counter regression, which counts as a failed login
+    authenticator.sign_count = 0;
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let assertion = authenticator.assert(&options);
+    let result = db.finish_webauthn_assertion(assertion.clone(), Default::default());
+    assert!(matches!(result, Err(Error::WebAuthnCounterRegression)));
+    let result = db.finish_webauthn_assertion(assertion, Default::default());
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+
+    authenticator.sign_count = 0;
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::WebAuthnCounterRegression)));
+
+    authenticator.sign_count = 10;
+    let options = db.start_webauthn_assertion(Some("test")).unwrap();
+    let result = db.finish_webauthn_assertion(authenticator.assert(&options), Default::default());
+    assert!(matches!(result, Err(Error::TooManyAttempts { .. })));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_totp_challenge_expired() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        challenge_lifetime: Duration::seconds(-1),
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+
+    let enrollment = db.enroll_totp(user_id).unwrap();
+    db.confirm_totp_at(
+        user_id,
+        &totp_test_code(&enrollment.secret, -1),
+        totp_test_time(),
+    )
+    .unwrap();
+
+    let challenge = match db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+    {
+        CreateSession::SecondFactorRequired(challenge) => challenge,
+        CreateSession::Session(_) => panic!("second factor not required"),
+    };
+
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::ChallengeExpired)));
+
+    db.delete_expired_login_challenges().unwrap();
+    let result = db.complete_totp_challenge_at(
+        &challenge.challenge_token,
+        &totp_test_code(&enrollment.secret, 0),
+        totp_test_time(),
+    );
+    assert!(matches!(result, Err(Error::ChallengeNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_idle_timeout() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        idle_timeout: Some(Duration::hours(1)),
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let token = &session.session_token;
+
+    set_session_date(
+        &db,
+        token,
+        "last_used_at",
+        Utc::now() - Duration::minutes(30),
+    );
+    let verify = db.verify_session(token).unwrap();
+    assert!(matches!(verify, VerifySession::Session(_)));
+
+    // verifying counts as a use
+    set_session_date(
+        &db,
+        token,
+        "last_used_at",
+        Utc::now() - Duration::minutes(90),
+    );
+    // idle sessions are not listed, even before they are deleted
+    assert!(db.list_sessions(user_id, None).unwrap().is_empty());
+    assert_eq!(
+        db.verify_session(token).unwrap(),
+        VerifySession::SessionIdleTimeout
+    );
+
+    db.delete_expired_sessions().unwrap();
+    assert_eq!(
+        db.verify_session(token).unwrap(),
+        VerifySession::SessionNotFound
+    );
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_max_lifetime() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        max_lifetime: Duration::days(1),
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let lifetime = session.expiry_date - Utc::now();
+    assert!(lifetime <= Duration::days(1) && lifetime > Duration::hours(23));
+
+    set_session_date(
+        &db,
+        &session.session_token,
+        "expiry_date",
+        Utc::now() - Duration::seconds(1),
+    );
+    assert_eq!(
+        db.verify_session(&session.session_token).unwrap(),
+        VerifySession::SessionExpired
+    );
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_sliding_renewal() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        max_lifetime: Duration::days(1),
+        idle_timeout: None,
+        sliding_renewal: Some(Duration::hours(1)),
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let token = &session.session_token;
+    assert!(session.expiry_date - Utc::now() <= Duration::hours(1));
+
+    set_session_date(&db, token, "expiry_date", Utc::now() + Duration::minutes(5));
+    let renewed = db.verify_session(token).unwrap().unwrap_session();
+    assert!(renewed.expiry_date - Utc::now() > Duration::minutes(55));
+
+    // renewal never extends past the absolute lifetime
+    let created_at = Utc::now() - Duration::hours(23) - Duration::minutes(50);
+    set_session_date(&db, token, "created_at", created_at);
+    set_session_date(&db, token, "expiry_date", Utc::now() + Duration::minutes(5));
+    let renewed = db.verify_session(token).unwrap().unwrap_session();
+    assert_eq!(renewed.expiry_date, created_at + Duration::days(1));
+
+    set_session_date(&db, token, "created_at", Utc::now() - Duration::days(2));
+    assert_eq!(
+        db.verify_session(token).unwrap(),
+        VerifySession::SessionExpired
+    );
+}
+
+fn refresh_test_db() -> Database {
+    setup_test_db().with_session_policy(SessionPolicy {
+        max_lifetime: Duration::minutes(15),
+        refresh_token_lifetime: Some(Duration::days(30)),
+        ..Default::default()
+    })
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_refresh_session_rotates_tokens() {
+    let mut db = refresh_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", track_from_ip("10.0.0.1"))
+        .unwrap()
+        .unwrap_session();
+    let refresh_token = session.refresh_token.clone().unwrap();
+
+    let refreshed = db.refresh_session(&refresh_token).unwrap();
+    let new_refresh_token = refreshed.refresh_token.clone().unwrap();
+    assert_ne!(refreshed.session_token, session.session_token);
+    assert_ne!(new_refresh_token, refresh_token);
+    assert_eq!(refreshed.user.username, "test");
+    assert_eq!(refreshed.track.ip_address.as_deref(), Some("10.0.0.1"));
+
+    // the old session is revoked, the new one works
+    assert_eq!(
+        db.verify_session(&session.session_token).unwrap(),
+        VerifySession::SessionNotFound
+    );
+    db.verify_session(&refreshed.session_token)
+        .unwrap()
+        .unwrap_session();
+
+    let refreshed_again = db.refresh_session(&new_refresh_token).unwrap();
+    db.verify_session(&refreshed_again.session_token)
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_refresh_token_reuse_revokes_family() {
+    let mut db = refresh_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let other = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let refresh_token = session.refresh_token.unwrap();
+
+    let refreshed = db.refresh_session(&refresh_token).unwrap();
+    let result = db.refresh_session(&refresh_token);
+    assert!(matches!(result, Err(Error::RefreshTokenReused)));
+
+    // everything issued from the stolen token is revoked
+    assert_eq!(
+        db.verify_session(&refreshed.session_token).unwrap(),
+        VerifySession::SessionNotFound
+    );
+    let result = db.refresh_session(&refreshed.refresh_token.unwrap());
+    assert!(matches!(result, Err(Error::RefreshTokenNotFound)));
+
+    // other logins are not affected
+    db.verify_session(&other.session_token)
+        .unwrap()
+        .unwrap_session();
+    db.refresh_session(&other.refresh_token.unwrap()).unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_refresh_token_expired() {
+    let mut db = refresh_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let refresh_token = session.refresh_token.unwrap();
+
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::update("refresh_tokens")
+            .set("expiry_date", param(1))
+            .condition(query::eq(query::column("token_hash"), param(2)))
+            .into_query();
+        query
+            .update(
+                &tx,
+                params![
+                    Utc::now() - Duration::seconds(1),
+                    hash_token(&refresh_token)
+                ],
+            )
+            .unwrap();
+        tx.commit().unwrap();
+    }
+
+    let result = db.refresh_session(&refresh_token);
+    assert!(matches!(result, Err(Error::RefreshTokenExpired)));
+
+    db.delete_expired_refresh_tokens().unwrap();
+    let result = db.refresh_session(&refresh_token);
+    assert!(matches!(result, Err(Error::RefreshTokenNotFound)));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_refresh_tokens_disabled_by_default() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.refresh_token, None);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_logout_revokes_refresh_token() {
+    let mut db = refresh_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let refreshed = db.refresh_session(&session.refresh_token.unwrap()).unwrap();
+    db.delete_session(&refreshed.session_token).unwrap();
+    let result = db.refresh_session(&refreshed.refresh_token.unwrap());
+    assert!(matches!(result, Err(Error::RefreshTokenNotFound)));
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let kept = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    db.revoke_all_sessions(user_id, Some(&kept.session_token))
+        .unwrap();
+    let result = db.refresh_session(&session.refresh_token.unwrap());
+    assert!(matches!(result, Err(Error::RefreshTokenNotFound)));
+    db.refresh_session(&kept.refresh_token.unwrap()).unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_refresh_tokens_do_not_reach_other_sessions() {
+    let mut db = refresh_test_db();
+    let alice_id = create_test_user(&mut db, "alice");
+    let bob_id = create_test_user(&mut db, "bob");
+
+    let alice = db
+        .create_session("alice", "password123", track_from_ip("10.0.0.1"))
+        .unwrap()
+        .unwrap_session();
+    let alice_session_id = db.list_sessions(alice_id, None).unwrap()[0].id;
+    set_session_date(
+        &db,
+        &alice.session_token,
+        "expiry_date",
+        Utc::now() - Duration::seconds(1),
+    );
+    db.delete_expired_sessions().unwrap();
+
+    // the id of the deleted session is not given out again
+    db.create_session("bob", "password123", track_from_ip("10.0.0.2"))
+        .unwrap()
+        .unwrap_session();
+    let bob_session_id = db.list_sessions(bob_id, None).unwrap()[0].id;
+    assert!(bob_session_id > alice_session_id);
+
+    // refresh tokens outlive their session
+    let refreshed = db.refresh_session(&alice.refresh_token.unwrap()).unwrap();
+    assert_eq!(refreshed.user.username, "alice");
+    assert_eq!(refreshed.track.ip_address, None);
+
+    // a refresh token pointing at another user's session leaves it alone
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::update("refresh_tokens")
+            .set("session_id", param(1))
+            .condition(query::eq(query::column("user_id"), param(2)))
+            .into_query();
+        query
+            .update(&tx, params![bob_session_id, alice_id])
+            .unwrap();
+        tx.commit().unwrap();
+    }
+    db.revoke_session_by_id(bob_id, bob_session_id).unwrap();
+    db.refresh_session(&refreshed.refresh_token.unwrap())
+        .unwrap();
+
+    let bob = db
+        .create_session("bob", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let bob_session_id = db.list_sessions(bob_id, None).unwrap()[0].id;
+    let alice = db
+        .create_session("alice", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::update("refresh_tokens")
+            .set("session_id", param(1))
+            .condition(query::eq(query::column("token_hash"), param(2)))
+            .into_query();
+        query
+            .update(
+                &tx,
+                params![
+                    bob_session_id,
+                    hash_token(alice.refresh_token.as_ref().unwrap())
+                ],
+            )
+            .unwrap();
+        tx.commit().unwrap();
+    }
+    let refreshed = db.refresh_session(&alice.refresh_token.unwrap()).unwrap();
+    assert_eq!(refreshed.user.username, "alice");
+    db.verify_session(&bob.session_token)
+        .unwrap()
+        .unwrap_session();
+}
+
+fn signed_test_db() -> Database {
+    setup_test_db().with_session_policy(SessionPolicy {
+        signed_token_lifetime: Some(Duration::minutes(5)),
+        signing_key_encryption_key: Some(SigningKeyEncryptionKey::new([7; 32])),
+        ..Default::default()
+    })
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signing_keys_encrypted_at_rest() {
+    let mut db = signed_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let signed_token = session.signed_token.unwrap();
+
+    {
+        struct InnerSigningKey {
+            private_key: Vec<u8>,
+        }
+
+        impl kodama_api::FromRow for InnerSigningKey {
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    private_key: row.get("private_key")?,
+                })
+            }
+        }
+
+        let tx = db.database.transaction().unwrap();
+        let query = Query::select_from("signing_keys")
+            .all_columns()
+            .into_query();
+        let keys = query
+            .select_many::<InnerSigningKey>(&tx, params![])
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+        // nonce, encrypted scalar and authentication tag
+        assert_eq!(keys[0].private_key.len(), 12 + 32 + 16);
+    }
+
+    let key = SigningKeyEncryptionKey::generate();
+    assert_eq!(
+        SigningKeyEncryptionKey::from_hex(&key.to_hex()).unwrap(),
+        key
+    );
+    assert!(matches!(
+        SigningKeyEncryptionKey::from_hex("abcd"),
+        Err(Error::InvalidSigningKeyEncryptionKey)
+    ));
+
+    let mut db = db.with_session_policy(SessionPolicy {
+        signed_token_lifetime: Some(Duration::minutes(5)),
+        signing_key_encryption_key: Some(key),
+        ..Default::default()
+    });
+    assert!(matches!(
+        db.signing_key_set(),
+        Err(Error::InvalidSigningKey)
+    ));
+
+    let mut db = db.with_session_policy(SessionPolicy {
+        signed_token_lifetime: Some(Duration::minutes(5)),
+        ..Default::default()
+    });
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(result, Err(Error::SigningKeyEncryptionKeyMissing)));
+
+    let mut db = db.with_session_policy(SessionPolicy {
+        signed_token_lifetime: Some(Duration::minutes(5)),
+        signing_key_encryption_key: Some(SigningKeyEncryptionKey::new([7; 32])),
+        ..Default::default()
+    });
+    db.verify_signed_token(&signed_token).unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signed_token_verified_offline() {
+    let mut db = signed_test_db();
+    let user_id = create_test_user(&mut db, "test");
+    db.add_permission(user_id, "site", "admin", None).unwrap();
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let signed_token = session.signed_token.unwrap();
+
+    // the key set round trips through JSON like a published JWKS document
+    let key_set = serde_json::to_string(&db.signing_key_set().unwrap()).unwrap();
+    let key_set: JsonWebKeySet = serde_json::from_str(&key_set).unwrap();
+    let verifier = SignedTokenVerifier::new(&key_set).unwrap();
+
+    let claims = verifier.verify(&signed_token).unwrap();
+    assert_eq!(claims.user_id(), Some(user_id));
+    assert_eq!(claims.username, "test");
+    assert_eq!(claims.permissions, vec![permission("site", "admin")]);
+    assert!(claims.exp <= session.expiry_date.timestamp());
+    assert!(claims.has_permission(&admin_policy(), "site", "read"));
+    assert!(!claims.has_permission(&admin_policy(), "other", "read"));
+
+    // tampering with the claims breaks the signature
+    let (header, rest) = signed_token.split_once('.').unwrap();
+    let (_, signature) = rest.split_once('.').unwrap();
+    let forged_claims = SignedTokenClaims {
+        permissions: vec![permission("*", "admin")],
+        ..claims
+    };
+    let forged = format!(
+        "{}.{}.{}",
+        header,
+        data_encoding::BASE64URL_NOPAD.encode(&serde_json::to_vec(&forged_claims).unwrap()),
+        signature
+    );
+    assert!(matches!(
+        verifier.verify(&forged),
+        Err(Error::SignedTokenInvalid)
+    ));
+    assert!(matches!(
+        verifier.verify("not a token"),
+        Err(Error::SignedTokenInvalid)
+    ));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signing_key_rotation() {
+    let mut db = signed_test_db();
+    create_test_user(&mut db, "test");
+
+    let old_token = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session()
+        .signed_token
+        .unwrap();
+    let kid = db.rotate_signing_key().unwrap();
+    let new_token = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session()
+        .signed_token
+        .unwrap();
+
+    let key_set = db.signing_key_set().unwrap();
+    assert_eq!(key_set.keys.len(), 2);
+    assert!(key_set.keys.iter().any(|key| key.kid == kid));
+    db.verify_signed_token(&old_token).unwrap();
+    db.verify_signed_token(&new_token).unwrap();
+
+    // retired keys are kept until their tokens have expired
+    db.delete_retired_signing_keys().unwrap();
+    db.verify_signed_token(&old_token).unwrap();
+
+    {
+        let tx = db.database.transaction().unwrap();
+        let query = Query::update("signing_keys")
+            .set("retired_at", param(1))
+            .condition(query::lt(query::column("retired_at"), param(2)))
+            .into_query();
+        let retired_at = Utc::now() - Duration::hours(1);
+        query
+            .update(&tx, params![retired_at, Utc::now() + Duration::hours(1)])
+            .unwrap();
+        tx.commit().unwrap();
+    }
+    db.delete_retired_signing_keys().unwrap();
+    assert_eq!(db.signing_key_set().unwrap().keys.len(), 1);
+    assert!(matches!(
+        db.verify_signed_token(&old_token),
+        Err(Error::SignedTokenInvalid)
+    ));
+    db.verify_signed_token(&new_token).unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signed_token_revocation() {
+    let mut db = signed_test_db();
+    let user_id = create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let signed_token = session.signed_token.clone().unwrap();
+    let stale_verifier = db.signed_token_verifier().unwrap();
+    let jti = stale_verifier.verify(&signed_token).unwrap().jti;
+
+    db.delete_session(&session.session_token).unwrap();
+    assert_eq!(db.revoked_signed_tokens().unwrap(), vec![jti]);
+    assert!(matches!(
+        db.verify_signed_token(&signed_token),
+        Err(Error::SignedTokenRevoked)
+    ));
+    // verifiers only learn about revocations from a fresh list
+    stale_verifier.verify(&signed_token).unwrap();
+
+    // logging in again does not bring back the revoked token
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    db.verify_signed_token(session.signed_token.as_ref().unwrap())
+        .unwrap();
+    assert!(matches!(
+        db.verify_signed_token(&signed_token),
+        Err(Error::SignedTokenRevoked)
+    ));
+
+    // disabled users lose their signed tokens until new ones are issued
+    db.disable_user(user_id, None, None).unwrap();
+    assert!(matches!(
+        db.verify_signed_token(session.signed_token.as_ref().unwrap()),
+        Err(Error::SignedTokenRevoked)
+    ));
+    assert!(matches!(
+        db.issue_signed_token(&session.session_token),
+        Err(Error::UserDisabled { .. })
+    ));
+
+    db.enable_user(user_id).unwrap();
+    let signed_token = db.issue_signed_token(&session.session_token).unwrap();
+    db.verify_signed_token(&signed_token).unwrap();
+    assert!(matches!(
+        db.verify_signed_token(session.signed_token.as_ref().unwrap()),
+        Err(Error::SignedTokenRevoked)
+    ));
+
+    // the revocations outlive the user
+    db.delete_user_by_username("test").unwrap();
+    assert!(matches!(
+        db.verify_signed_token(&signed_token),
+        Err(Error::SignedTokenRevoked)
+    ));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signed_token_revoked_with_expired_session() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        idle_timeout: Some(Duration::hours(1)),
+        signed_token_lifetime: Some(Duration::minutes(5)),
+        signing_key_encryption_key: Some(SigningKeyEncryptionKey::new([7; 32])),
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let idle = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let expired = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let active = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    set_session_date(
+        &db,
+        &idle.session_token,
+        "last_used_at",
+        Utc::now() - Duration::minutes(90),
+    );
+    set_session_date(
+        &db,
+        &expired.session_token,
+        "expiry_date",
+        Utc::now() - Duration::seconds(1),
+    );
+
+    db.delete_expired_sessions().unwrap();
+    for session in [&idle, &expired] {
+        assert!(matches!(
+            db.verify_signed_token(session.signed_token.as_ref().unwrap()),
+            Err(Error::SignedTokenRevoked)
+        ));
+    }
+    db.verify_signed_token(active.signed_token.as_ref().unwrap())
+        .unwrap();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signed_token_expired() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        signed_token_lifetime: Some(Duration::seconds(-1)),
+        signing_key_encryption_key: Some(SigningKeyEncryptionKey::new([7; 32])),
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert!(matches!(
+        db.verify_signed_token(&session.signed_token.unwrap()),
+        Err(Error::SignedTokenExpired)
+    ));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_signed_tokens_disabled_by_default() {
+    let mut db = setup_test_db();
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    assert_eq!(session.signed_token, None);
+    assert!(matches!(
+        db.issue_signed_token(&session.session_token),
+        Err(Error::SignedTokensDisabled)
+    ));
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_limit_evicts_least_recently_used() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        max_sessions: Some(2),
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+
+    let create = |db: &mut Database| {
+        db.create_session("test", "password123", Default::default())
+            .unwrap()
+            .unwrap_session()
+            .session_token
+    };
+    let first = create(&mut db);
+    let second = create(&mut db);
+    set_session_date(&db, &first, "last_used_at", Utc::now());
+    set_session_date(
+        &db,
+        &second,
+        "last_used_at",
+        Utc::now() - Duration::hours(1),
+    );
+
+    let third = create(&mut db);
+    assert_eq!(
+        db.verify_session(&second).unwrap(),
+        VerifySession::SessionNotFound
+    );
+    db.verify_session(&first).unwrap().unwrap_session();
+    db.verify_session(&third).unwrap().unwrap_session();
+
+    // expired sessions do not count towards the limit
+    set_session_date(
+        &db,
+        &first,
+        "expiry_date",
+        Utc::now() - Duration::seconds(1),
+    );
+    create(&mut db);
+    db.verify_session(&third).unwrap().unwrap_session();
+    assert_eq!(db.list_sessions(user_id, None).unwrap().len(), 2);
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_limit_refuse() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        max_sessions: Some(1),
+        session_limit_action: SessionLimitAction::Refuse,
+        ..Default::default()
+    });
+    create_test_user(&mut db, "test");
+
+    let session = db
+        .create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(
+        result,
+        Err(Error::SessionLimitReached { limit: 1 })
+    ));
+
+    // signing out frees the slot
+    db.delete_session(&session.session_token).unwrap();
+    db.create_session("test", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+}
+
+#[test]
+#[tracing_test::traced_test]
+fn test_session_limit_override() {
+    let mut db = setup_test_db().with_session_policy(SessionPolicy {
+        max_sessions: Some(1),
+        session_limit_action: SessionLimitAction::Refuse,
+        ..Default::default()
+    });
+    let user_id = create_test_user(&mut db, "test");
+    create_test_user(&mut db, "other");
+
+    db.set_session_limit(user_id, Some(3)).unwrap();
+    assert_eq!(db.session_limit(user_id).unwrap(), Some(3));
+    for _ in 0..3 {
+        db.create_session("test", "password123", Default::default())
+            .unwrap()
+            .unwrap_session();
+    }
+    let result = db.create_session("test", "password123", Default::default());
+    assert!(matches!(
+        result,
+        Err(Error::SessionLimitReached { limit: 3 })
+    ));
+
+    // other users keep the policy's limit
+    db.create_session("other", "password123", Default::default())
+        .unwrap()
+        .unwrap_session();
+    let result = db.create_session("other", "password123", Default::default());
+    assert!(matches!(
+        result,
+        Err(Error::SessionLimitReached { limit: 1 })
+    ));
+
+    db.set_session_limit(user_id, None).unwrap();
+    assert_eq!(db.session_limit(user_id).unwrap(), Some(1));
+
+    let result = db.set_session_limit(-1, Some(1));
+    assert!(matches!(result, Err(Error::UserNotFound)));
+}
+
+#[cfg(feature = "axum")]
+mod axum_tests {
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{EnigmaState, ForwardAuth, RequirePermission, Session, User};
+
+    fn setup_state() -> (EnigmaState, String) {
+        let mut db = setup_test_db();
+        let user_id = create_test_user(&mut db, "test");
+        db.add_permission(user_id, "example.com", "read", None)
+            .unwrap();
+        let session = db
+            .create_session("test", "password123", Default::default())
+            .unwrap()
+            .unwrap_session();
+        (EnigmaState::new(db), session.session_token)
+    }
+
+    async fn status(router: Router, request: Request<Body>) -> StatusCode {
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_session_extractor() {
+        let (state, session_token) = setup_state();
+        let router = Router::new()
+            .route(
+                "/",
+                get(|session: Session| async move { session.user.username }),
+            )
+            .with_state(state);
+
+        let request = Request::get("/")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session_token))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(router.clone(), request).await, StatusCode::OK);
+
+        let request = Request::get("/")
+            .header(
+                header::COOKIE,
+                format!("theme=dark; enigma_session={}", session_token),
+            )
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(router.clone(), request).await, StatusCode::OK);
+
+        let request = Request::get("/")
+            .header(header::AUTHORIZATION, "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            status(router.clone(), request).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(status(router, request).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_require_permission() {
+        let (state, session_token) = setup_state();
+        let router = Router::new()
+            .route("/read", get(|user: User| async move { user.username }))
+            .route_layer(RequirePermission::new(state.clone(), "example.com", "read"))
+            .route(
+                "/write",
+                get(|| async { "written" }).route_layer(RequirePermission::new(
+                    state.clone(),
+                    "example.com",
+                    "write",
+                )),
+            )
+            .with_state(state);
+
+        let request = Request::get("/read")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session_token))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(router.clone(), request).await, StatusCode::OK);
+
+        let request = Request::get("/write")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session_token))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(router.clone(), request).await, StatusCode::FORBIDDEN);
+
+        let request = Request::get("/read").body(Body::empty()).unwrap();
+        assert_eq!(status(router, request).await, StatusCode::UNAUTHORIZED);
+    }
+
+    fn forward_auth_request(host: &str, session_token: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/auth")
+            .header("x-forwarded-proto", "https")
+            .header("x-forwarded-host", host)
+            .header("x-forwarded-uri", "/some/page?x=1");
+        if let Some(session_token) = session_token {
+            request = request.header(header::COOKIE, format!("enigma_session={}", session_token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth() {
+        let (state, session_token) = setup_state();
+        let forward_auth = ForwardAuth {
+            sites: [("wiki.internal".to_string(), "example.com".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let router = Router::new()
+            .route("/auth", forward_auth.route(state.clone()))
+            .with_state(state);
+
+        let request = forward_auth_request("wiki.internal:8443", Some(&session_token));
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-enigma-user"], "test");
+        assert_eq!(response.headers()["x-enigma-permissions"], "read");
+
+        let request = forward_auth_request("example.com", Some(&session_token));
+        assert_eq!(status(router.clone(), request).await, StatusCode::OK);
+
+        let request = forward_auth_request("other.internal", Some(&session_token));
+        assert_eq!(status(router.clone(), request).await, StatusCode::FORBIDDEN);
+
+        let request = forward_auth_request("wiki.internal", None);
+        assert_eq!(status(router, request).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_wildcard_site() {
+        let mut db = setup_test_db().with_permission_policy(PermissionPolicy::empty());
+        let user_id = create_test_user(&mut db, "test");
+        db.add_permission(user_id, "*.internal", "read", None)
+            .unwrap();
+        db.add_permission(user_id, "git.internal", "admin", None)
+            .unwrap();
+        let session = db
+            .create_session("test", "password123", Default::default())
+            .unwrap()
+            .unwrap_session();
+        let state = EnigmaState::new(db);
+        let router = Router::new()
+            .route("/auth", ForwardAuth::default().route(state.clone()))
+            .with_state(state);
+
+        let request = forward_auth_request("git.internal", Some(&session.session_token));
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-enigma-permissions"], "read,admin");
+
+        let request = forward_auth_request("wiki.internal", Some(&session.session_token));
+        assert_eq!(status(router.clone(), request).await, StatusCode::OK);
+
+        let request = forward_auth_request("internal", Some(&session.session_token));
+        assert_eq!(status(router, request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_login_redirect() {
+        let (state, _) = setup_state();
+        let forward_auth = ForwardAuth {
+            login_url: Some("https://login.internal/".into()),
+            ..Default::default()
+        };
+        let router = Router::new()
+            .route("/auth", forward_auth.route(state.clone()))
+            .with_state(state);
+
+        let request = forward_auth_request("example.com", None);
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://login.internal/?rd=https%3A%2F%2Fexample.com%2Fsome%2Fpage%3Fx%3D1"
+        );
+    }
+}
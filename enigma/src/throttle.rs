@@ -0,0 +1,225 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rusqlite::params;
+
+use crate::Error;
+use crate::Result;
+
+use super::Database;
+
+/// Limits on failed logins before `create_session` starts refusing attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginThrottle {
+    /// Failed attempts allowed for a single user within `window`. Only
+    /// failures after the user's most recent successful login are counted.
+    pub max_failures_per_user: u32,
+    /// Failed attempts allowed from a single IP address within `window`.
+    pub max_failures_per_ip: u32,
+    pub window: Duration,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self {
+            max_failures_per_user: 5,
+            max_failures_per_ip: 20,
+            window: Duration::minutes(15),
+        }
+    }
+}
+
+struct InnerAttempt {
+    attempt_date: DateTime<Utc>,
+    success: bool,
+}
+
+impl FromRow for InnerAttempt {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            attempt_date: row.get("attempt_date")?,
+            success: row.get("success")?,
+        })
+    }
+}
+
+/// Returns when the oldest failure that keeps `failures` at or above `max`
+/// leaves the window, or `None` if the limit has not been reached.
+fn retry_after(
+    mut failures: Vec<DateTime<Utc>>,
+    max: u32,
+    window: Duration,
+) -> Option<DateTime<Utc>> {
+    let max = max as usize;
+    if failures.len() < max {
+        return None;
+    }
+
+    failures.sort();
+    Some(failures[failures.len() - max] + window)
+}
+
+impl Database {
+    pub fn with_login_throttle(mut self, login_throttle: LoginThrottle) -> Self {
+        self.login_throttle = login_throttle;
+        self
+    }
+
+    pub(crate) fn tx_record_login_attempt(
+        tx: &Transaction<'_>,
+        user_id: Option<i64>,
+        ip_address: Option<&str>,
+        success: bool,
+    ) -> Result<()> {
+        tracing::trace!("[database] tx_record_login_attempt:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  ip_address: {:?}", ip_address);
+        tracing::trace!("  success: {:?}", success);
+
+        let query = Query::insert_into("login_attempts")
+            .column("user_id", param(1))
+            .column("attempt_date", param(2))
+            .column("attempt_ip_address", param(3))
+            .column("success", param(4))
+            .into_query();
+
+        query.insert(tx, params![user_id, Utc::now(), ip_address, success])?;
+        Ok(())
+    }
+
+    pub(crate) fn tx_check_login_throttle(
+        tx: &Transaction<'_>,
+        throttle: &LoginThrottle,
+        user_id: Option<i64>,
+        ip_address: Option<&str>,
+    ) -> Result<()> {
+        tracing::trace!("[database] tx_check_login_throttle:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  ip_address: {:?}", ip_address);
+
+        let now = Utc::now();
+        let since = now - throttle.window;
+        let mut retry = None;
+
+        if let Some(user_id) = user_id {
+            let query = Query::select_from("login_attempts")
+                .column("attempt_date")
+                .column("success")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .condition(query::lt(param(2), query::column("attempt_date")))
+                .into_query();
+
+            let attempts = query.select_many::<InnerAttempt>(tx, params![user_id, since])?;
+            let last_success = attempts
+                .iter()
+                .filter(|a| a.success)
+                .map(|a| a.attempt_date)
+                .max();
+            let failures = attempts
+                .into_iter()
+                .filter(|a| !a.success)
+                .filter(|a| last_success.is_none_or(|s| a.attempt_date > s))
+                .map(|a| a.attempt_date)
+                .collect();
+
+            retry = retry_after(failures, throttle.max_failures_per_user, throttle.window);
+        }
+
+        if let Some(ip_address) = ip_address {
+            let query = Query::select_from("login_attempts")
+                .column("attempt_date")
+                .column("success")
+                .condition(query::eq(query::column("attempt_ip_address"), param(1)))
+                .condition(query::eq(query::column("success"), param(2)))
+                .condition(query::lt(param(3), query::column("attempt_date")))
+                .into_query();
+
+            let failures = query
+                .select_many::<InnerAttempt>(tx, params![ip_address, false, since])?
+                .into_iter()
+                .map(|a| a.attempt_date)
+                .collect();
+
+            let ip_retry = retry_after(failures, throttle.max_failures_per_ip, throttle.window);
+            retry = retry.max(ip_retry);
+        }
+
+        match retry {
+            Some(retry_after) if retry_after > now => {
+                tracing::warn!(
+                    "login throttled (user_id: {:?}, ip_address: {:?}) until {}",
+                    user_id,
+                    ip_address,
+                    retry_after
+                );
+                Err(Error::TooManyAttempts { retry_after })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Clears the failed login attempts recorded for a user, lifting any
+    /// lockout on the account.
+    pub fn clear_login_attempts(&mut self, user_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] clear_login_attempts:");
+            tracing::trace!("  user_id: {:?}", user_id);
+
+            let query = Query::delete_from("login_attempts")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .condition(query::eq(query::column("success"), param(2)))
+                .into_query();
+
+            query.delete(&tx, params![user_id, false])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Clears the failed login attempts recorded for an IP address, lifting
+    /// any lockout on it.
+    pub fn clear_login_attempts_by_ip(&mut self, ip_address: &str) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] clear_login_attempts_by_ip:");
+            tracing::trace!("  ip_address: {:?}", ip_address);
+
+            let query = Query::delete_from("login_attempts")
+                .condition(query::eq(query::column("attempt_ip_address"), param(1)))
+                .condition(query::eq(query::column("success"), param(2)))
+                .into_query();
+
+            query.delete(&tx, params![ip_address, false])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_expired_login_attempts(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_login_attempts");
+            let query = Query::delete_from("login_attempts")
+                .condition(query::lt(query::column("attempt_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now() - self.login_throttle.window])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Generates a random 256-bit bearer token, hex encoded.
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Digest of a bearer token as stored in the database. Tokens carry 256 bits
+/// of entropy, so a plain SHA-256 is enough to make the stored value useless
+/// to anyone reading the database.
+pub(crate) fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+pub(crate) fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
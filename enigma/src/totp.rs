@@ -0,0 +1,324 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use rand_core::{OsRng, RngCore};
+use rusqlite::params;
+use sha1::Sha1;
+
+use crate::token::url_encode;
+use crate::Error;
+use crate::Result;
+use crate::Session;
+
+use super::Database;
+
+/// Parameters of the TOTP (RFC 6238) codes accepted as a second factor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpPolicy {
+    /// Shown next to the account name in authenticator apps.
+    pub issuer: String,
+    pub digits: u32,
+    pub step: Duration,
+    /// Number of steps before and after the current one whose codes are
+    /// still accepted, to allow for clock drift.
+    pub skew: i64,
+}
+
+impl Default for TotpPolicy {
+    fn default() -> Self {
+        Self {
+            issuer: "enigma".into(),
+            digits: 6,
+            step: Duration::seconds(30),
+            skew: 1,
+        }
+    }
+}
+
+impl TotpPolicy {
+    /// Checks that codes can be generated with these parameters: 6 to 8
+    /// digits, and a step of at least one second.
+    pub fn validate(&self) -> Result<()> {
+        if !(6..=8).contains(&self.digits) {
+            return Err(Error::InvalidTotpPolicy("digits must be between 6 and 8"));
+        }
+        if self.step.num_seconds() <= 0 {
+            return Err(Error::InvalidTotpPolicy("step must be at least one second"));
+        }
+        Ok(())
+    }
+
+    fn time_step(&self, time: DateTime<Utc>) -> i64 {
+        time.timestamp().div_euclid(self.step.num_seconds())
+    }
+
+    /// Returns the time step whose code matches, ignoring steps at or before
+    /// `last_used_step` so that a code cannot be used twice.
+    fn matching_step(
+        &self,
+        secret: &[u8],
+        code: &str,
+        now: DateTime<Utc>,
+        last_used_step: Option<i64>,
+    ) -> Option<i64> {
+        let current = self.time_step(now);
+        (current - self.skew..=current + self.skew)
+            .filter(|step| *step >= 0)
+            .filter(|step| last_used_step.is_none_or(|last| *step > last))
+            .find(|step| {
+                let expected = totp_code(secret, *step as u64, self.digits);
+                constant_time_eq(expected.as_bytes(), code.trim().as_bytes())
+            })
+    }
+}
+
+/// Returned by `enroll_totp`. The user adds the secret to an authenticator
+/// app, usually by scanning the URI as a QR code.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32 encoded secret.
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// HOTP value (RFC 4226) for `counter`, zero padded to `digits`.
+pub(crate) fn totp_code(secret: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct InnerTotpSecret {
+    secret: Vec<u8>,
+    confirmed_at: Option<DateTime<Utc>>,
+    last_used_step: Option<i64>,
+}
+
+impl FromRow for InnerTotpSecret {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            secret: row.get("secret")?,
+            confirmed_at: row.get("confirmed_at")?,
+            last_used_step: row.get("last_used_step")?,
+        })
+    }
+}
+
+impl Database {
+    /// Replaces the TOTP policy, failing if it is invalid.
+    pub fn with_totp_policy(mut self, totp_policy: TotpPolicy) -> Result<Self> {
+        totp_policy.validate()?;
+        self.totp_policy = totp_policy;
+        Ok(self)
+    }
+
+    fn tx_get_totp_secret(tx: &Transaction<'_>, user_id: i64) -> Result<Option<InnerTotpSecret>> {
+        tracing::trace!("[database] tx_get_totp_secret: {:?}", user_id);
+
+        let query = Query::select_from("totp_secrets")
+            .all_columns()
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+
+        Ok(query.select_maybe::<InnerTotpSecret>(tx, params![user_id])?)
+    }
+
+    fn tx_set_totp_last_used_step(tx: &Transaction<'_>, user_id: i64, step: i64) -> Result<()> {
+        tracing::trace!("[database] tx_set_totp_last_used_step:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  step: {:?}", step);
+
+        let query = Query::update("totp_secrets")
+            .set("last_used_step", param(1))
+            .condition(query::eq(query::column("user_id"), param(2)))
+            .into_query();
+
+        query.update(tx, params![step, user_id])?;
+        Ok(())
+    }
+
+    /// Whether the user has a confirmed TOTP enrollment.
+    pub(crate) fn tx_has_totp(tx: &Transaction<'_>, user_id: i64) -> Result<bool> {
+        let secret = Self::tx_get_totp_secret(tx, user_id)?;
+        Ok(secret.is_some_and(|secret| secret.confirmed_at.is_some()))
+    }
+
+    /// Generates a new TOTP secret for the user. It is only required at login
+    /// once confirmed with `confirm_totp`; enrolling again before that
+    /// replaces the pending secret.
+    pub fn enroll_totp(&mut self, user_id: i64) -> Result<TotpEnrollment> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] enroll_totp:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        if Self::tx_has_totp(&tx, user_id)? {
+            return Err(Error::TotpAlreadyEnrolled);
+        }
+
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+
+        {
+            let query = Query::delete_from("totp_secrets")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![user_id])?;
+
+            let query = Query::insert_into("totp_secrets")
+                .column("user_id", param(1))
+                .column("secret", param(2))
+                .column("created_at", param(3))
+                .into_query();
+            query.insert(&tx, params![user_id, &secret[..], Utc::now()])?;
+        }
+
+        tx.commit()?;
+
+        let policy = &self.totp_policy;
+        let secret = data_encoding::BASE32_NOPAD.encode(&secret);
+        let otpauth_uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            url_encode(&policy.issuer),
+            url_encode(&user.username),
+            secret,
+            url_encode(&policy.issuer),
+            policy.digits,
+            policy.step.num_seconds()
+        );
+
+        Ok(TotpEnrollment {
+            secret,
+            otpauth_uri,
+        })
+    }
+
+    /// Completes enrollment with a code from the authenticator app.
+    pub fn confirm_totp(&mut self, user_id: i64, code: &str) -> Result<()> {
+        self.confirm_totp_at(user_id, code, Utc::now())
+    }
+
+    pub(crate) fn confirm_totp_at(
+        &mut self,
+        user_id: i64,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] confirm_totp:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let secret = Self::tx_get_totp_secret(&tx, user_id)?.ok_or(Error::TotpNotEnrolled)?;
+        if secret.confirmed_at.is_some() {
+            return Err(Error::TotpAlreadyEnrolled);
+        }
+
+        let step = self
+            .totp_policy
+            .matching_step(&secret.secret, code, now, None)
+            .ok_or(Error::TotpCodeIncorrect)?;
+
+        {
+            let query = Query::update("totp_secrets")
+                .set("confirmed_at", param(1))
+                .set("last_used_step", param(2))
+                .condition(query::eq(query::column("user_id"), param(3)))
+                .into_query();
+            query.update(&tx, params![now, step, user_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes the user's TOTP secret, confirmed or not.
+    pub fn disable_totp(&mut self, user_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] disable_totp:");
+            tracing::trace!("  user_id: {:?}", user_id);
+
+            let query = Query::delete_from("totp_secrets")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .into_query();
+            query.delete(&tx, params![user_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Exchanges a challenge from `create_session` and a TOTP code for the
+    /// session. Wrong codes count as failed logins for the login throttle.
+    pub fn complete_totp_challenge(
+        &mut self,
+        challenge_token: &str,
+        code: &str,
+    ) -> Result<Session> {
+        self.complete_totp_challenge_at(challenge_token, code, Utc::now())
+    }
+
+    pub(crate) fn complete_totp_challenge_at(
+        &mut self,
+        challenge_token: &str,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Session> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] complete_totp_challenge:");
+
+        let totp_policy = &self.totp_policy;
+        Self::complete_login_challenge(
+            tx,
+            &self.login_throttle,
+            &self.session_policy,
+            challenge_token,
+            Error::TotpCodeIncorrect,
+            |tx, user_id| {
+                let secret = Self::tx_get_totp_secret(tx, user_id)?
+                    .filter(|secret| secret.confirmed_at.is_some())
+                    .ok_or(Error::TotpNotEnrolled)?;
+
+                let step =
+                    totp_policy.matching_step(&secret.secret, code, now, secret.last_used_step);
+                match step {
+                    Some(step) => {
+                        Self::tx_set_totp_last_used_step(tx, user_id, step)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            },
+        )
+    }
+}
@@ -1,3 +1,5 @@
+use chrono::DateTime;
+use chrono::Utc;
 use kodama_api::query;
 use kodama_api::query::param;
 use kodama_api::query::IntoQuery;
@@ -9,20 +11,119 @@ use pbkdf2::password_hash::SaltString;
 use rand_core::OsRng;
 use rusqlite::params;
 
+use crate::password::PasswordHashers;
+use crate::Error;
 use crate::Permission;
 use crate::Result;
-use crate::Session;
 use crate::User;
+use crate::UserStatus;
 
 use super::Database;
 
+struct InnerUser {
+    id: i64,
+    username: String,
+    email: Option<String>,
+    email_verified_at: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+    disabled_reason: Option<String>,
+    disabled_until: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerUser {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            username: row.get("username")?,
+            email: row.get("email")?,
+            email_verified_at: row.get("email_verified_at")?,
+            disabled_at: row.get("disabled_at")?,
+            disabled_reason: row.get("disabled_reason")?,
+            disabled_until: row.get("disabled_until")?,
+        })
+    }
+}
+
+impl InnerUser {
+    fn into_user(self, tx: &Transaction<'_>) -> Result<User> {
+        let disabled = self.disabled_at.is_some()
+            && self.disabled_until.is_none_or(|until| until > Utc::now());
+        let status = if disabled {
+            UserStatus::Disabled {
+                reason: self.disabled_reason,
+                until: self.disabled_until,
+            }
+        } else {
+            UserStatus::Active
+        };
+
+        Ok(User {
+            permissions: Database::tx_get_user_permissions(tx, self.id)?,
+            id: self.id,
+            username: self.username,
+            email: self.email,
+            email_verified_at: self.email_verified_at,
+            status,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
 pub struct CreateUser {
     pub username: String,
     pub password: String,
     pub email: Option<String>,
 }
 
+/// Changes to a user. Fields left as `None` are kept; `email: Some(None)`
+/// removes the email address.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct UserUpdate {
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub email: Option<Option<String>>,
+}
+
+// Distinguishes an explicit `null` from a missing field.
+fn deserialize_some<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
 impl Database {
+    fn tx_check_username_available(
+        tx: &Transaction<'_>,
+        username: &str,
+        user_id: Option<i64>,
+    ) -> Result<()> {
+        match Self::tx_get_user_by_username(tx, username) {
+            Ok(user) if Some(user.id) != user_id => Err(Error::UsernameTaken),
+            Ok(_) | Err(Error::UserNotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Email addresses are stored and looked up in lowercase, as schema 018
+    /// leaves them.
+    pub(crate) fn normalize_email(email: &str) -> String {
+        email.trim().to_ascii_lowercase()
+    }
+
+    fn tx_check_email_available(
+        tx: &Transaction<'_>,
+        email: &str,
+        user_id: Option<i64>,
+    ) -> Result<()> {
+        match Self::tx_get_user_by_email(tx, email) {
+            Ok(user) if Some(user.id) != user_id => Err(Error::EmailTaken),
+            Ok(_) | Err(Error::UserNotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
     pub(crate) fn create_user_with_hash_password(
         &mut self,
         username: &str,
@@ -33,6 +134,12 @@ impl Database {
     ) -> Result<i64> {
         let tx = self.database.transaction()?;
         let salt = password_salt.as_str();
+        let email = email.as_deref().map(Self::normalize_email);
+
+        Self::tx_check_username_available(&tx, username, None)?;
+        if let Some(email) = &email {
+            Self::tx_check_email_available(&tx, email, None)?;
+        }
 
         let result = {
             tracing::trace!("[database] create_user_with_hash_password:");
@@ -62,16 +169,196 @@ impl Database {
     pub fn create_user(&mut self, user: CreateUser) -> Result<i64> {
         let password_salt = SaltString::generate(&mut OsRng);
         let password_hash = self.hash_password(&password_salt, &user.password)?;
+        let password_method = self.password_hashers.default_method().to_string();
         self.create_user_with_hash_password(
             &user.username,
             &user.email,
             &password_hash,
             password_salt,
-            "pbkdf2-sha256",
+            &password_method,
         )
     }
 
-    pub fn add_permission(&mut self, user_id: i64, site: &str, permission: &str) -> Result<()> {
+    /// Renames a user or changes their email address, keeping their
+    /// sessions and permissions. A changed email address has to be verified
+    /// again.
+    pub fn update_user(&mut self, user_id: i64, update: UserUpdate) -> Result<User> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] update_user:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  update: {:?}", update);
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+
+        if let Some(username) = &update.username {
+            if *username != user.username {
+                Self::tx_check_username_available(&tx, username, Some(user_id))?;
+
+                let query = Query::update("users")
+                    .set("username", param(1))
+                    .condition(query::eq(query::column("id"), param(2)))
+                    .into_query();
+                query.update(&tx, params![username, user_id])?;
+            }
+        }
+
+        if let Some(email) = &update.email {
+            let email = email.as_deref().map(Self::normalize_email);
+            if email != user.email {
+                if let Some(email) = &email {
+                    Self::tx_check_email_available(&tx, email, Some(user_id))?;
+                }
+
+                let query = Query::update("users")
+                    .set("email", param(1))
+                    .set("email_verified_at", param(2))
+                    .condition(query::eq(query::column("id"), param(3)))
+                    .into_query();
+                query.update(&tx, params![email, None::<DateTime<Utc>>, user_id])?;
+
+                let query = Query::delete_from("email_verification_tokens")
+                    .condition(query::eq(query::column("user_id"), param(1)))
+                    .into_query();
+                query.delete(&tx, params![user_id])?;
+            }
+        }
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        tx.commit()?;
+        Ok(user)
+    }
+
+    /// Fails with `Error::UserDisabled` unless the user is active.
+    pub(crate) fn tx_check_user_active(tx: &Transaction<'_>, user_id: i64) -> Result<()> {
+        match Self::tx_get_user_by_id(tx, user_id)?.status {
+            UserStatus::Active => Ok(()),
+            UserStatus::Disabled { reason, until } => Err(Error::UserDisabled { reason, until }),
+        }
+    }
+
+    /// Blocks a user from signing in and from using their sessions, until
+    /// `until` if given or until `enable_user`. Sessions are kept and work
+    /// again once the user is enabled.
+    pub fn disable_user(
+        &mut self,
+        user_id: i64,
+        reason: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] disable_user:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  reason: {:?}", reason);
+        tracing::trace!("  until: {:?}", until);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+
+        let query = Query::update("users")
+            .set("disabled_at", param(1))
+            .set("disabled_reason", param(2))
+            .set("disabled_until", param(3))
+            .condition(query::eq(query::column("id"), param(4)))
+            .into_query();
+        query.update(&tx, params![Utc::now(), reason, until, user_id])?;
+
+        // signed tokens are verified offline, so they have to be revoked
+        Self::tx_revoke_user_signed_tokens(&tx, user_id)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn enable_user(&mut self, user_id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] enable_user:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+
+        let query = Query::update("users")
+            .set("disabled_at", param(1))
+            .set("disabled_reason", param(2))
+            .set("disabled_until", param(3))
+            .condition(query::eq(query::column("id"), param(4)))
+            .into_query();
+        query.update(
+            &tx,
+            params![
+                None::<DateTime<Utc>>,
+                None::<String>,
+                None::<DateTime<Utc>>,
+                user_id
+            ],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Changes the password of a user who knows the current one and revokes
+    /// all of their sessions. Wrong passwords count as failed logins.
+    pub fn change_password(
+        &mut self,
+        user_id: i64,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] change_password:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  old_password: [REDACTED]");
+        tracing::trace!("  new_password: [REDACTED]");
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        Self::tx_check_login_throttle(&tx, &self.login_throttle, Some(user_id), None)?;
+
+        let verified =
+            Self::tx_verify_password(&tx, &self.password_hashers, &user.username, old_password)?;
+        if verified != Some(user_id) {
+            Self::tx_record_login_attempt(&tx, Some(user_id), None, false)?;
+            tx.commit()?;
+            return Err(Error::PasswordIncorrect);
+        }
+
+        Self::tx_set_user_password(&tx, &self.password_hashers, user_id, new_password)?;
+        Self::tx_delete_user_sessions(&tx, user_id, None)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Sets a user's password without knowing the current one and revokes
+    /// all of their sessions.
+    pub fn set_password(&mut self, user_id: i64, new_password: &str) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] set_password:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  new_password: [REDACTED]");
+
+        Self::tx_get_user_by_id(&tx, user_id)?;
+        Self::tx_set_user_password(&tx, &self.password_hashers, user_id, new_password)?;
+        Self::tx_delete_user_sessions(&tx, user_id, None)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Grants a permission, until `expiry_date` if given. Granting an
+    /// existing permission again keeps whichever grant lasts longer, so a
+    /// permanent grant is never shortened; remove the permission first to
+    /// shorten it.
+    pub fn add_permission(
+        &mut self,
+        user_id: i64,
+        site: &str,
+        permission: &str,
+        expiry_date: Option<DateTime<Utc>>,
+    ) -> Result<()> {
         let tx = self.database.transaction()?;
 
         {
@@ -79,17 +366,47 @@ impl Database {
             tracing::trace!("  user_id: {:?}", user_id);
             tracing::trace!("  site: {:?}", site);
             tracing::trace!("  permission: {:?}", permission);
+            tracing::trace!("  expiry_date: {:?}", expiry_date);
+
+            struct InnerExpiryDate {
+                expiry_date: Option<DateTime<Utc>>,
+            }
+
+            impl FromRow for InnerExpiryDate {
+                fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                    Ok(Self {
+                        expiry_date: row.get("expiry_date")?,
+                    })
+                }
+            }
+
+            let query = Query::select_from("permissions")
+                .column("expiry_date")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .condition(query::eq(query::column("site"), param(2)))
+                .condition(query::eq(query::column("permission"), param(3)))
+                .into_query();
+            let expiry_date = match query
+                .select_maybe::<InnerExpiryDate>(&tx, params![user_id, site, permission])?
+            {
+                Some(existing) => existing.expiry_date.zip(expiry_date).map(|(a, b)| a.max(b)),
+                None => expiry_date,
+            };
+
+            let query = Query::delete_from("permissions")
+                .condition(query::eq(query::column("user_id"), param(1)))
+                .condition(query::eq(query::column("site"), param(2)))
+                .condition(query::eq(query::column("permission"), param(3)))
+                .into_query();
+            query.delete(&tx, params![user_id, site, permission])?;
 
             let query = Query::insert_into("permissions")
-                .or_ignore()
                 .column("user_id", param(1))
                 .column("site", param(2))
                 .column("permission", param(3))
+                .column("expiry_date", param(4))
                 .into_query();
-            match query.insert(&tx, params![user_id, site, permission]) {
-                Ok(_) => (),
-                Err(err) => Err(err)?,
-            }
+            query.insert(&tx, params![user_id, site, permission, expiry_date])?;
         }
 
         tx.commit()?;
@@ -118,6 +435,24 @@ impl Database {
         Ok(())
     }
 
+    pub fn delete_expired_permissions(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_permissions");
+            let query = Query::delete_from("permissions")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Effective permissions of the user: unexpired direct grants merged
+    /// with the grants of their roles.
     pub(crate) fn tx_get_user_permissions(
         tx: &Transaction<'_>,
         user_id: i64,
@@ -125,12 +460,14 @@ impl Database {
         let query = Query::select_from("permissions")
             .column("site")
             .column("permission")
+            .column("expiry_date")
             .condition(query::eq(query::column("user_id"), param(1)))
             .into_query();
 
         struct InnerPermission {
             site: String,
             permission: String,
+            expiry_date: Option<DateTime<Utc>>,
         }
 
         impl FromRow for InnerPermission {
@@ -138,18 +475,30 @@ impl Database {
                 Ok(Self {
                     site: row.get("site")?,
                     permission: row.get("permission")?,
+                    expiry_date: row.get("expiry_date")?,
                 })
             }
         }
 
+        let now = Utc::now();
         let permissions = query.select_many::<InnerPermission>(tx, params![user_id])?;
-        let permissions = permissions
+        let mut permissions = permissions
             .into_iter()
+            .filter(|p| p.expiry_date.is_none_or(|expiry_date| expiry_date > now))
             .map(|p| Permission {
                 site: p.site,
                 permission: p.permission,
             })
-            .collect();
+            .collect::<Vec<_>>();
+
+        // direct grants first, then those of the user's roles
+        for role in Self::tx_get_user_roles(tx, user_id)? {
+            for permission in role.permissions {
+                if !permissions.contains(&permission) {
+                    permissions.push(permission);
+                }
+            }
+        }
         Ok(permissions)
     }
 
@@ -198,6 +547,7 @@ impl Database {
 
     pub(crate) fn tx_verify_password(
         tx: &Transaction<'_>,
+        password_hashers: &PasswordHashers,
         username: &str,
         password: &str,
     ) -> Result<Option<i64>> {
@@ -221,7 +571,12 @@ impl Database {
         tracing::trace!("  password_salt: {:?}", password_salt);
         tracing::trace!("  password_method: {:?}", password_method);
 
-        if Self::verify_password(password, &password_hash, &password_salt, &password_method)? {
+        if password_hashers.verify_password(
+            password,
+            &password_hash,
+            &password_salt,
+            &password_method,
+        )? {
             Ok(Some(user_id))
         } else {
             Ok(None)
@@ -229,69 +584,39 @@ impl Database {
     }
 
     pub(crate) fn tx_get_user_by_id(tx: &Transaction<'_>, user_id: i64) -> Result<User> {
-        struct InnerUser {
-            id: i64,
-            username: String,
-        }
-
-        impl FromRow for InnerUser {
-            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-                Ok(Self {
-                    id: row.get("id")?,
-                    username: row.get("username")?,
-                })
-            }
-        }
-
         let query = Query::select_from("users")
             .all_columns()
             .condition(query::eq(query::column("id"), param(1)))
             .into_query();
 
-        let mut user = query
+        query
             .select_maybe::<InnerUser>(tx, params![user_id])?
-            .ok_or(crate::Error::UserNotFound)
-            .map(|u| User {
-                id: u.id,
-                username: u.username,
-                permissions: vec![],
-            })?;
-
-        user.permissions = Self::tx_get_user_permissions(tx, user.id)?;
-        Ok(user)
+            .ok_or(crate::Error::UserNotFound)?
+            .into_user(tx)
     }
 
     pub(crate) fn tx_get_user_by_username(tx: &Transaction<'_>, username: &str) -> Result<User> {
-        struct InnerUser {
-            id: i64,
-            username: String,
-        }
-
-        impl FromRow for InnerUser {
-            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-                Ok(Self {
-                    id: row.get("id")?,
-                    username: row.get("username")?,
-                })
-            }
-        }
-
         let query = Query::select_from("users")
             .all_columns()
             .condition(query::eq(query::column("username"), param(1)))
             .into_query();
 
-        let mut user = query
+        query
             .select_maybe::<InnerUser>(tx, params![username])?
-            .ok_or(crate::Error::UserNotFound)
-            .map(|u| User {
-                id: u.id,
-                username: u.username,
-                permissions: vec![],
-            })?;
-
-        user.permissions = Self::tx_get_user_permissions(tx, user.id)?;
-        Ok(user)
+            .ok_or(crate::Error::UserNotFound)?
+            .into_user(tx)
+    }
+
+    pub(crate) fn tx_get_user_by_email(tx: &Transaction<'_>, email: &str) -> Result<User> {
+        let query = Query::select_from("users")
+            .all_columns()
+            .condition(query::eq(query::column("email"), param(1)))
+            .into_query();
+
+        query
+            .select_maybe::<InnerUser>(tx, params![Self::normalize_email(email)])?
+            .ok_or(crate::Error::UserNotFound)?
+            .into_user(tx)
     }
 
     pub fn get_user_by_id(&mut self, user_id: i64) -> Result<User> {
@@ -318,37 +643,28 @@ impl Database {
         Ok(user)
     }
 
+    pub fn get_user_by_email(&self, email: &str) -> Result<User> {
+        let tx = self.database.transaction()?;
+        let user = {
+            tracing::trace!("[database] get_user_by_email:");
+            tracing::trace!("  email: {:?}", email);
+            Self::tx_get_user_by_email(&tx, email)?
+        };
+
+        tx.commit()?;
+        Ok(user)
+    }
+
     pub fn list_users(&mut self) -> Result<Vec<User>> {
         let mut users = vec![];
         let tx = self.database.transaction()?;
 
         {
-            struct InnerUser {
-                id: i64,
-                username: String,
-            }
-
-            impl FromRow for InnerUser {
-                fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-                    Ok(Self {
-                        id: row.get("id")?,
-                        username: row.get("username")?,
-                    })
-                }
-            }
-
             let query = Query::select_from("users").all_columns().into_query();
 
             let inner_users = query.select_many::<InnerUser>(&tx, params![])?;
             for inner_user in inner_users {
-                let mut user = User {
-                    id: inner_user.id,
-                    username: inner_user.username,
-                    permissions: vec![],
-                };
-
-                user.permissions = Self::tx_get_user_permissions(&tx, user.id)?;
-                users.push(user);
+                users.push(inner_user.into_user(&tx)?);
             }
         }
 
@@ -363,6 +679,13 @@ impl Database {
             tracing::trace!("[database] delete_user_by_username:");
             tracing::trace!("  username: {:?}", username);
 
+            // signed tokens are verified offline, so they have to be revoked
+            match Self::tx_get_user_by_username(&tx, username) {
+                Ok(user) => Self::tx_revoke_user_signed_tokens(&tx, user.id)?,
+                Err(Error::UserNotFound) => (),
+                Err(err) => return Err(err),
+            }
+
             let query = Query::delete_from("users")
                 .condition(query::eq("username", param(1)))
                 .into_query();
@@ -0,0 +1,786 @@
+use chrono::DateTime;
+use chrono::Utc;
+use ciborium::Value;
+use data_encoding::BASE64URL_NOPAD;
+use kodama_api::query;
+use kodama_api::query::param;
+use kodama_api::query::IntoQuery;
+use kodama_api::query::Query;
+use kodama_api::DatabaseQuery;
+use kodama_api::FromRow;
+use kodama_api::Transaction;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+
+use crate::token::hash_token;
+use crate::Error;
+use crate::Result;
+use crate::Session;
+use crate::TrackInformation;
+
+use super::Database;
+
+/// COSE algorithm identifier of ES256, the only supported algorithm.
+pub const COSE_ALG_ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+const CEREMONY_REGISTRATION: &str = "registration";
+const CEREMONY_ASSERTION: &str = "assertion";
+
+/// The WebAuthn relying party, i.e. the site users sign in to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingParty {
+    /// Domain the credentials are scoped to.
+    pub id: String,
+    pub name: String,
+    /// Origin the browser reports in the client data, e.g.
+    /// `https://login.example.com`.
+    pub origin: String,
+    /// Require the authenticator to verify the user with a PIN or biometrics,
+    /// not just their presence.
+    pub user_verification: bool,
+}
+
+impl Default for RelyingParty {
+    fn default() -> Self {
+        Self {
+            id: "localhost".into(),
+            name: "enigma".into(),
+            origin: "http://localhost".into(),
+            user_verification: true,
+        }
+    }
+}
+
+/// Options for `navigator.credentials.create()`. Binary values are base64url
+/// encoded. Attestation is not verified against a trust root, so clients
+/// should request `attestation: "none"`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WebAuthnRegistrationOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub rp_name: String,
+    pub user_handle: String,
+    pub username: String,
+    pub algorithms: Vec<i64>,
+    /// Credentials the user already registered.
+    pub exclude_credentials: Vec<String>,
+}
+
+/// Response of the authenticator to a registration, base64url encoded.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WebAuthnRegistration {
+    pub client_data_json: String,
+    pub attestation_object: String,
+    /// Name the user gave the credential.
+    pub name: Option<String>,
+}
+
+/// Options for `navigator.credentials.get()`. Binary values are base64url
+/// encoded.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WebAuthnAssertionOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    /// Empty for usernameless logins with discoverable credentials.
+    pub allow_credentials: Vec<String>,
+}
+
+/// Response of the authenticator to an assertion, base64url encoded.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WebAuthnAssertion {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WebAuthnCredential {
+    pub id: i64,
+    pub user_id: i64,
+    /// Base64url encoded credential id.
+    pub credential_id: String,
+    pub name: Option<String>,
+    pub sign_count: u32,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+fn verification_failed(reason: &str) -> Error {
+    Error::WebAuthnVerificationFailed(reason.into())
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    BASE64URL_NOPAD
+        .decode(value.trim_end_matches('=').as_bytes())
+        .map_err(|_| verification_failed("invalid base64url"))
+}
+
+fn user_handle(user_id: i64) -> String {
+    BASE64URL_NOPAD.encode(&user_id.to_be_bytes())
+}
+
+#[derive(serde::Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String,
+}
+
+fn parse_client_data(client_data_json: &[u8], ceremony_type: &str, origin: &str) -> Result<String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| verification_failed("invalid client data"))?;
+
+    if client_data.ceremony_type != ceremony_type {
+        return Err(verification_failed("unexpected client data type"));
+    }
+    if client_data.origin != origin {
+        return Err(verification_failed("unexpected origin"));
+    }
+
+    Ok(client_data.challenge)
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and public key, present in registrations.
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>> {
+    if data.len() < 37 {
+        return Err(verification_failed("authenticator data too short"));
+    }
+
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // 16 byte AAGUID, then the length-prefixed credential id and the
+        // COSE encoded public key
+        let rest = data
+            .get(37 + 16..)
+            .ok_or_else(|| verification_failed("attested credential data too short"))?;
+        if rest.len() < 2 {
+            return Err(verification_failed("attested credential data too short"));
+        }
+        let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let credential_id = rest
+            .get(2..2 + length)
+            .ok_or_else(|| verification_failed("attested credential data too short"))?;
+
+        let mut cose_key = &rest[2 + length..];
+        let cose_key: Value = ciborium::de::from_reader(&mut cose_key)
+            .map_err(|_| verification_failed("invalid credential public key"))?;
+        Some((credential_id.to_vec(), parse_cose_key(&cose_key)?))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        rp_id_hash: &data[..32],
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+fn map_get(map: &[(Value, Value)], key: impl Into<Value>) -> Option<&Value> {
+    let key = key.into();
+    map.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Converts an ES256 COSE key to an uncompressed SEC1 point.
+fn parse_cose_key(cose_key: &Value) -> Result<Vec<u8>> {
+    let map = cose_key
+        .as_map()
+        .ok_or_else(|| verification_failed("invalid credential public key"))?;
+    let integer = |key: i64| {
+        map_get(map, key)
+            .and_then(|value| value.as_integer())
+            .map(i128::from)
+    };
+    let bytes = |key: i64| map_get(map, key).and_then(|value| value.as_bytes());
+
+    // kty EC2, crv P-256
+    if integer(1) != Some(2) || integer(3) != Some(COSE_ALG_ES256 as i128) || integer(-1) != Some(1)
+    {
+        return Err(verification_failed("unsupported credential algorithm"));
+    }
+
+    let (Some(x), Some(y)) = (bytes(-2), bytes(-3)) else {
+        return Err(verification_failed("invalid credential public key"));
+    };
+
+    let mut public_key = vec![0x04];
+    public_key.extend_from_slice(x);
+    public_key.extend_from_slice(y);
+    VerifyingKey::from_sec1_bytes(&public_key)
+        .map_err(|_| verification_failed("invalid credential public key"))?;
+    Ok(public_key)
+}
+
+fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<bool> {
+    let public_key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|_| verification_failed("invalid credential public key"))?;
+    let Ok(signature) = Signature::from_der(signature) else {
+        return Ok(false);
+    };
+    Ok(public_key.verify(message, &signature).is_ok())
+}
+
+struct InnerCredential {
+    id: i64,
+    user_id: i64,
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+    sign_count: u32,
+    name: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for InnerCredential {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            credential_id: row.get("credential_id")?,
+            public_key: row.get("public_key")?,
+            sign_count: row.get("sign_count")?,
+            name: row.get("name")?,
+            created_at: row.get("created_at")?,
+            last_used_at: row.get("last_used_at")?,
+        })
+    }
+}
+
+impl From<InnerCredential> for WebAuthnCredential {
+    fn from(credential: InnerCredential) -> Self {
+        Self {
+            id: credential.id,
+            user_id: credential.user_id,
+            credential_id: BASE64URL_NOPAD.encode(&credential.credential_id),
+            name: credential.name,
+            sign_count: credential.sign_count,
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        }
+    }
+}
+
+struct InnerChallenge {
+    id: i64,
+    user_id: Option<i64>,
+    ceremony: String,
+    expiry_date: DateTime<Utc>,
+}
+
+impl FromRow for InnerChallenge {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            ceremony: row.get("ceremony")?,
+            expiry_date: row.get("expiry_date")?,
+        })
+    }
+}
+
+impl Database {
+    pub fn with_relying_party(mut self, relying_party: RelyingParty) -> Self {
+        self.relying_party = relying_party;
+        self
+    }
+
+    fn tx_get_webauthn_credentials(
+        tx: &Transaction<'_>,
+        user_id: i64,
+    ) -> Result<Vec<InnerCredential>> {
+        tracing::trace!("[database] tx_get_webauthn_credentials: {:?}", user_id);
+
+        let query = Query::select_from("webauthn_credentials")
+            .all_columns()
+            .condition(query::eq(query::column("user_id"), param(1)))
+            .into_query();
+
+        Ok(query.select_many::<InnerCredential>(tx, params![user_id])?)
+    }
+
+    fn tx_create_webauthn_challenge(
+        tx: &Transaction<'_>,
+        expiry_date: DateTime<Utc>,
+        user_id: Option<i64>,
+        ceremony: &str,
+    ) -> Result<String> {
+        tracing::trace!("[database] tx_create_webauthn_challenge:");
+        tracing::trace!("  user_id: {:?}", user_id);
+        tracing::trace!("  ceremony: {:?}", ceremony);
+
+        let mut challenge = [0u8; 32];
+        OsRng.fill_bytes(&mut challenge);
+        let challenge = BASE64URL_NOPAD.encode(&challenge);
+        let query = Query::insert_into("webauthn_challenges")
+            .column("user_id", param(1))
+            .column("challenge", param(2))
+            .column("ceremony", param(3))
+            .column("expiry_date", param(4))
+            .column("created_at", param(5))
+            .into_query();
+
+        query.insert(
+            tx,
+            params![
+                user_id,
+                hash_token(&challenge),
+                ceremony,
+                expiry_date,
+                Utc::now()
+            ],
+        )?;
+
+        Ok(challenge)
+    }
+
+    /// Consumes a challenge issued for `ceremony`, returning the user it was
+    /// issued for.
+    fn tx_take_webauthn_challenge(
+        tx: &Transaction<'_>,
+        challenge: &str,
+        ceremony: &str,
+    ) -> Result<Option<i64>> {
+        tracing::trace!("[database] tx_take_webauthn_challenge:");
+        tracing::trace!("  ceremony: {:?}", ceremony);
+
+        let query = Query::select_from("webauthn_challenges")
+            .all_columns()
+            .condition(query::eq(query::column("challenge"), param(1)))
+            .into_query();
+
+        let inner = query
+            .select_maybe::<InnerChallenge>(tx, params![hash_token(challenge)])?
+            .filter(|inner| inner.ceremony == ceremony)
+            .ok_or(Error::ChallengeNotFound)?;
+
+        let query = Query::delete_from("webauthn_challenges")
+            .condition(query::eq(query::column("id"), param(1)))
+            .into_query();
+        query.delete(tx, params![inner.id])?;
+
+        if inner.expiry_date < Utc::now() {
+            return Err(Error::ChallengeExpired);
+        }
+
+        Ok(inner.user_id)
+    }
+
+    /// Checks the parts of the authenticator data common to both ceremonies.
+    fn check_authenticator_data(&self, data: &AuthenticatorData<'_>) -> Result<()> {
+        if data.rp_id_hash != Sha256::digest(self.relying_party.id.as_bytes()).as_slice() {
+            return Err(verification_failed("unexpected relying party"));
+        }
+        if data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(verification_failed("user not present"));
+        }
+        if self.relying_party.user_verification && data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(verification_failed("user not verified"));
+        }
+        Ok(())
+    }
+
+    /// Starts registering a new credential for the user.
+    pub fn start_webauthn_registration(
+        &mut self,
+        user_id: i64,
+    ) -> Result<WebAuthnRegistrationOptions> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] start_webauthn_registration:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let user = Self::tx_get_user_by_id(&tx, user_id)?;
+        let exclude_credentials = Self::tx_get_webauthn_credentials(&tx, user_id)?
+            .into_iter()
+            .map(|credential| BASE64URL_NOPAD.encode(&credential.credential_id))
+            .collect();
+
+        let expiry_date = Utc::now() + self.session_policy.challenge_lifetime;
+        let challenge = Self::tx_create_webauthn_challenge(
+            &tx,
+            expiry_date,
+            Some(user_id),
+            CEREMONY_REGISTRATION,
+        )?;
+
+        tx.commit()?;
+        Ok(WebAuthnRegistrationOptions {
+            challenge,
+            rp_id: self.relying_party.id.clone(),
+            rp_name: self.relying_party.name.clone(),
+            user_handle: user_handle(user_id),
+            username: user.username,
+            algorithms: vec![COSE_ALG_ES256],
+            exclude_credentials,
+        })
+    }
+
+    /// Takes the registration challenge and checks the attestation. Returns
+    /// the credential id, public key and signature counter.
+    fn tx_verify_webauthn_registration(
+        &self,
+        tx: &Transaction<'_>,
+        user_id: i64,
+        challenge: &str,
+        client_data_json: &[u8],
+        registration: &WebAuthnRegistration,
+    ) -> Result<(Vec<u8>, Vec<u8>, u32)> {
+        if Self::tx_take_webauthn_challenge(tx, challenge, CEREMONY_REGISTRATION)? != Some(user_id)
+        {
+            return Err(Error::ChallengeNotFound);
+        }
+
+        let attestation_object = decode(&registration.attestation_object)?;
+        let attestation: Value = ciborium::de::from_reader(attestation_object.as_slice())
+            .map_err(|_| verification_failed("invalid attestation object"))?;
+        let attestation = attestation
+            .as_map()
+            .ok_or_else(|| verification_failed("invalid attestation object"))?;
+
+        let fmt = map_get(attestation, "fmt").and_then(|value| value.as_text());
+        let auth_data = map_get(attestation, "authData").and_then(|value| value.as_bytes());
+        let statement = map_get(attestation, "attStmt").and_then(|value| value.as_map());
+        let (Some(fmt), Some(auth_data), Some(statement)) = (fmt, auth_data, statement) else {
+            return Err(verification_failed("invalid attestation object"));
+        };
+
+        let data = parse_authenticator_data(auth_data)?;
+        self.check_authenticator_data(&data)?;
+        let Some((credential_id, public_key)) = data.attested_credential else {
+            return Err(verification_failed("missing attested credential data"));
+        };
+
+        match fmt {
+            "none" => {}
+            "packed" if map_get(statement, "x5c").is_none() => {
+                let alg = map_get(statement, "alg").and_then(|value| value.as_integer());
+                let sig = map_get(statement, "sig").and_then(|value| value.as_bytes());
+                let (Some(alg), Some(sig)) = (alg, sig) else {
+                    return Err(verification_failed("invalid attestation statement"));
+                };
+                if i128::from(alg) != COSE_ALG_ES256 as i128 {
+                    return Err(verification_failed("unsupported attestation algorithm"));
+                }
+
+                let mut message = auth_data.to_vec();
+                message.extend_from_slice(&Sha256::digest(client_data_json));
+                if !verify_signature(&public_key, &message, sig)? {
+                    return Err(verification_failed("invalid attestation signature"));
+                }
+            }
+            _ => return Err(verification_failed("unsupported attestation format")),
+        }
+
+        {
+            let query = Query::select_from("webauthn_credentials")
+                .all_columns()
+                .condition(query::eq(query::column("credential_id"), param(1)))
+                .into_query();
+            if query
+                .select_maybe::<()>(tx, params![credential_id])?
+                .is_some()
+            {
+                return Err(verification_failed("credential already registered"));
+            }
+        }
+
+        Ok((credential_id, public_key, data.sign_count))
+    }
+
+    /// Verifies the authenticator's response to `start_webauthn_registration`
+    /// and stores the new credential. Attestation statements are checked for
+    /// the `none` format and `packed` self attestation.
+    pub fn finish_webauthn_registration(
+        &mut self,
+        user_id: i64,
+        registration: WebAuthnRegistration,
+    ) -> Result<WebAuthnCredential> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] finish_webauthn_registration:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let client_data_json = decode(&registration.client_data_json)?;
+        let challenge = parse_client_data(
+            &client_data_json,
+            "webauthn.create",
+            &self.relying_party.origin,
+        )?;
+        // failures past this point are committed, so the challenge stays used
+        let (credential_id, public_key, sign_count) = match self.tx_verify_webauthn_registration(
+            &tx,
+            user_id,
+            &challenge,
+            &client_data_json,
+            &registration,
+        ) {
+            Ok(verified) => verified,
+            Err(err) => {
+                tx.commit()?;
+                return Err(err);
+            }
+        };
+
+        let now = Utc::now();
+        let id = {
+            let query = Query::insert_into("webauthn_credentials")
+                .column("user_id", param(1))
+                .column("credential_id", param(2))
+                .column("public_key", param(3))
+                .column("sign_count", param(4))
+                .column("name", param(5))
+                .column("created_at", param(6))
+                .into_query();
+
+            query.insert(
+                &tx,
+                params![
+                    user_id,
+                    credential_id,
+                    public_key,
+                    sign_count,
+                    registration.name,
+                    now
+                ],
+            )?
+        };
+
+        tx.commit()?;
+        Ok(WebAuthnCredential {
+            id,
+            user_id,
+            credential_id: BASE64URL_NOPAD.encode(&credential_id),
+            name: registration.name,
+            sign_count,
+            created_at: now,
+            last_used_at: None,
+        })
+    }
+
+    /// Starts a passkey login. Without a username any discoverable
+    /// credential of the relying party is accepted.
+    pub fn start_webauthn_assertion(
+        &mut self,
+        username: Option<&str>,
+    ) -> Result<WebAuthnAssertionOptions> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] start_webauthn_assertion:");
+        tracing::trace!("  username: {:?}", username);
+
+        let (user_id, allow_credentials) = match username {
+            Some(username) => {
+                let user = Self::tx_get_user_by_username(&tx, username)?;
+                let credentials = Self::tx_get_webauthn_credentials(&tx, user.id)?
+                    .into_iter()
+                    .map(|credential| BASE64URL_NOPAD.encode(&credential.credential_id))
+                    .collect();
+                (Some(user.id), credentials)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let expiry_date = Utc::now() + self.session_policy.challenge_lifetime;
+        let challenge =
+            Self::tx_create_webauthn_challenge(&tx, expiry_date, user_id, CEREMONY_ASSERTION)?;
+
+        tx.commit()?;
+        Ok(WebAuthnAssertionOptions {
+            challenge,
+            rp_id: self.relying_party.id.clone(),
+            allow_credentials,
+        })
+    }
+
+    /// Takes the assertion challenge and checks the signature and counter of
+    /// the credential. Returns the new signature counter.
+    fn tx_verify_webauthn_assertion(
+        &self,
+        tx: &Transaction<'_>,
+        credential: &InnerCredential,
+        challenge: &str,
+        client_data_json: &[u8],
+        assertion: &WebAuthnAssertion,
+        ip_address: Option<&str>,
+    ) -> Result<u32> {
+        let user_id = credential.user_id;
+        let challenge_user_id =
+            Self::tx_take_webauthn_challenge(tx, challenge, CEREMONY_ASSERTION)?;
+        if challenge_user_id.is_some_and(|challenge_user_id| challenge_user_id != user_id) {
+            return Err(Error::ChallengeNotFound);
+        }
+
+        let authenticator_data = decode(&assertion.authenticator_data)?;
+        let data = parse_authenticator_data(&authenticator_data)?;
+        self.check_authenticator_data(&data)?;
+
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&Sha256::digest(client_data_json));
+        let signature = decode(&assertion.signature)?;
+        if !verify_signature(&credential.public_key, &message, &signature)? {
+            Self::tx_record_login_attempt(tx, Some(user_id), ip_address, false)?;
+            return Err(verification_failed("invalid signature"));
+        }
+
+        // authenticators without a counter always report zero
+        if (data.sign_count != 0 || credential.sign_count != 0)
+            && data.sign_count <= credential.sign_count
+        {
+            tracing::warn!(
+                "sign count of credential {} went from {} to {}",
+                credential.id,
+                credential.sign_count,
+                data.sign_count
+            );
+            Self::tx_record_login_attempt(tx, Some(user_id), ip_address, false)?;
+            return Err(Error::WebAuthnCounterRegression);
+        }
+
+        Ok(data.sign_count)
+    }
+
+    /// Verifies the authenticator's response to `start_webauthn_assertion`
+    /// and creates a session. A signature counter that did not increase
+    /// suggests a cloned authenticator and fails the login.
+    pub fn finish_webauthn_assertion(
+        &mut self,
+        assertion: WebAuthnAssertion,
+        track: TrackInformation,
+    ) -> Result<Session> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] finish_webauthn_assertion:");
+        tracing::trace!("  credential_id: {:?}", assertion.credential_id);
+        tracing::trace!("  track: {:?}", track);
+
+        let credential = {
+            let query = Query::select_from("webauthn_credentials")
+                .all_columns()
+                .condition(query::eq(query::column("credential_id"), param(1)))
+                .into_query();
+            query
+                .select_maybe::<InnerCredential>(&tx, params![decode(&assertion.credential_id)?])?
+                .ok_or(Error::CredentialNotFound)?
+        };
+        let user_id = credential.user_id;
+        let ip_address = track.ip_address.clone();
+
+        Self::tx_check_login_throttle(
+            &tx,
+            &self.login_throttle,
+            Some(user_id),
+            ip_address.as_deref(),
+        )?;
+
+        if let Some(handle) = &assertion.user_handle {
+            if *handle != user_handle(user_id) {
+                return Err(verification_failed("unexpected user handle"));
+            }
+        }
+
+        let client_data_json = decode(&assertion.client_data_json)?;
+        let challenge = parse_client_data(
+            &client_data_json,
+            "webauthn.get",
+            &self.relying_party.origin,
+        )?;
+        // failures past this point are committed, so the challenge stays used
+        // and the failed attempt is recorded
+        let sign_count = match self.tx_verify_webauthn_assertion(
+            &tx,
+            &credential,
+            &challenge,
+            &client_data_json,
+            &assertion,
+            ip_address.as_deref(),
+        ) {
+            Ok(sign_count) => sign_count,
+            Err(err) => {
+                tx.commit()?;
+                return Err(err);
+            }
+        };
+
+        let now = Utc::now();
+        {
+            let query = Query::update("webauthn_credentials")
+                .set("sign_count", param(1))
+                .set("last_used_at", param(2))
+                .condition(query::eq(query::column("id"), param(3)))
+                .into_query();
+            query.update(&tx, params![sign_count, now, credential.id])?;
+        }
+
+        Self::tx_record_login_attempt(&tx, Some(user_id), ip_address.as_deref(), true)?;
+
+        let session = Self::tx_issue_session(&tx, &self.session_policy, user_id, track)?;
+
+        tx.commit()?;
+        Ok(session)
+    }
+
+    pub fn list_webauthn_credentials(&mut self, user_id: i64) -> Result<Vec<WebAuthnCredential>> {
+        let tx = self.database.transaction()?;
+
+        tracing::trace!("[database] list_webauthn_credentials:");
+        tracing::trace!("  user_id: {:?}", user_id);
+
+        let credentials = Self::tx_get_webauthn_credentials(&tx, user_id)?;
+        Ok(credentials.into_iter().map(Into::into).collect())
+    }
+
+    pub fn delete_webauthn_credential(&mut self, user_id: i64, id: i64) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_webauthn_credential:");
+            tracing::trace!("  user_id: {:?}", user_id);
+            tracing::trace!("  id: {:?}", id);
+
+            let query = Query::delete_from("webauthn_credentials")
+                .condition(query::eq(query::column("id"), param(1)))
+                .condition(query::eq(query::column("user_id"), param(2)))
+                .into_query();
+            query.delete(&tx, params![id, user_id])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_expired_webauthn_challenges(&mut self) -> Result<()> {
+        let tx = self.database.transaction()?;
+
+        {
+            tracing::trace!("[database] delete_expired_webauthn_challenges");
+            let query = Query::delete_from("webauthn_challenges")
+                .condition(query::lt(query::column("expiry_date"), param(1)))
+                .into_query();
+
+            query.delete(&tx, params![Utc::now()])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS permissions_new (
+    site TEXT NOT NULL,
+    permission TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, site, permission)
+);
+
+INSERT OR IGNORE INTO permissions_new (site, permission, user_id)
+    SELECT site, permission, user_id FROM permissions;
+
+DROP TABLE permissions;
+
+ALTER TABLE permissions_new RENAME TO permissions;
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS login_attempts_new (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER,
+    attempt_date DATETIME NOT NULL,
+    attempt_ip_address TEXT,
+    success INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO login_attempts_new (id, user_id, attempt_date, attempt_ip_address)
+    SELECT id, user_id, attempt_date, attempt_ip_address FROM login_attempts;
+
+DROP TABLE login_attempts;
+
+ALTER TABLE login_attempts_new RENAME TO login_attempts;
+
+CREATE INDEX IF NOT EXISTS login_attempts_user_id ON login_attempts (user_id);
+CREATE INDEX IF NOT EXISTS login_attempts_ip_address ON login_attempts (attempt_ip_address);
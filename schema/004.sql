@@ -0,0 +1 @@
+ALTER TABLE qr_codes ADD COLUMN approved_at DATETIME;
@@ -0,0 +1,2 @@
+-- Existing plaintext tokens are hashed in place when the database is opened.
+ALTER TABLE sessions ADD COLUMN token_hashed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS totp_secrets (
+    user_id INTEGER PRIMARY KEY,
+    secret BLOB NOT NULL,
+    confirmed_at DATETIME,
+    last_used_step INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS login_challenges (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    challenge_token TEXT NOT NULL UNIQUE,
+    expiry_date DATETIME NOT NULL,
+    track_device TEXT,
+    track_user_agent TEXT,
+    track_ip_address TEXT,
+    track_location TEXT,
+    track_os TEXT,
+    track_browser TEXT,
+    track_screen_resolution TEXT,
+    track_timezone TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
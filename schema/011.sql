@@ -0,0 +1,5 @@
+-- A user is disabled while disabled_at is set and disabled_until is unset or
+-- in the future.
+ALTER TABLE users ADD COLUMN disabled_at DATETIME;
+ALTER TABLE users ADD COLUMN disabled_reason TEXT;
+ALTER TABLE users ADD COLUMN disabled_until DATETIME;
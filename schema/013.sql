@@ -0,0 +1,2 @@
+-- Grants without an expiry date are permanent.
+ALTER TABLE permissions ADD COLUMN expiry_date DATETIME;
@@ -0,0 +1,16 @@
+-- Expiry of the last signed token issued for the session, so revoking the
+-- session only has to list it until its signed tokens have expired.
+ALTER TABLE sessions ADD COLUMN signed_token_expiry_date DATETIME;
+
+CREATE TABLE IF NOT EXISTS signing_keys (
+    id INTEGER PRIMARY KEY,
+    kid TEXT NOT NULL UNIQUE,
+    private_key BLOB NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    retired_at DATETIME
+);
+
+CREATE TABLE IF NOT EXISTS revoked_signed_sessions (
+    session_id INTEGER PRIMARY KEY,
+    expiry_date DATETIME NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Per-user override of `SessionPolicy::max_sessions`, NULL uses the policy.
+ALTER TABLE users ADD COLUMN max_sessions INTEGER;
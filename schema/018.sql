@@ -0,0 +1,22 @@
+-- Email addresses are unique regardless of case. An address shared by several
+-- users stays with the one who verified it, or else with the oldest account,
+-- and is removed from the others.
+DROP INDEX IF EXISTS users_email;
+
+UPDATE users SET email = NULL, email_verified_at = NULL
+WHERE email IS NOT NULL AND EXISTS (
+    SELECT 1 FROM users AS other
+    WHERE other.id != users.id
+        AND lower(trim(other.email)) = lower(trim(users.email))
+        AND (
+            (other.email_verified_at IS NOT NULL AND users.email_verified_at IS NULL)
+            OR ((other.email_verified_at IS NULL) = (users.email_verified_at IS NULL)
+                AND other.id < users.id)
+        )
+);
+
+UPDATE users SET email = lower(trim(email)) WHERE email IS NOT NULL;
+UPDATE email_verification_tokens SET email = lower(trim(email));
+
+-- NULL emails do not collide, so users without an address are unaffected.
+CREATE UNIQUE INDEX IF NOT EXISTS users_email ON users(email COLLATE NOCASE);
@@ -0,0 +1,50 @@
+-- Session ids are never reused, so refresh tokens and revocations that refer
+-- to a deleted session cannot reach a later session, possibly of another
+-- user, which was given the same id.
+CREATE TABLE IF NOT EXISTS sessions_autoincrement (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    session_token TEXT NOT NULL UNIQUE,
+    expiry_date DATETIME NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    track_device TEXT,
+    track_user_agent TEXT,
+    track_ip_address TEXT,
+    track_location TEXT,
+    track_os TEXT,
+    track_browser TEXT,
+    track_screen_resolution TEXT,
+    track_timezone TEXT,
+    token_hashed INTEGER NOT NULL DEFAULT 0,
+    signed_token_expiry_date DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO sessions_autoincrement (
+    id, user_id, session_token, expiry_date, created_at, last_used_at,
+    track_device, track_user_agent, track_ip_address, track_location,
+    track_os, track_browser, track_screen_resolution, track_timezone,
+    token_hashed, signed_token_expiry_date
+)
+SELECT
+    id, user_id, session_token, expiry_date, created_at, last_used_at,
+    track_device, track_user_agent, track_ip_address, track_location,
+    track_os, track_browser, track_screen_resolution, track_timezone,
+    token_hashed, signed_token_expiry_date
+FROM sessions;
+
+DROP TABLE sessions;
+ALTER TABLE sessions_autoincrement RENAME TO sessions;
+
+-- Ids of sessions that were already deleted are skipped as well.
+INSERT INTO sqlite_sequence (name, seq)
+SELECT 'sessions', 0
+WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'sessions');
+
+UPDATE sqlite_sequence SET seq = max(
+    seq,
+    (SELECT coalesce(max(session_id), 0) FROM refresh_tokens),
+    (SELECT coalesce(max(session_id), 0) FROM revoked_signed_sessions)
+)
+WHERE name = 'sessions';
@@ -0,0 +1,19 @@
+-- Signed tokens are revoked one by one through their `jti` claim, so issuing
+-- a new token for a session never brings back one that was revoked. Rows are
+-- kept after their user is deleted, until the token has expired.
+CREATE TABLE IF NOT EXISTS signed_tokens (
+    jti TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    session_id INTEGER NOT NULL,
+    expiry_date DATETIME NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS signed_tokens_user_id ON signed_tokens (user_id);
+CREATE INDEX IF NOT EXISTS signed_tokens_session_id ON signed_tokens (session_id);
+
+-- Tokens issued before have no `jti` and are no longer accepted, clients
+-- get a new one from `/sessions/signed-token`.
+DROP TABLE IF EXISTS revoked_signed_sessions;
+ALTER TABLE sessions DROP COLUMN signed_token_expiry_date;